@@ -1,16 +1,204 @@
-use std::cell::RefCell;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use super::mango::Mango;
-use anyhow::Result;
-use sled::Tree;
+use crate::{
+    cache::SharedObjectCache,
+    chunk::{chunk_key, ChunkManifest},
+    format::SerFormat,
+    label::Label,
+    object::{unix_millis, Object, ObjectID, ObjectMeta},
+    query::{
+        delete::DeleteRequest,
+        error::{InsertError, TransactionError},
+        execute::{BucketTx, ExecuteTransaction},
+        find::FindRequest,
+        insert::InsertRequest,
+        rename::RenameLabels,
+        transaction::{Request, RequestResult, Transaction},
+    },
+    watch::LabelWatcher,
+};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, UnabortableTransactionError},
+    Batch, IVec, Transactional, Tree,
+};
 
 pub const SEPARATOR: &str = "\u{001F}";
 
+/// The suffix of every tree a bucket opens, after its name and SEPARATOR
+pub(crate) const TREE_SUFFIXES: &[&str] = &[
+    "labels",
+    "ilabels",
+    "objects",
+    "objectlabels",
+    "objectilabels",
+    "contenthashes",
+    "contentrefcount",
+    "objectattrs",
+    "meta",
+    "chunks",
+    "versions",
+    "objectmeta",
+    "labelkeys",
+    "objectcreated",
+    "objectupdated",
+];
+
+/// The key the BucketConfig is stored under in the meta tree
+pub(crate) const CONFIG_KEY: &[u8] = b"config";
+
+/// Computes labels from an object's payload, see `Bucket::add_label_deriver`
+pub type DeriveLabels = dyn Fn(&Bytes) -> Vec<Label> + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct LabelDeriver(Arc<DeriveLabels>);
+
+impl Debug for LabelDeriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LabelDeriver")
+    }
+}
+
+/// Settings stored with a bucket so its behavior is stable across reopens
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BucketConfig {
+    /// Deduplicate every insert by content, as if each set `dedupe_by_content`
+    pub dedupe_by_content: bool,
+
+    /// Trim and lowercase label keys on insert and find, so `Content_Type` and
+    /// `content_type` index together. Values are left as they are.
+    pub normalize_keys: bool,
+
+    /// How values are encoded. Keys are flexbuffers regardless.
+    pub format: SerFormat,
+
+    /// Keep up to this many bytes of recently read payloads in memory
+    ///
+    /// Default: None (every read goes to sled)
+    pub object_cache_bytes: Option<usize>,
+
+    /// Inserts with a longer label key, in bytes, are rejected
+    ///
+    /// Default: 256
+    pub max_label_key_len: usize,
+
+    /// Inserts with a longer label value, in bytes, are rejected. Typed values count
+    /// with their encoding.
+    ///
+    /// Default: 4096
+    pub max_label_value_len: usize,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            dedupe_by_content: false,
+            normalize_keys: false,
+            format: SerFormat::default(),
+            object_cache_bytes: None,
+            max_label_key_len: 256,
+            max_label_value_len: 4096,
+        }
+    }
+}
+
+impl BucketConfig {
+    /// Read the config stored in a bucket's meta tree
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(flexbuffers::from_slice(bytes)?)
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        self.serialize(&mut s)?;
+        Ok(s.take_buffer())
+    }
+
+    /// Fail with a `TransactionError` if `label` is over the length limits
+    pub(crate) fn check_label(&self, label: &Label) -> Result<()> {
+        if label.0.len() > self.max_label_key_len {
+            return Err(
+                TransactionError::LabelKeyTooLong(label.0.len(), self.max_label_key_len).into(),
+            );
+        }
+        if label.1.len() > self.max_label_value_len {
+            return Err(TransactionError::LabelValueTooLong(
+                label.0.clone(),
+                label.1.len(),
+                self.max_label_value_len,
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The key an object is stored under in the per-object trees
+pub(crate) fn id_key(id: ObjectID) -> Result<Vec<u8>> {
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    id.serialize(&mut s)?;
+    Ok(s.take_buffer())
+}
+
+/// The key version `version` of object `id` is stored under in the versions tree
+pub(crate) fn version_key(id: ObjectID, version: u64) -> Result<Vec<u8>> {
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    (id, version).serialize(&mut s)?;
+    Ok(s.take_buffer())
+}
+
+/// `field` quoted for CSV if it holds a comma, quote or line break
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")).into(),
+        false => field.into(),
+    }
+}
+
+/// The object ids that differ between two buckets, found by `Bucket::diff`. Each list is
+/// sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BucketDiff {
+    /// Objects stored only in the bucket `diff` was called on
+    pub only_in_self: Vec<ObjectID>,
+
+    /// Objects stored only in the other bucket
+    pub only_in_other: Vec<ObjectID>,
+
+    /// Objects stored in both whose payloads differ
+    pub differing: Vec<ObjectID>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Bucket {
-    parent: Mango,
-    name: String,
-    is_ok: RefCell<bool>,
+    pub(crate) parent: Mango,
+    pub(crate) name: String,
+
+    /// Cleared by `empty`, shared with every handle to the bucket through the Mango
+    is_ok: Arc<AtomicBool>,
+
+    /// The value codec from the stored BucketConfig
+    pub(crate) format: SerFormat,
+
+    /// Set when the stored BucketConfig enables `object_cache_bytes`
+    pub(crate) cache: Option<SharedObjectCache>,
+
+    /// The label every object seen or written through this handle must carry, see
+    /// `Bucket::with_scope`
+    pub(crate) scope: Option<Label>,
 
     /// Key = ([lhs][SEPARATOR][rhs]), Value = Label
     ///
@@ -36,40 +224,931 @@ pub struct Bucket {
     ///
     /// Stores a list of Objects described by a specific label
     pub(crate) t_labels_objects: Tree,
+
+    /// Key = content hash, Value = ObjectID
+    ///
+    /// Stores the object holding each distinct payload, used for dedup
+    pub(crate) t_content_hashes: Tree,
+
+    /// Key = content hash, Value = u64
+    ///
+    /// Stores how many inserts share each deduplicated payload
+    pub(crate) t_content_refcount: Tree,
+
+    /// Key = ObjectID, Value = HashMap<String, String>
+    ///
+    /// Stores descriptive attributes for each object. These are not indexed.
+    pub(crate) t_objects_attrs: Tree,
+
+    /// Key = setting name, Value = setting
+    ///
+    /// Stores the BucketConfig written when the bucket was first opened
+    pub(crate) t_meta: Tree,
+
+    /// Key = ObjectID, Value = ChunkManifest; Key = (ObjectID, n), Value = Bytes
+    ///
+    /// Stores the payloads of objects inserted with `InsertRequest::chunked`, in pieces.
+    /// Their entry in t_objects holds an empty payload.
+    pub(crate) t_chunks: Tree,
+
+    /// Key = ObjectID, Value = u64; Key = (ObjectID, version), Value = Bytes
+    ///
+    /// Stores the payloads replaced by inserts with `InsertRequest::keep_versions`, and
+    /// the latest version number of each object.
+    pub(crate) t_object_versions: Tree,
+
+    /// Key = ObjectID, Value = ObjectMeta
+    ///
+    /// Stores the expiry of objects inserted with `InsertRequest::ttl`
+    pub(crate) t_objects_meta: Tree,
+
+    /// Key = raw [lhs][SEPARATOR][rhs], Value = Label
+    ///
+    /// Stores the same labels as t_labels, keyed so every value of a key can be found with
+    /// a bounded prefix scan.
+    pub(crate) t_label_keys: Tree,
+
+    /// Key = ObjectID, Value = u64
+    ///
+    /// Stores when each object was first inserted, in milliseconds since the unix epoch
+    pub(crate) t_objects_created: Tree,
+
+    /// Key = ObjectID, Value = u64
+    ///
+    /// Stores when each object's payload or labels last changed, in milliseconds since
+    /// the unix epoch
+    pub(crate) t_objects_updated: Tree,
 }
 
 impl Bucket {
     pub(crate) fn open(name: &str, parent: Mango) -> Result<Self> {
+        Self::open_with_config(name, parent, BucketConfig::default())
+    }
+
+    /// Open a bucket, storing `config` if the bucket has none yet. An existing bucket
+    /// keeps the config it was created with.
+    pub(crate) fn open_with_config(
+        name: &str,
+        parent: Mango,
+        config: BucketConfig,
+    ) -> Result<Self> {
         let db = parent.inner.clone();
-        Ok(Self {
+        let mut this = Self {
             parent: parent.clone(),
             name: name.to_string(),
-            is_ok: RefCell::new(true),
+            is_ok: parent.bucket_open_flag(name)?,
+            format: config.format,
+            cache: None,
+            scope: None,
             t_labels: db.open_tree(format!("{name}{SEPARATOR}labels"))?,
             t_labels_invert: db.open_tree(format!("{name}{SEPARATOR}ilabels"))?,
             t_objects: db.open_tree(format!("{name}{SEPARATOR}objects"))?,
             t_objects_labels: db.open_tree(format!("{name}{SEPARATOR}objectlabels"))?,
             t_labels_objects: db.open_tree(format!("{name}{SEPARATOR}objectilabels"))?,
-        })
+            t_content_hashes: db.open_tree(format!("{name}{SEPARATOR}contenthashes"))?,
+            t_content_refcount: db.open_tree(format!("{name}{SEPARATOR}contentrefcount"))?,
+            t_objects_attrs: db.open_tree(format!("{name}{SEPARATOR}objectattrs"))?,
+            t_meta: db.open_tree(format!("{name}{SEPARATOR}meta"))?,
+            t_chunks: db.open_tree(format!("{name}{SEPARATOR}chunks"))?,
+            t_object_versions: db.open_tree(format!("{name}{SEPARATOR}versions"))?,
+            t_objects_meta: db.open_tree(format!("{name}{SEPARATOR}objectmeta"))?,
+            t_label_keys: db.open_tree(format!("{name}{SEPARATOR}labelkeys"))?,
+            t_objects_created: db.open_tree(format!("{name}{SEPARATOR}objectcreated"))?,
+            t_objects_updated: db.open_tree(format!("{name}{SEPARATOR}objectupdated"))?,
+        };
+        let _ = this.t_meta.compare_and_swap(
+            CONFIG_KEY,
+            None as Option<&[u8]>,
+            Some(config.encode()?),
+        )?;
+        let stored = this.config()?;
+        this.format = stored.format;
+
+        // Buckets written before the label keys tree existed
+        if this.t_label_keys.is_empty() && !this.t_labels.is_empty() {
+            let mut batch = Batch::default();
+            for kv in this.t_labels.iter() {
+                let (_, v) = kv?;
+                let label: Label = this.format.de(&v)?;
+                batch.insert(label.key_raw(), v);
+            }
+            this.t_label_keys.apply_batch(batch)?;
+        }
+
+        // Buckets written when labels invert keys were serialized strings rather than raw
+        let legacy_invert = match this.t_labels_invert.first()? {
+            Some((k, _)) => {
+                flexbuffers::from_slice::<String>(&k).is_ok_and(|key| key.contains(SEPARATOR))
+            }
+            None => false,
+        };
+        if legacy_invert {
+            let mut batch = Batch::default();
+            for kv in this.t_labels_invert.iter() {
+                let (k, _) = kv?;
+                batch.remove(k);
+            }
+            for kv in this.t_labels.iter() {
+                let (_, v) = kv?;
+                let label: Label = this.format.de(&v)?;
+                batch.insert(label.key_rtl(), v);
+            }
+            this.t_labels_invert.apply_batch(batch)?;
+        }
+        if let Some(capacity) = stored.object_cache_bytes {
+            this.cache = Some(parent.object_cache(name, capacity)?);
+        }
+        Ok(this)
+    }
+
+    /// The settings this bucket was created with
+    pub fn config(&self) -> Result<BucketConfig> {
+        match self.t_meta.get(CONFIG_KEY)? {
+            Some(bytes) => BucketConfig::decode(&bytes),
+            None => Ok(BucketConfig::default()),
+        }
+    }
+
+    /// Every tree belonging to this bucket, in the order `BucketTx::from_trees` expects
+    pub(crate) fn trees(&self) -> Vec<&Tree> {
+        vec![
+            &self.t_labels,
+            &self.t_labels_invert,
+            &self.t_objects,
+            &self.t_objects_labels,
+            &self.t_labels_objects,
+            &self.t_content_hashes,
+            &self.t_content_refcount,
+            &self.t_objects_attrs,
+            &self.t_meta,
+            &self.t_chunks,
+            &self.t_object_versions,
+            &self.t_objects_meta,
+            &self.t_label_keys,
+            &self.t_objects_created,
+            &self.t_objects_updated,
+        ]
     }
 
     pub fn check(&self) -> Result<bool> {
-        let ok = self.is_ok.try_borrow()?;
-        Ok(*ok)
+        Ok(self.is_ok.load(Ordering::SeqCst))
     }
 
     pub fn empty(&self) -> Result<()> {
         let name = &self.name;
         let db = self.parent.inner.clone();
-        db.drop_tree(format!("{name}{SEPARATOR}labels"))?;
-        db.drop_tree(format!("{name}{SEPARATOR}ilabels"))?;
-        db.drop_tree(format!("{name}{SEPARATOR}objects"))?;
-        db.drop_tree(format!("{name}{SEPARATOR}objectlabels"))?;
-        db.drop_tree(format!("{name}{SEPARATOR}objectilabels"))?;
+        for suffix in TREE_SUFFIXES {
+            db.drop_tree(format!("{name}{SEPARATOR}{suffix}"))?;
+        }
+
+        if let Some(cache) = &self.cache {
+            cache.lock().map_err(|e| anyhow!("{e}"))?.clear();
+        }
 
-        let mut is_ok = self.is_ok.try_borrow_mut()?;
-        *is_ok = false;
+        self.is_ok.store(false, Ordering::SeqCst);
 
         Ok(())
     }
+
+    /// The ids of every object described by `label`, in no particular order.
+    /// Empty for a label no object carries.
+    ///
+    /// Reads one entry of the labels objects tree, without a transaction. The key is
+    /// normalized first if the bucket normalizes keys.
+    pub fn object_ids_for_label(&self, label: &Label) -> Result<Vec<ObjectID>> {
+        let label = match self.config()?.normalize_keys {
+            true => label.normalized(),
+            false => label.clone(),
+        };
+        match self.t_labels_objects.get(label.key_ltr()?)? {
+            Some(bytes) => self.format.de(&bytes),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Whether any object carries every one of `labels`, false for no labels.
+    ///
+    /// Walks the smallest label's ids and stops at the first one the others share,
+    /// without building the full intersection. Reads without a transaction, like
+    /// `object_ids_for_label`.
+    pub fn any_match(&self, labels: Vec<Label>) -> Result<bool> {
+        let mut lists = labels
+            .iter()
+            .map(|label| self.object_ids_for_label(label))
+            .collect::<Result<Vec<_>>>()?;
+        lists.sort_by_key(Vec::len);
+        let mut lists = lists.into_iter();
+        let Some(smallest) = lists.next() else {
+            return Ok(false);
+        };
+        let others: Vec<HashSet<ObjectID>> = lists.map(|ids| ids.into_iter().collect()).collect();
+        Ok(smallest
+            .iter()
+            .any(|id| others.iter().all(|ids| ids.contains(id))))
+    }
+
+    /// Execute `reqs` in a single transaction and return their results in order
+    pub fn run(&self, reqs: Vec<Request>) -> Result<Vec<RequestResult>> {
+        let tx: Transaction = self.into();
+        for req in reqs {
+            tx.append_request(req)?;
+        }
+        tx.execute()?;
+        tx.results()
+    }
+
+    /// Whether `id` is free or holds an object carrying `scope`
+    pub(crate) fn in_scope(&self, id: ObjectID, scope: &Label, normalize: bool) -> Result<bool> {
+        let scope = match normalize {
+            true => scope.normalized(),
+            false => scope.clone(),
+        };
+        let labels = self.labels(id)?;
+        let exists = !labels.is_empty() || self.t_objects.contains_key(id_key(id)?)?;
+        Ok(!exists || labels.contains(&scope))
+    }
+
+    /// A handle to this bucket confined to the objects carrying `label`, for serving
+    /// one tenant of a shared bucket.
+    ///
+    /// Transactions through the handle only find objects with the label and add it to
+    /// every insert. Gets, deletes, swaps and inserts naming an existing object without
+    /// it fail with `TransactionError::OutOfScope`. `insert_many_batched` and
+    /// `purge_expired` honor the scope too; other bucket methods that do not go through a
+    /// Transaction, such as `get_object`, are not scoped.
+    pub fn with_scope(&self, label: Label) -> Bucket {
+        Bucket {
+            scope: Some(label),
+            ..self.clone()
+        }
+    }
+
+    /// Label every object inserted into this bucket with whatever `deriver` computes from
+    /// its payload, such as a size class. Returns how many derivers the bucket has.
+    ///
+    /// Derivers are kept in memory by the Mango, so every handle to the bucket uses them
+    /// until the process exits; register them again after each open. They run before the
+    /// transaction starts, in the order they were added. A derived label is dropped when
+    /// the insert already has a label with its key, so user labels win, and duplicates are
+    /// stored once. `insert_many_batched` applies them too.
+    pub fn add_label_deriver(&self, deriver: Box<DeriveLabels>) -> Result<usize> {
+        self.parent
+            .add_label_deriver(&self.name, LabelDeriver(Arc::from(deriver)))
+    }
+
+    /// The labels the registered derivers add to an insert of `payload` with `labels`
+    pub(crate) fn derived_labels(&self, payload: &Bytes, labels: &[Label]) -> Result<Vec<Label>> {
+        let keys: HashSet<&str> = labels.iter().map(Label::key).collect();
+        let mut derived = vec![];
+        for deriver in self.parent.label_derivers(&self.name)? {
+            for label in (deriver.0)(payload) {
+                if !keys.contains(label.key()) && !derived.contains(&label) {
+                    derived.push(label);
+                }
+            }
+        }
+        Ok(derived)
+    }
+
+    /// Rename every label with key `old_key` to `new_key`, keeping its value, and return
+    /// how many labels changed.
+    ///
+    /// Objects already carrying the renamed label are merged into it. Objects inserted
+    /// with `skip_label_index` are not in the index and keep their old labels.
+    pub fn rename_label_key(&self, old_key: &str, new_key: &str) -> Result<usize> {
+        if !self.check()? {
+            return Err(TransactionError::BucketDropped(self.name.clone()).into());
+        }
+        let (old_key, new_key) = match self.config()?.normalize_keys {
+            true => (old_key.trim().to_lowercase(), new_key.trim().to_lowercase()),
+            false => (old_key.to_string(), new_key.to_string()),
+        };
+        if old_key == new_key {
+            return Ok(0);
+        }
+
+        let mut renames = vec![];
+        for kv in self.t_labels.iter() {
+            let (_, v) = kv?;
+            let label: Label = self.format.de(&v)?;
+            if label.key() == old_key {
+                let renamed = Label(new_key.clone(), label.1.clone());
+                renames.push((label, renamed));
+            }
+        }
+        let rename = RenameLabels::new(renames);
+
+        let trees: Vec<Tree> = self.trees().into_iter().cloned().collect();
+        let changed = trees
+            .transaction(|trees| {
+                let tx =
+                    BucketTx::from_trees(trees, self.format, self.cache.as_deref(), None, None)
+                        .ok_or_else(|| {
+                            UnabortableTransactionError::Storage(sled::Error::Unsupported(
+                                "bucket trees do not match BucketTx".to_string(),
+                            ))
+                        })?;
+                Ok::<usize, ConflictableTransactionError<String>>(rename.execute(&tx)?)
+            })
+            .map_err(|e| anyhow!("{}", e))?;
+        Ok(changed)
+    }
+
+    /// The ids matched by `find`, yielded one at a time.
+    ///
+    /// The id set is resolved from the label indexes first, since groups intersect and
+    /// exclude, but no object's labels are read, so only ids are held in memory. Ordering
+    /// by id is applied; ordering by label value is an error. Any failure is yielded as the
+    /// only item.
+    pub fn query_ids_stream(&self, find: FindRequest) -> impl Iterator<Item = Result<ObjectID>> {
+        let (ids, err) = match find.ids_in(self) {
+            Ok(ids) => (ids, None),
+            Err(e) => (vec![], Some(Err(e))),
+        };
+        err.into_iter().chain(ids.into_iter().map(Ok))
+    }
+
+    /// Run each insert in its own transaction, so one failing item does not stop the rest.
+    ///
+    /// Returns the outcome of each item, in order. Only a dropped bucket fails the whole
+    /// call. Use `run` instead when the inserts must succeed or fail together.
+    pub fn try_insert_many(
+        &self,
+        items: Vec<InsertRequest>,
+    ) -> Result<Vec<std::result::Result<ObjectID, InsertError>>> {
+        if !self.check()? {
+            return Err(TransactionError::BucketDropped(self.name.clone()).into());
+        }
+        let mut results = Vec::with_capacity(items.len());
+        for req in items {
+            let id = *req.id.try_borrow()?;
+            let result = self.run(vec![req.into()]).and_then(|r| {
+                r[0].as_insert_id()
+                    .ok_or_else(|| anyhow!("insert returned no id"))
+            });
+            results.push(result.map_err(|error| InsertError { id, error }));
+        }
+        Ok(results)
+    }
+
+    /// Store each payload under its hash id with its labels, without a transaction.
+    ///
+    /// Faster than running an InsertRequest per item through `run`, but not atomic: each
+    /// tree is written with its own `sled::Batch`, so a crash or error part way through
+    /// can leave objects stored but not yet indexed. Labels of an object that is already
+    /// stored are added to rather than replaced, and nothing is deduplicated. With `fsync`
+    /// set, the database is flushed before returning.
+    ///
+    /// On a scoped handle every item gets the scope label, and an item whose id already
+    /// holds an object outside the scope fails the whole call with
+    /// `TransactionError::OutOfScope` before anything is written.
+    ///
+    /// Returns the id of each item, in order.
+    pub fn insert_many_batched(
+        &self,
+        mut items: Vec<(Bytes, Vec<Label>)>,
+        fsync: bool,
+    ) -> Result<Vec<ObjectID>> {
+        if !self.check()? {
+            return Err(TransactionError::BucketDropped(self.name.clone()).into());
+        }
+        let config = self.config()?;
+        for (payload, labels) in items.iter_mut() {
+            let derived = self.derived_labels(payload, labels)?;
+            labels.extend(derived);
+            if let Some(scope) = &self.scope {
+                labels.push(scope.clone());
+            }
+        }
+        for label in items.iter().flat_map(|(_, labels)| labels) {
+            config.check_label(label)?;
+        }
+        let normalize = config.normalize_keys;
+        if let Some(scope) = &self.scope {
+            for (payload, _) in items.iter() {
+                let id = Object::new(payload.clone()).hash_id();
+                if !self.in_scope(id, scope, normalize)? {
+                    return Err(TransactionError::OutOfScope(id, scope.to_string()).into());
+                }
+            }
+        }
+
+        let mut ids = Vec::with_capacity(items.len());
+        let mut objects: BTreeMap<ObjectID, (Object, HashSet<Label>)> = BTreeMap::new();
+        for (payload, labels) in items {
+            let object = Object::new(payload);
+            let id = object.hash_id();
+            let entry = objects
+                .entry(id)
+                .or_insert_with(|| (object, HashSet::new()));
+            entry.1.extend(labels.into_iter().map(|l| match normalize {
+                true => l.normalized(),
+                false => l,
+            }));
+            ids.push(id);
+        }
+
+        let mut t_objects = Batch::default();
+        let mut t_objects_labels = Batch::default();
+        let mut t_content_hashes = Batch::default();
+        let mut t_content_refcount = Batch::default();
+        let mut t_chunks = Batch::default();
+        let mut t_objects_created = Batch::default();
+        let mut t_objects_updated = Batch::default();
+        let created_at = self.format.ser(&unix_millis())?;
+        let mut label_ids: BTreeMap<Label, Vec<ObjectID>> = BTreeMap::new();
+        for (id, (object, labels)) in objects.iter() {
+            let key = id_key(*id)?;
+            t_objects.insert(key.clone(), self.format.ser(&object.get_inner())?);
+            if !self.t_objects_created.contains_key(&key)? {
+                t_objects_created.insert(key.clone(), created_at.clone());
+            }
+            t_objects_updated.insert(key.clone(), created_at.clone());
+
+            // A chunked copy would shadow the new payload
+            if let Some(bytes) = self.t_chunks.get(&key)? {
+                let manifest: ChunkManifest = self.format.de(&bytes)?;
+                for n in 0..manifest.chunks {
+                    t_chunks.remove(chunk_key(*id, n)?);
+                }
+                t_chunks.remove(key.clone());
+            }
+
+            let mut all_labels = self.labels(*id)?;
+            all_labels.extend(labels.iter().cloned());
+            all_labels.sort();
+            all_labels.dedup();
+            t_objects_labels.insert(key, self.format.ser(&all_labels)?);
+            for label in labels {
+                label_ids.entry(label.clone()).or_default().push(*id);
+            }
+
+            let hash_key = id_key(object.hash_id())?;
+            if !self.t_content_hashes.contains_key(&hash_key)? {
+                t_content_hashes.insert(hash_key.clone(), self.format.ser(id)?);
+                t_content_refcount.insert(hash_key, self.format.ser(&1u64)?);
+            }
+        }
+
+        let mut t_labels = Batch::default();
+        let mut t_labels_invert = Batch::default();
+        let mut t_label_keys = Batch::default();
+        let mut t_labels_objects = Batch::default();
+        for (label, new_ids) in label_ids {
+            let value = self.format.ser(&label)?;
+            t_labels.insert(label.key_ltr()?, value.clone());
+            t_labels_invert.insert(label.key_rtl(), value.clone());
+            t_label_keys.insert(label.key_raw(), value);
+
+            let mut ids = self.object_ids_for_label(&label)?;
+            let existing: HashSet<ObjectID> = ids.iter().copied().collect();
+            ids.extend(new_ids.into_iter().filter(|id| !existing.contains(id)));
+            t_labels_objects.insert(label.key_ltr()?, self.format.ser(&ids)?);
+        }
+
+        // Payloads first, so an interrupted load never indexes a missing object
+        self.t_objects.apply_batch(t_objects)?;
+        self.t_chunks.apply_batch(t_chunks)?;
+        self.t_objects_created.apply_batch(t_objects_created)?;
+        self.t_objects_updated.apply_batch(t_objects_updated)?;
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().map_err(|e| anyhow!("{e}"))?;
+            for id in objects.keys() {
+                cache.remove(*id);
+            }
+        }
+        self.t_content_hashes.apply_batch(t_content_hashes)?;
+        self.t_content_refcount.apply_batch(t_content_refcount)?;
+        self.t_objects_labels.apply_batch(t_objects_labels)?;
+        self.t_labels.apply_batch(t_labels)?;
+        self.t_labels_invert.apply_batch(t_labels_invert)?;
+        self.t_label_keys.apply_batch(t_label_keys)?;
+        self.t_labels_objects.apply_batch(t_labels_objects)?;
+
+        if fsync {
+            self.parent.inner.flush()?;
+        }
+        Ok(ids)
+    }
+
+    /// How many deduplicated inserts share `payload`, stored at `id`. 1 when it isn't shared.
+    pub(crate) fn content_refs(&self, id: ObjectID, payload: &Bytes) -> Result<u64> {
+        let hash_key = id_key(Object::new(payload.clone()).hash_id())?;
+        match self.t_content_hashes.get(&hash_key)? {
+            Some(owner) if self.format.de::<ObjectID>(&owner)? == id => {
+                match self.t_content_refcount.get(&hash_key)? {
+                    Some(bytes) => self.format.de(&bytes),
+                    None => Ok(1),
+                }
+            }
+            _ => Ok(1),
+        }
+    }
+
+    /// Get an object's payload, from the object cache when the bucket has one
+    pub fn get_object(&self, id: ObjectID) -> Result<Option<Bytes>> {
+        let Some(cache) = &self.cache else {
+            return Ok(self.get_ivec(id)?.map(|ivec| Bytes::copy_from_slice(&ivec)));
+        };
+        let epoch = {
+            let mut cache = cache.lock().map_err(|e| anyhow!("{e}"))?;
+            if let Some(payload) = cache.get(id) {
+                return Ok(Some(payload));
+            }
+            cache.epoch()
+        };
+
+        // Not cached if a writer invalidated anything while sled was read
+        let payload = self.get_ivec(id)?.map(|ivec| Bytes::copy_from_slice(&ivec));
+        if let Some(payload) = &payload {
+            cache
+                .lock()
+                .map_err(|e| anyhow!("{e}"))?
+                .fill(id, payload.clone(), epoch);
+        }
+        Ok(payload)
+    }
+
+    /// Get a payload by its content hash, as computed by `Object::hash_id`.
+    ///
+    /// This only finds objects inserted with hash ids (`InsertRequest::new`), since the
+    /// hash is looked up as the object id. Objects stored under an explicit or rehashed id
+    /// are not found, and a payload that does not hash to `hash` is never returned.
+    pub fn get_by_content_hash(&self, hash: u64) -> Result<Option<Bytes>> {
+        Ok(self
+            .get_object(hash)?
+            .filter(|payload| Object::new(payload.clone()).hash_id() == hash))
+    }
+
+    /// A payload `id` held before it was overwritten by an insert with `keep_versions`.
+    ///
+    /// Versions count up from 1 with each overwrite, and only the most recent are kept.
+    /// They are not removed when the object is deleted.
+    pub fn get_version(&self, id: ObjectID, version: u64) -> Result<Option<Bytes>> {
+        match self.t_object_versions.get(version_key(id, version)?)? {
+            Some(bytes) => Ok(Some(self.format.de(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The number of the most recent version kept for `id`, if any
+    pub fn latest_version(&self, id: ObjectID) -> Result<Option<u64>> {
+        match self.t_object_versions.get(id_key(id)?)? {
+            Some(bytes) => Ok(Some(self.format.de(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get an object's payload as the buffer owned by sled, without copying it.
+    /// Always reads from sled, bypassing the object cache.
+    ///
+    /// Chunked payloads are copied into a new buffer as they are reassembled.
+    pub fn get_ivec(&self, id: ObjectID) -> Result<Option<IVec>> {
+        if let Some(payload) = self.get_chunked(id)? {
+            return Ok(Some(payload));
+        }
+        match self.t_objects.get(id_key(id)?)? {
+            Some(ivec) => match self.format.payload_range(&ivec) {
+                Ok(Some((offset, len))) => Ok(Some(ivec.subslice(offset, len))),
+                _ => Ok(Some(self.format.de_payload(&ivec)?.as_ref().into())),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Compare the object ids and payloads of this bucket with `other`. Labels are not
+    /// compared.
+    ///
+    /// Both objects trees are walked side by side in key order, and payloads are only read
+    /// for ids stored in both, one pair at a time, and compared by `Object::hash_id`. The
+    /// buckets may use different formats. Writes during the walk may or may not be seen.
+    pub fn diff(&self, other: &Bucket) -> Result<BucketDiff> {
+        let mut diff = BucketDiff::default();
+        let checksum = |bucket: &Bucket, id: ObjectID| -> Result<Option<ObjectID>> {
+            Ok(bucket
+                .get_ivec(id)?
+                .map(|p| Object::new(Bytes::copy_from_slice(&p)).hash_id()))
+        };
+        let mut ours = self.t_objects.iter().keys();
+        let mut theirs = other.t_objects.iter().keys();
+        let (mut a, mut b) = (ours.next().transpose()?, theirs.next().transpose()?);
+        loop {
+            match (&a, &b) {
+                (None, None) => break,
+                (Some(key), None) => {
+                    diff.only_in_self.push(flexbuffers::from_slice(key)?);
+                    a = ours.next().transpose()?;
+                }
+                (None, Some(key)) => {
+                    diff.only_in_other.push(flexbuffers::from_slice(key)?);
+                    b = theirs.next().transpose()?;
+                }
+                (Some(ka), Some(kb)) if ka < kb => {
+                    diff.only_in_self.push(flexbuffers::from_slice(ka)?);
+                    a = ours.next().transpose()?;
+                }
+                (Some(ka), Some(kb)) if ka > kb => {
+                    diff.only_in_other.push(flexbuffers::from_slice(kb)?);
+                    b = theirs.next().transpose()?;
+                }
+                (Some(key), Some(_)) => {
+                    let id: ObjectID = flexbuffers::from_slice(key)?;
+                    if checksum(self, id)? != checksum(other, id)? {
+                        diff.differing.push(id);
+                    }
+                    a = ours.next().transpose()?;
+                    b = theirs.next().transpose()?;
+                }
+            }
+        }
+        diff.only_in_self.sort();
+        diff.only_in_other.sort();
+        diff.differing.sort();
+        Ok(diff)
+    }
+
+    /// Reassemble the payload of an object inserted with `InsertRequest::chunked`
+    fn get_chunked(&self, id: ObjectID) -> Result<Option<IVec>> {
+        let Some(bytes) = self.t_chunks.get(id_key(id)?)? else {
+            return Ok(None);
+        };
+        let manifest: ChunkManifest = self.format.de(&bytes)?;
+        let mut payload = Vec::with_capacity(manifest.len as usize);
+        for n in 0..manifest.chunks {
+            let chunk = self
+                .t_chunks
+                .get(chunk_key(id, n)?)?
+                .ok_or_else(|| anyhow!("chunk {n} of object {id} is missing"))?;
+            payload.extend_from_slice(&self.format.de::<Bytes>(&chunk)?);
+        }
+        Ok(Some(payload.into()))
+    }
+
+    /// Up to `n` ids picked uniformly at random from every object in the bucket.
+    ///
+    /// Reservoir sampling reads each object id once but only keeps `n` of them in memory.
+    /// Payloads are never read.
+    pub fn sample(&self, n: usize) -> Result<Vec<ObjectID>> {
+        self.sample_with(n, fastrand::Rng::new())
+    }
+
+    /// Like `sample`, but the same seed picks the same ids from the same bucket contents
+    pub fn sample_seeded(&self, n: usize, seed: u64) -> Result<Vec<ObjectID>> {
+        self.sample_with(n, fastrand::Rng::with_seed(seed))
+    }
+
+    fn sample_with(&self, n: usize, mut rng: fastrand::Rng) -> Result<Vec<ObjectID>> {
+        let mut reservoir = vec![];
+        for (seen, key) in self.t_objects.iter().keys().enumerate() {
+            let id: ObjectID = flexbuffers::from_slice(&key?)?;
+            if seen < n {
+                reservoir.push(id);
+            } else {
+                let slot = rng.usize(0..=seen);
+                if slot < n {
+                    reservoir[slot] = id;
+                }
+            }
+        }
+        Ok(reservoir)
+    }
+
+    /// When the object with `id` was first inserted, in milliseconds since the unix
+    /// epoch. None for missing objects and those inserted before timestamps were kept.
+    pub fn created_at(&self, id: ObjectID) -> Result<Option<u64>> {
+        match self.t_objects_created.get(id_key(id)?)? {
+            Some(bytes) => Ok(Some(self.format.de(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// When the payload or labels of the object with `id` last changed, in milliseconds
+    /// since the unix epoch. Reads never change it. None for missing objects and those
+    /// last written before timestamps were kept.
+    pub fn updated_at(&self, id: ObjectID) -> Result<Option<u64>> {
+        match self.t_objects_updated.get(id_key(id)?)? {
+            Some(bytes) => Ok(Some(self.format.de(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The expiry and other bookkeeping stored for an object, default if it has none
+    pub fn object_meta(&self, id: ObjectID) -> Result<ObjectMeta> {
+        match self.t_objects_meta.get(id_key(id)?)? {
+            Some(bytes) => self.format.de(&bytes),
+            None => Ok(ObjectMeta::default()),
+        }
+    }
+
+    /// Give an object a new expiry `new_ttl_seconds` from now, without rewriting its
+    /// payload. Counts as an update for `updated_at`. Returns false if the object does
+    /// not exist.
+    pub fn touch(&self, id: ObjectID, new_ttl_seconds: u64) -> Result<bool> {
+        let key = id_key(id)?;
+        let meta = self.format.ser(&ObjectMeta::expiring_in(new_ttl_seconds))?;
+        let updated_at = self.format.ser(&unix_millis())?;
+        (
+            &self.t_objects,
+            &self.t_objects_meta,
+            &self.t_objects_updated,
+        )
+            .transaction(|(objects, objects_meta, objects_updated)| {
+                if objects.get(&key)?.is_none() {
+                    return Ok(false);
+                }
+                objects_meta.insert(key.clone(), meta.clone())?;
+                objects_updated.insert(key.clone(), updated_at.clone())?;
+                Ok::<bool, ConflictableTransactionError<String>>(true)
+            })
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Delete every object whose TTL has passed, returning their ids.
+    ///
+    /// Expired objects stay readable until purged. Expiries are read before the delete
+    /// runs, so an object touched in between is still removed. A scoped handle only
+    /// purges objects carrying its scope label.
+    pub fn purge_expired(&self) -> Result<Vec<ObjectID>> {
+        let scope = match (&self.scope, self.config()?.normalize_keys) {
+            (Some(scope), true) => Some(scope.normalized()),
+            (scope, _) => scope.clone(),
+        };
+        let mut expired = vec![];
+        for kv in self.t_objects_meta.iter() {
+            let (k, v) = kv?;
+            if self.format.de::<ObjectMeta>(&v)?.is_expired() {
+                let id = flexbuffers::from_slice::<ObjectID>(&k)?;
+                match &scope {
+                    Some(scope) if !self.labels(id)?.contains(scope) => {}
+                    _ => expired.push(id),
+                }
+            }
+        }
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        let results = self.run(vec![DeleteRequest::new(expired).into()])?;
+        Ok(results[0]
+            .as_delete()
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| *id)
+            .collect())
+    }
+
+    /// The labels describing an object, empty if it has none
+    pub fn labels(&self, id: ObjectID) -> Result<Vec<Label>> {
+        match self.t_objects_labels.get(id_key(id)?)? {
+            Some(bytes) => self.format.de(&bytes),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The attributes set on an object at insert, empty if it has none
+    pub fn attrs(&self, id: ObjectID) -> Result<HashMap<String, String>> {
+        match self.t_objects_attrs.get(id_key(id)?)? {
+            Some(bytes) => self.format.de(&bytes),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// How many objects carry `label`, read without deserializing the object list. The
+    /// key is normalized first if the bucket normalizes keys.
+    pub fn label_cardinality(&self, label: &Label) -> Result<usize> {
+        let label = match self.config()?.normalize_keys {
+            true => label.normalized(),
+            false => label.clone(),
+        };
+        match self.t_labels_objects.get(label.key_ltr()?)? {
+            Some(bytes) => self.format.id_list_len(&bytes),
+            None => Ok(0),
+        }
+    }
+
+    /// The `top_n` labels most often carried alongside `label`, with how many of its
+    /// objects carry each, most frequent first. Ties are ordered by label.
+    ///
+    /// Reads the label list of every object carrying `label`, without a transaction.
+    pub fn co_occurring_labels(&self, label: &Label, top_n: usize) -> Result<Vec<(Label, usize)>> {
+        let label = match self.config()?.normalize_keys {
+            true => label.normalized(),
+            false => label.clone(),
+        };
+        let mut counts: HashMap<Label, usize> = HashMap::new();
+        for id in self.object_ids_for_label(&label)? {
+            for other in self.labels(id)? {
+                if other != label {
+                    *counts.entry(other).or_default() += 1;
+                }
+            }
+        }
+        let mut counts: Vec<(Label, usize)> = counts.into_iter().collect();
+        counts.sort_by(|(a, n), (b, m)| m.cmp(n).then_with(|| a.cmp(b)));
+        counts.truncate(top_n);
+        Ok(counts)
+    }
+
+    /// Write every (object, label) pair as CSV rows of `object_id,label_key,label_value`
+    /// after a header row, returning the number of pairs written.
+    ///
+    /// Typed values are written plainly, so `Label::int("n", 1)` and `Label::new("n", "1")`
+    /// export the same. Fields are quoted as RFC 4180 requires.
+    pub fn export_labels_csv<W: Write>(&self, mut w: W) -> Result<usize> {
+        writeln!(w, "object_id,label_key,label_value")?;
+        let mut rows = 0;
+        for kv in self.t_objects_labels.iter() {
+            let (k, v) = kv?;
+            let id: ObjectID = flexbuffers::from_slice(&k)?;
+            let labels: Vec<Label> = self.format.de(&v)?;
+            for label in labels {
+                writeln!(
+                    w,
+                    "{id},{},{}",
+                    csv_field(label.key()),
+                    csv_field(&label.value().to_string())
+                )?;
+                rows += 1;
+            }
+        }
+        w.flush()?;
+        Ok(rows)
+    }
+
+    /// Every label in the bucket with how many objects carry it, read lazily.
+    ///
+    /// Labels come from the labels tree and counts from the labels objects tree. A label
+    /// with no object list counts 0, and an object list with no label is still yielded
+    /// at the end. Both are logged as warnings.
+    pub fn scan_labels(&self) -> impl Iterator<Item = Result<(Label, usize)>> + '_ {
+        let labeled = self.t_labels.iter().map(move |kv| {
+            let (k, v) = kv?;
+            let label: Label = self.format.de(&v)?;
+            match self.t_labels_objects.get(&k)? {
+                Some(ids) => Ok((label, self.format.id_list_len(&ids)?)),
+                None => {
+                    log::warn!("label {label} has no object list");
+                    Ok((label, 0))
+                }
+            }
+        });
+        let unlabeled = self.t_labels_objects.iter().filter_map(move |kv| {
+            let scan = || -> Result<Option<(Label, usize)>> {
+                let (k, v) = kv?;
+                if self.t_labels.contains_key(&k)? {
+                    return Ok(None);
+                }
+                let label = Label::from_key_ltr(&k)?;
+                log::warn!("object list for label {label} has no label entry");
+                Ok(Some((label, self.format.id_list_len(&v)?)))
+            };
+            scan().transpose()
+        });
+        labeled.chain(unlabeled)
+    }
+
+    /// How many of `labels` an insert would add to the bucket, and how many it already
+    /// has, as `(new, existing)`. Repeated labels count once. Nothing is written.
+    pub fn preflight_labels(&self, labels: &[Label]) -> Result<(usize, usize)> {
+        let normalize = self.config()?.normalize_keys;
+        let distinct: HashSet<Label> = labels
+            .iter()
+            .map(|l| match normalize {
+                true => l.normalized(),
+                false => l.clone(),
+            })
+            .collect();
+
+        let mut existing = 0;
+        for label in distinct.iter() {
+            if self.t_labels.contains_key(label.key_ltr()?)? {
+                existing += 1;
+            }
+        }
+        Ok((distinct.len() - existing, existing))
+    }
+
+    /// The label carried by the fewest objects, or None if `labels` is empty. Labels are
+    /// counted as `label_cardinality` does, and the winner is returned as given.
+    pub fn rarest_label(&self, labels: &[Label]) -> Result<Option<Label>> {
+        let mut rarest: Option<(usize, &Label)> = None;
+        for label in labels {
+            let count = self.label_cardinality(label)?;
+            if rarest.is_none_or(|(min, _)| count < min) {
+                rarest = Some((count, label));
+            }
+        }
+        Ok(rarest.map(|(_, label)| label.clone()))
+    }
+
+    /// Watch the set of objects described by `label` for inserts and removals.
+    ///
+    /// Events are best-effort and scoped to this process; see `LabelWatcher`. The key is
+    /// normalized first if the bucket normalizes keys.
+    pub fn watch_label(&self, label: Label) -> Result<LabelWatcher> {
+        let label = match self.config()?.normalize_keys {
+            true => label.normalized(),
+            false => label,
+        };
+        LabelWatcher::new(&label, &self.t_labels_objects, self.format)
+    }
 }
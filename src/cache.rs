@@ -0,0 +1,171 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+
+use bytes::Bytes;
+
+use crate::object::ObjectID;
+
+pub(crate) type SharedObjectCache = Arc<Mutex<ObjectCache>>;
+
+/// A least-recently-used cache of object payloads, bounded by their total size
+///
+/// One cache is shared by every handle to a bucket, see `Mango::object_cache`.
+#[derive(Debug)]
+pub(crate) struct ObjectCache {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+
+    /// Payload and the tick it was last used at
+    entries: HashMap<ObjectID, (Bytes, u64)>,
+
+    /// Ids by last use, oldest first
+    order: BTreeMap<u64, ObjectID>,
+
+    /// Reads answered from the cache
+    pub(crate) hits: u64,
+
+    /// Reads that had to go to sled
+    pub(crate) misses: u64,
+
+    /// Bumped by every invalidation, so a read from sled can tell whether a writer
+    /// invalidated anything while it ran. See `ObjectCache::fill`.
+    epoch: u64,
+}
+
+impl ObjectCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            epoch: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: ObjectID) -> Option<Bytes> {
+        self.tick += 1;
+        let Some((payload, last_used)) = self.entries.get_mut(&id) else {
+            self.misses += 1;
+            return None;
+        };
+        self.order.remove(last_used);
+        self.order.insert(self.tick, id);
+        *last_used = self.tick;
+        self.hits += 1;
+        Some(payload.clone())
+    }
+
+    /// Cache a payload read from sled, evicting the least recently used to make room.
+    /// Payloads larger than the whole cache are not kept.
+    pub(crate) fn insert(&mut self, id: ObjectID, payload: Bytes) {
+        self.remove(id);
+        if payload.len() > self.capacity {
+            return;
+        }
+        while self.used + payload.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                        self.used -= evicted.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.used += payload.len();
+        self.order.insert(self.tick, id);
+        self.entries.insert(id, (payload, self.tick));
+    }
+
+    /// The current invalidation epoch, taken before reading a payload from sled
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Cache a payload read from sled since `epoch`, unless something was invalidated in
+    /// between, as the payload may then be stale
+    pub(crate) fn fill(&mut self, id: ObjectID, payload: Bytes, epoch: u64) {
+        if epoch == self.epoch {
+            self.insert(id, payload);
+        }
+    }
+
+    /// Forget `id`, after it was replaced or deleted
+    pub(crate) fn remove(&mut self, id: ObjectID) {
+        self.epoch += 1;
+        if let Some((payload, last_used)) = self.entries.remove(&id) {
+            self.order.remove(&last_used);
+            self.used -= payload.len();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.epoch += 1;
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+}
+
+/// Changes to the object cache made by one run of a transaction, applied only once it
+/// commits so an aborted or retried run leaves the cache as it was
+#[derive(Debug, Default)]
+pub(crate) struct CacheOps {
+    /// The cache epoch when the run started, None without a cache
+    epoch: Option<u64>,
+
+    /// Ids the run wrote or deleted
+    written: HashSet<ObjectID>,
+
+    /// Payloads the run read from sled
+    fills: Vec<(ObjectID, Bytes)>,
+}
+
+impl CacheOps {
+    pub(crate) fn new(epoch: Option<u64>) -> Self {
+        Self {
+            epoch,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the run wrote `id`, so the cache no longer matches what it sees
+    pub(crate) fn wrote(&self, id: ObjectID) -> bool {
+        self.written.contains(&id)
+    }
+
+    pub(crate) fn invalidate(&mut self, id: ObjectID) {
+        self.written.insert(id);
+    }
+
+    /// Remember a payload read from sled, unless the run wrote it itself
+    pub(crate) fn fill(&mut self, id: ObjectID, payload: Bytes) {
+        if !self.wrote(id) {
+            self.fills.push((id, payload));
+        }
+    }
+
+    /// Apply the run's reads and invalidations to `cache` after it committed
+    pub(crate) fn apply(self, cache: &Mutex<ObjectCache>) -> Result<()> {
+        let mut cache = cache.lock().map_err(|e| anyhow!("{e}"))?;
+        if let Some(epoch) = self.epoch {
+            for (id, payload) in self.fills {
+                cache.fill(id, payload, epoch);
+            }
+        }
+        for id in self.written {
+            cache.remove(id);
+        }
+        Ok(())
+    }
+}
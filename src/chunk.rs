@@ -0,0 +1,24 @@
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::object::ObjectID;
+
+/// Describes a payload stored in pieces in the chunks tree, see `InsertRequest::chunked`
+///
+/// The manifest is stored in the chunks tree under the object's id, and each chunk
+/// under `chunk_key`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChunkManifest {
+    /// Length of the whole payload
+    pub(crate) len: u64,
+
+    /// How many chunks the payload was split into
+    pub(crate) chunks: u64,
+}
+
+/// The key chunk `n` of object `id` is stored under in the chunks tree
+pub(crate) fn chunk_key(id: ObjectID, n: u64) -> anyhow::Result<Vec<u8>> {
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    (id, n).serialize(&mut s)?;
+    Ok(s.take_buffer())
+}
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::object::ObjectID;
+
+#[derive(Error, Debug)]
+pub enum MangoError {
+    #[error("path {0:?} is not accessible: {1}")]
+    PathNotAccessible(PathBuf, std::io::Error),
+
+    #[error("database at {0:?} is corrupt: {1}")]
+    Corrupt(PathBuf, sled::Error),
+
+    #[error("bucket name {0:?} is reserved")]
+    ReservedBucketName(String),
+
+    #[error("cannot move an object within bucket {0}")]
+    SameBucket(String),
+
+    #[error("bucket {0} already holds object id {1}")]
+    ObjectExists(String, ObjectID),
+
+    #[error("object id {0} shares its payload with {1} deduplicated inserts")]
+    SharedObject(ObjectID, u64),
+
+    #[error("sled error: {0}")]
+    SledError(#[from] sled::Error),
+}
+
+impl MangoError {
+    pub(crate) fn from_open(path: PathBuf, err: sled::Error) -> Self {
+        match err {
+            sled::Error::Io(e) => Self::PathNotAccessible(path, e),
+            e @ sled::Error::Corruption { .. } => Self::Corrupt(path, e),
+            e => Self::SledError(e),
+        }
+    }
+}
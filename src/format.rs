@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+
+/// The codec a Bucket stores its values with
+///
+/// Keys are always flexbuffers, so only values change with the format. The format is
+/// part of the BucketConfig and fixed when the bucket is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SerFormat {
+    #[default]
+    Flexbuffers,
+
+    /// Smaller values
+    Bincode,
+
+    /// Human-readable values, for debugging
+    Json,
+}
+
+impl SerFormat {
+    pub(crate) fn ser<T: Serialize>(&self, item: &T) -> Result<Vec<u8>> {
+        match self {
+            SerFormat::Flexbuffers => {
+                let mut s = flexbuffers::FlexbufferSerializer::new();
+                item.serialize(&mut s)?;
+                Ok(s.take_buffer())
+            }
+            SerFormat::Bincode => Ok(bincode::serialize(item)?),
+            SerFormat::Json => Ok(serde_json::to_vec(item)?),
+        }
+    }
+
+    pub(crate) fn de<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            SerFormat::Flexbuffers => Ok(flexbuffers::from_slice(bytes)?),
+            SerFormat::Bincode => Ok(bincode::deserialize(bytes)?),
+            SerFormat::Json => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+
+    /// Decode a stored object payload, falling back to flexbuffers and then bincode for
+    /// payloads written in another format, such as by older versions of this crate.
+    /// Fails with this format's error when none of them can read it.
+    pub(crate) fn de_payload(&self, bytes: &[u8]) -> Result<Bytes> {
+        let err = match self.de(bytes) {
+            Ok(payload) => return Ok(payload),
+            Err(e) => e,
+        };
+        for format in [SerFormat::Flexbuffers, SerFormat::Bincode] {
+            if format == *self {
+                continue;
+            }
+            if let Ok(payload) = format.de(bytes) {
+                log::debug!("decoded a {self:?} bucket payload as {format:?}");
+                return Ok(payload);
+            }
+        }
+        Err(err)
+    }
+
+    /// Where a stored object's payload sits within its serialized value, or None if
+    /// this format does not store payloads as contiguous bytes
+    pub(crate) fn payload_range(&self, bytes: &[u8]) -> Result<Option<(usize, usize)>> {
+        match self {
+            SerFormat::Flexbuffers => {
+                let blob = flexbuffers::Reader::get_root(bytes)?.get_blob()?;
+                let offset = blob.0.as_ptr() as usize - bytes.as_ptr() as usize;
+                Ok(Some((offset, blob.0.len())))
+            }
+            // A little-endian u64 length, then the bytes
+            SerFormat::Bincode => {
+                let len = Self::bincode_len(bytes)?;
+                match bytes.len().checked_sub(8) {
+                    Some(rest) if rest == len => Ok(Some((8, len))),
+                    _ => Err(anyhow!("bincode payload length does not match its value")),
+                }
+            }
+            SerFormat::Json => Ok(None),
+        }
+    }
+
+    /// The length of a serialized `Vec<ObjectID>`, without deserializing it where the
+    /// format allows
+    pub(crate) fn id_list_len(&self, bytes: &[u8]) -> Result<usize> {
+        match self {
+            SerFormat::Flexbuffers => Ok(flexbuffers::Reader::get_root(bytes)?.as_vector().len()),
+            SerFormat::Bincode => Self::bincode_len(bytes),
+            SerFormat::Json => Ok(self.de::<Vec<serde::de::IgnoredAny>>(bytes)?.len()),
+        }
+    }
+
+    /// The u64 length prefix bincode writes before sequences and byte strings
+    fn bincode_len(bytes: &[u8]) -> Result<usize> {
+        let prefix: [u8; 8] = bytes
+            .get(..8)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| anyhow!("bincode value is missing its length prefix"))?;
+        Ok(u64::from_le_bytes(prefix) as usize)
+    }
+}
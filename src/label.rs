@@ -3,10 +3,65 @@ use bytes::Bytes;
 use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 use sled::IVec;
-use std::hash::Hash;
+use std::{borrow::Borrow, fmt::Display, hash::Hash, str::FromStr};
 
 pub const SEPARATOR: &str = "\u{001F}";
 
+/// Marks a label value as holding a typed value rather than a plain string
+const TYPE_TAG: char = '\u{001E}';
+
+/// The value half of a label
+///
+/// Typed values are stored with a canonical encoding, so `Label::int("n", 13)` only
+/// matches other integer 13 labels and never the strings `"13"` or `"13.0"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl LabelValue {
+    /// The canonical string this value is indexed under
+    pub fn encode(&self) -> String {
+        match self {
+            LabelValue::Str(s) => s.clone(),
+            LabelValue::Int(i) => format!("{TYPE_TAG}i{i}"),
+            // -0.0 and 0.0 compare equal, so they share an encoding
+            LabelValue::Float(f) if *f == 0.0 => format!("{TYPE_TAG}f0"),
+            LabelValue::Float(f) => format!("{TYPE_TAG}f{f}"),
+            LabelValue::Bool(b) => format!("{TYPE_TAG}b{b}"),
+        }
+    }
+
+    /// Recover a value from its canonical encoding, falling back to a plain string
+    pub fn decode(encoded: &str) -> Self {
+        let Some(typed) = encoded.strip_prefix(TYPE_TAG) else {
+            return LabelValue::Str(encoded.to_string());
+        };
+        let parsed = match typed.split_at_checked(1) {
+            Some(("i", v)) => v.parse().ok().map(LabelValue::Int),
+            Some(("f", v)) => v.parse().ok().map(LabelValue::Float),
+            Some(("b", v)) => v.parse().ok().map(LabelValue::Bool),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| LabelValue::Str(encoded.to_string()))
+    }
+}
+
+/// The plain value, without the encoding's type tag
+impl Display for LabelValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LabelValue::Str(s) => write!(f, "{s}"),
+            LabelValue::Int(i) => write!(f, "{i}"),
+            LabelValue::Float(v) => write!(f, "{v}"),
+            LabelValue::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
 /// Labels are key=value pairs describing an Object.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Label(pub(crate) String, pub(crate) String);
@@ -17,6 +72,58 @@ impl Label {
         Self(lhs.to_string(), rhs.to_string())
     }
 
+    /// Create a label with a typed value
+    pub fn typed(key: &str, value: LabelValue) -> Self {
+        Self(key.to_string(), value.encode())
+    }
+
+    /// Create a label with an integer value
+    pub fn int(key: &str, value: i64) -> Self {
+        Self::typed(key, LabelValue::Int(value))
+    }
+
+    /// Create a label with a float value
+    pub fn float(key: &str, value: f64) -> Self {
+        Self::typed(key, LabelValue::Float(value))
+    }
+
+    /// Create a label with a boolean value
+    pub fn bool(key: &str, value: bool) -> Self {
+        Self::typed(key, LabelValue::Bool(value))
+    }
+
+    /// Parse a label from `key=value`, splitting at the first `=`
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Self::new(key, value)),
+            _ => Err(anyhow!("invalid label {s:?}, expected key=value")),
+        }
+    }
+
+    /// The key half of this label
+    pub fn key(&self) -> &str {
+        &self.0
+    }
+
+    /// The value half of this label, decoded to its type
+    pub fn value(&self) -> LabelValue {
+        LabelValue::decode(&self.1)
+    }
+
+    /// The value as an integer, whether typed with `Label::int` or a plain string
+    pub(crate) fn as_int(&self) -> Option<i64> {
+        match self.value() {
+            LabelValue::Int(i) => Some(i),
+            LabelValue::Str(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// This label with its key trimmed and lowercased
+    pub fn normalized(&self) -> Self {
+        Self(self.0.trim().to_lowercase(), self.1.clone())
+    }
+
     pub fn to_string_ltr(&self) -> String {
         format!("{}{SEPARATOR}{}", self.0, self.1)
     }
@@ -24,6 +131,34 @@ impl Label {
     pub fn to_string_rtl(&self) -> String {
         format!("{}{SEPARATOR}{}", self.1, self.0)
     }
+
+    /// The key this label is stored under in the labels and labels objects trees
+    pub(crate) fn key_ltr(&self) -> anyhow::Result<IVec> {
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        self.to_string_ltr().serialize(&mut s)?;
+        Ok(s.take_buffer().into())
+    }
+
+    /// Recover a label from its key in the labels or labels objects tree
+    pub(crate) fn from_key_ltr(key: &[u8]) -> anyhow::Result<Self> {
+        let key: String = flexbuffers::from_slice(key)?;
+        match key.split_once(SEPARATOR) {
+            Some((lhs, rhs)) => Ok(Self::new(lhs, rhs)),
+            None => Err(anyhow!("malformed label key {key:?}")),
+        }
+    }
+
+    /// The key this label is stored under in the label keys tree. Unlike the flexbuffers
+    /// keys of the other trees, these are raw bytes so a key's values share a prefix.
+    pub(crate) fn key_raw(&self) -> Vec<u8> {
+        self.to_string_ltr().into_bytes()
+    }
+
+    /// The key this label is stored under in the labels invert tree. Raw bytes like
+    /// `key_raw`, so the labels holding a value share a prefix.
+    pub(crate) fn key_rtl(&self) -> Vec<u8> {
+        self.to_string_rtl().into_bytes()
+    }
 }
 
 impl Hash for Label {
@@ -33,6 +168,71 @@ impl Hash for Label {
     }
 }
 
+impl AsRef<Label> for Label {
+    fn as_ref(&self) -> &Label {
+        self
+    }
+}
+
+/// A label's key and encoded value, however they are held
+///
+/// Maps and sets keyed by `Label` can be searched with any `LabelParts`, such as a
+/// `(&str, &str)` pair, without building a `Label`:
+/// `map.get(&("color", "red") as &dyn LabelParts)`.
+pub trait LabelParts {
+    fn parts(&self) -> (&str, &str);
+}
+
+impl LabelParts for Label {
+    fn parts(&self) -> (&str, &str) {
+        (&self.0, &self.1)
+    }
+}
+
+impl LabelParts for (&str, &str) {
+    fn parts(&self) -> (&str, &str) {
+        (self.0, self.1)
+    }
+}
+
+impl<'a> Borrow<dyn LabelParts + 'a> for Label {
+    fn borrow(&self) -> &(dyn LabelParts + 'a) {
+        self
+    }
+}
+
+/// Hashes the same as the `Label` with these parts
+impl Hash for dyn LabelParts + '_ {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        let (key, value) = self.parts();
+        key.hash(state);
+        value.hash(state);
+    }
+}
+
+impl PartialEq for dyn LabelParts + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for dyn LabelParts + '_ {}
+
+/// `key=value`, with a typed value shown decoded. Parsing it back gives a string value.
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.0, self.value())
+    }
+}
+
+impl FromStr for Label {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
 impl TryFrom<String> for Label {
     type Error = anyhow::Error;
 
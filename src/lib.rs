@@ -1,32 +1,48 @@
 pub mod bucket;
+mod cache;
+mod chunk;
+pub mod error;
+pub mod format;
 pub mod label;
 pub mod mango;
 pub mod object;
 pub mod query;
+pub mod watch;
 
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
-    use std::env;
+    use std::{
+        env,
+        sync::{Mutex, Once},
+    };
 
     use anyhow::{anyhow, Result};
     use bytes::Bytes;
     use flexbuffers::FlexbufferSerializer;
     use log::LevelFilter;
     use simplelog::{CombinedLogger, TermLogger, TerminalMode};
+    use sled::Transactional;
     use walkdir::WalkDir;
 
     use crate::{
-        label::Label,
+        bucket::{id_key, Bucket, BucketConfig, BucketDiff, CONFIG_KEY},
+        error::MangoError,
+        format::SerFormat,
         label::SEPARATOR as LabelSep,
-        mango::Mango,
-        object::Object,
+        label::{Label, LabelValue},
+        mango::{Mango, MangoConfig, VerifyReport},
+        object::{Object, ObjectID},
         query::{
-            find::FindRequest,
+            cswap::CswapRequest,
+            delete::DeleteRequest,
+            error::TransactionError,
+            find::{FindRequest, LabelCache, LabelGroup, ObjectTimes, OrderKey, PlanStep},
             get::GetRequest,
-            insert::InsertRequest,
-            transaction::{Request, Transaction},
+            insert::{CollisionPolicy, InsertRequest},
+            transaction::{Request, RequestResult, Transaction},
         },
+        watch::ChangeEvent,
     };
 
     fn ser<T: serde::Serialize>(item: T) -> Result<Bytes> {
@@ -39,18 +55,98 @@ mod tests {
         Ok(flexbuffers::from_slice(&bytes)?)
     }
 
+    /// Open the Mango at `path` again once a dropped handle has released its file lock.
+    ///
+    /// sled's background threads can hold the lock briefly after the last Db is dropped.
+    fn reopen(path: &std::path::Path) -> Result<Mango> {
+        reopen_with(|| Mango::new(path))
+    }
+
+    /// Like `reopen`, for databases opened through another constructor
+    fn reopen_with(open: impl Fn() -> Result<Mango>) -> Result<Mango> {
+        let mut attempts = 0;
+        loop {
+            match open() {
+                Err(e)
+                    if attempts < 50
+                        && matches!(
+                            e.downcast_ref::<MangoError>(),
+                            Some(MangoError::PathNotAccessible(..))
+                        ) =>
+                {
+                    attempts += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Insert objects with the given ids and labels in one transaction
+    fn insert_labeled(bucket: &Bucket, objects: Vec<(ObjectID, Vec<Label>)>) -> Result<()> {
+        let tx: Transaction = bucket.into();
+        for (id, labels) in objects {
+            let req = InsertRequest::new_static_id(id, Bytes::from(format!("object {id}")))?;
+            req.add_labels(labels)?;
+            tx.append_request(req.into())?;
+        }
+        tx.execute()
+    }
+
+    /// Run a FindRequest and return the sorted ids it matched
+    fn find_ids(bucket: &Bucket, find: FindRequest) -> Result<Vec<ObjectID>> {
+        let tx: Transaction = bucket.into();
+        tx.append_request(find.into())?;
+        tx.execute()?;
+        let mut ids: Vec<ObjectID> = tx.find_results()?[0].iter().map(|(id, _)| *id).collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    static LOGGER: Once = Once::new();
+    static CAPTURED: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    /// Forwards to the terminal logger and keeps each message for assertions
+    struct CaptureLogger(Box<CombinedLogger>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            self.0.enabled(metadata)
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target().starts_with("libmangochainsaw") {
+                CAPTURED.lock().unwrap().push(record.args().to_string());
+            }
+            self.0.log(record)
+        }
+
+        fn flush(&self) {
+            self.0.flush()
+        }
+    }
+
+    /// Install the test logger once for the whole test binary
+    fn init_logger() {
+        LOGGER.call_once(|| {
+            let inner = CombinedLogger::new(vec![TermLogger::new(
+                LevelFilter::Trace,
+                simplelog::ConfigBuilder::new()
+                    .set_thread_level(LevelFilter::Trace)
+                    .set_thread_mode(simplelog::ThreadLogMode::Both)
+                    .add_filter_ignore_str("sled")
+                    .build(),
+                TerminalMode::Mixed,
+                simplelog::ColorChoice::Auto,
+            )]);
+            log::set_boxed_logger(Box::new(CaptureLogger(inner))).expect("logger already set");
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
     #[test]
     fn test_full() -> Result<()> {
-        CombinedLogger::init(vec![TermLogger::new(
-            LevelFilter::Trace,
-            simplelog::ConfigBuilder::new()
-                .set_thread_level(LevelFilter::Trace)
-                .set_thread_mode(simplelog::ThreadLogMode::Both)
-                .add_filter_ignore_str("sled")
-                .build(),
-            TerminalMode::Mixed,
-            simplelog::ColorChoice::Auto,
-        )])?;
+        init_logger();
 
         let mango = Mango::new_temp()?;
         let bucket = mango.get_bucket("testing")?;
@@ -161,4 +257,3306 @@ mod tests {
             _ => Err(anyhow!("shit 2")),
         }
     }
+    #[test]
+    fn test_watch_label() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("watch")?;
+        let label = Label::new("animal", "dog");
+        let mut watcher = bucket.watch_label(label.clone())?;
+
+        let writer = bucket.clone();
+        let handle = std::thread::spawn(move || -> Result<()> {
+            let tx: Transaction = (&writer).into();
+            let req = InsertRequest::new_static_id(7, Bytes::from_static(b"woof"))?;
+            req.add_label(label)?;
+            tx.append_request(req.into())?;
+            tx.execute()
+        });
+        handle.join().expect("writer thread panicked")?;
+
+        let event = watcher.next_timeout(std::time::Duration::from_secs(5));
+        assert_eq!(event, Some(ChangeEvent::Inserted(7)));
+        Ok(())
+    }
+    #[test]
+    fn test_watch_label_normalized() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let config = BucketConfig {
+            normalize_keys: true,
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("watch", config)?;
+        let mut watcher = bucket.watch_label(Label::new("Animal ", "dog"))?;
+
+        insert_labeled(&bucket, vec![(7, vec![Label::new("ANIMAL", "dog")])])?;
+
+        let event = watcher.next_timeout(std::time::Duration::from_secs(5));
+        assert_eq!(event, Some(ChangeEvent::Inserted(7)));
+        Ok(())
+    }
+    #[test]
+    fn test_mango_new_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+
+        {
+            let mango = Mango::new(&path)?;
+            let bucket = mango.get_bucket("persist")?;
+            let tx: Transaction = (&bucket).into();
+            let req = InsertRequest::new_static_id(1, Bytes::from_static(b"still here"))?;
+            req.add_label(Label::new("state", "saved"))?;
+            tx.append_request(req.into())?;
+            tx.execute()?;
+        }
+
+        let mango = reopen(&path)?;
+        let bucket = mango.get_bucket("persist")?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(GetRequest::new(vec![1])?.into())?;
+        tx.execute()?;
+        match tx.results()?.first() {
+            Some(RequestResult::Get(_, Ok(res))) => {
+                assert_eq!(res, &vec![(1, Bytes::from_static(b"still here"))]);
+            }
+            _ => return Err(anyhow!("missing get result")),
+        }
+
+        let file = dir.path().join("not_a_dir");
+        std::fs::write(&file, b"")?;
+        let err = Mango::new(&file).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::PathNotAccessible(..))
+        ));
+        Ok(())
+    }
+    #[test]
+    fn test_mango_new_memory() -> Result<()> {
+        let mango = Mango::new_memory()?;
+        let bucket = mango.get_bucket("cache")?;
+
+        let tx: Transaction = (&bucket).into();
+        let req = InsertRequest::new_static_id(3, Bytes::from_static(b"ephemeral"))?;
+        req.add_label(Label::new("kind", "cache"))?;
+        tx.append_request(req.into())?;
+        tx.execute()?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("kind", "cache")])?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(find.into())?;
+        tx.execute()?;
+        match tx.results()?.first() {
+            Some(RequestResult::Find(_, Ok(res))) => {
+                assert_eq!(res, &vec![(3, vec![Label::new("kind", "cache")])]);
+            }
+            _ => return Err(anyhow!("missing find result")),
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_insert_dedupe_by_content() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("dedupe")?;
+        let payload = Bytes::from_static(b"same bytes");
+
+        let first = InsertRequest::new_static_id(1, payload.clone())?;
+        first.add_label(Label::new("source", "a"))?;
+        let second = InsertRequest::new_static_id(2, payload)?;
+        second.add_label(Label::new("source", "b"))?;
+        second.dedupe_by_content(true)?;
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(first.into())?;
+        tx.append_request(second.into())?;
+        tx.execute()?;
+        let ids: Vec<_> = tx
+            .results()?
+            .into_iter()
+            .map(|r| match r {
+                RequestResult::Insert(_, Ok(id)) => Ok(id),
+                _ => Err(anyhow!("unexpected result")),
+            })
+            .collect::<Result<_>>()?;
+        assert_eq!(ids, vec![1, 1]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("source", "a"), Label::new("source", "b")])?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(find.into())?;
+        tx.execute()?;
+        match tx.results()?.first() {
+            Some(RequestResult::Find(_, Ok(res))) => assert_eq!(
+                res,
+                &vec![(
+                    1,
+                    vec![Label::new("source", "a"), Label::new("source", "b")]
+                )]
+            ),
+            _ => return Err(anyhow!("missing find result")),
+        }
+        assert_eq!(bucket.t_objects.len(), 1);
+        Ok(())
+    }
+    #[test]
+    fn test_query_all_buckets() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let shared = Label::new("tier", "gold");
+
+        for (name, ids) in [
+            ("tenant_a", vec![1, 2]),
+            ("tenant_b", vec![3]),
+            ("tenant_c", vec![]),
+        ] {
+            let bucket = mango.get_bucket(name)?;
+            let tx: Transaction = (&bucket).into();
+            for id in ids {
+                let req = InsertRequest::new_static_id(id, Bytes::from(format!("{name}/{id}")))?;
+                req.add_label(shared.clone())?;
+                tx.append_request(req.into())?;
+            }
+            let other = InsertRequest::new_static_id(99, Bytes::from_static(b"other"))?;
+            other.add_label(Label::new("tier", "silver"))?;
+            tx.append_request(other.into())?;
+            tx.execute()?;
+        }
+
+        let results = mango.query_all_buckets(shared)?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results["tenant_a"], vec![1, 2]);
+        assert_eq!(results["tenant_b"], vec![3]);
+        assert!(results["tenant_c"].is_empty());
+        Ok(())
+    }
+    #[test]
+    fn test_find_include_all_planner() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("planner")?;
+        let common = Label::new("kind", "common");
+        let rare = Label::new("kind", "rare");
+
+        let tx: Transaction = (&bucket).into();
+        for id in 0..100 {
+            let req = InsertRequest::new_static_id(id, Bytes::from(id.to_string()))?;
+            req.add_label(common.clone())?;
+            if id % 50 == 0 {
+                req.add_label(rare.clone())?;
+            }
+            tx.append_request(req.into())?;
+        }
+        tx.execute()?;
+
+        let labels = vec![common.clone(), rare.clone()];
+        let run = |plan: bool| {
+            bucket.t_labels_objects.transaction(|t| {
+                Ok::<_, sled::transaction::ConflictableTransactionError<()>>(
+                    FindRequest::intersect(
+                        &mut LabelCache::new(t, SerFormat::default()),
+                        &labels,
+                        plan,
+                    )?,
+                )
+            })
+        };
+        let (unplanned, unplanned_checks) = run(false).map_err(|e| anyhow!("{e:?}"))?;
+        let (planned, planned_checks) = run(true).map_err(|e| anyhow!("{e:?}"))?;
+        assert_eq!(planned, unplanned);
+        assert_eq!(planned_checks, 2);
+        assert_eq!(unplanned_checks, 100);
+
+        let find = FindRequest::new()?;
+        find.add_include_all_group(labels)?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(find.into())?;
+        tx.execute()?;
+        match tx.results()?.first() {
+            Some(RequestResult::Find(_, Ok(res))) => {
+                let mut ids: Vec<_> = res.iter().map(|(id, _)| *id).collect();
+                ids.sort();
+                assert_eq!(ids, vec![0, 50]);
+            }
+            _ => return Err(anyhow!("missing find result")),
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_object_attrs() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("attrs")?;
+
+        let req = InsertRequest::new_static_id(5, Bytes::from_static(b"report"))?;
+        req.add_label(Label::new("kind", "report"))?;
+        req.set_attr("original_filename", "q3.pdf")?;
+        req.set_attr("uploaded_by", "ops")?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(req.into())?;
+        tx.execute()?;
+
+        let attrs = bucket.attrs(5)?;
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs["original_filename"], "q3.pdf");
+        assert_eq!(attrs["uploaded_by"], "ops");
+        assert!(bucket.attrs(6)?.is_empty());
+        Ok(())
+    }
+    #[test]
+    fn test_request_result_helpers() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("helpers")?;
+
+        let req = InsertRequest::new_static_id(11, Bytes::from_static(b"eleven"))?;
+        req.add_label(Label::new("n", "odd"))?;
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("n", "odd")])?;
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(req.into())?;
+        tx.append_request(GetRequest::new(vec![11])?.into())?;
+        tx.append_request(find.into())?;
+        tx.execute()?;
+
+        let results = tx.results()?;
+        assert_eq!(results[0].as_insert_id(), Some(11));
+        assert!(results[0].as_find().is_none());
+        assert_eq!(
+            results[1].as_get(),
+            Some(&vec![(11, Bytes::from_static(b"eleven"))])
+        );
+        assert_eq!(
+            tx.find_results()?,
+            vec![vec![(11, vec![Label::new("n", "odd")])]]
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_bucket_get_ivec() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("ivec")?;
+        let payload: Vec<u8> = (0..=255).cycle().take(4096).collect();
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(InsertRequest::new_static_id(1, payload.clone().into())?.into())?;
+        tx.execute()?;
+
+        let ivec = bucket.get_ivec(1)?.expect("object should exist");
+        assert_eq!(ivec.as_ref(), payload.as_slice());
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from(payload)));
+        assert!(bucket.get_ivec(2)?.is_none());
+        Ok(())
+    }
+    #[test]
+    fn test_insert_collision_policy() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let occupant = Bytes::from_static(b"already here");
+        let incoming = Bytes::from_static(b"hashes to the same id");
+        let id = Object::new(incoming.clone()).hash_id();
+
+        for (n, policy) in [
+            CollisionPolicy::Overwrite,
+            CollisionPolicy::Reject,
+            CollisionPolicy::Rehash,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let bucket = mango.get_bucket(&format!("collision{n}"))?;
+            // Force a collision by parking different bytes at the incoming payload's hash id
+            let tx: Transaction = (&bucket).into();
+            tx.append_request(InsertRequest::new_static_id(id, occupant.clone())?.into())?;
+            tx.execute()?;
+
+            let req = InsertRequest::new(incoming.clone())?;
+            req.collision_policy(policy)?;
+            let tx: Transaction = (&bucket).into();
+            tx.append_request(req.into())?;
+            let executed = tx.execute();
+
+            match policy {
+                CollisionPolicy::Overwrite => {
+                    executed?;
+                    assert_eq!(tx.results()?[0].as_insert_id(), Some(id));
+                    assert_eq!(bucket.get_object(id)?, Some(incoming.clone()));
+                }
+                CollisionPolicy::Reject => {
+                    assert!(matches!(
+                        executed.unwrap_err().downcast_ref::<TransactionError>(),
+                        Some(TransactionError::ObjectCollision(collided)) if *collided == id
+                    ));
+                    assert_eq!(bucket.get_object(id)?, Some(occupant.clone()));
+                }
+                CollisionPolicy::Rehash => {
+                    executed?;
+                    let new_id = tx.results()?[0].as_insert_id().unwrap();
+                    assert_ne!(new_id, id);
+                    assert_eq!(bucket.get_object(id)?, Some(occupant.clone()));
+                    assert_eq!(bucket.get_object(new_id)?, Some(incoming.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_transaction_correlation_id() -> Result<()> {
+        init_logger();
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("correlation")?;
+
+        let tx: Transaction = (&bucket).into();
+        assert_eq!(tx.correlation_id()?, None);
+        for id in 0..2 {
+            let req = InsertRequest::new_static_id(id, Bytes::from(id.to_string()))?;
+            req.add_label(Label::new("batch", "one"))?;
+            tx.append_request(req.into())?;
+        }
+        tx.execute()?;
+
+        let id = tx
+            .correlation_id()?
+            .expect("executed transaction has an id");
+        let prefix = format!("[tx {id}] ");
+        let lines = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|l| l.starts_with(&prefix))
+            .count();
+        assert!(
+            lines >= 4,
+            "expected several lines tagged {prefix}, got {lines}"
+        );
+
+        // Reads run in parallel on rayon workers and still carry the id
+        let tx: Transaction = (&bucket).into();
+        for id in 0..4 {
+            tx.append_request(GetRequest::new(vec![id % 2])?.into())?;
+        }
+        tx.execute()?;
+        let id = tx
+            .correlation_id()?
+            .expect("executed transaction has an id");
+        let tagged = format!("[tx {id}] completed request");
+        let lines = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|l| l.starts_with(&tagged))
+            .count();
+        assert_eq!(lines, 4);
+        Ok(())
+    }
+    #[test]
+    fn test_mango_space_usage() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        mango.get_bucket("empty")?;
+        let full = mango.get_bucket("full")?;
+
+        let tx: Transaction = (&full).into();
+        for id in 0..10 {
+            let req = InsertRequest::new_static_id(id, Bytes::from(vec![id as u8; 1024]))?;
+            req.add_label(Label::new("size", "1k"))?;
+            tx.append_request(req.into())?;
+        }
+        tx.execute()?;
+
+        // An empty bucket only holds its config
+        let config_len = CONFIG_KEY.len() + BucketConfig::default().encode()?.len();
+        let usage = mango.space_usage()?;
+        assert_eq!(usage["empty"], config_len as u64);
+        assert!(usage["full"] > 10 * 1024);
+        Ok(())
+    }
+    #[test]
+    fn test_mango_snapshot_round_trip() -> Result<()> {
+        let source = Mango::new_temp()?;
+        for name in ["alpha", "beta"] {
+            let bucket = source.get_bucket(name)?;
+            let tx: Transaction = (&bucket).into();
+            for id in 0..3 {
+                let req = InsertRequest::new_static_id(id, Bytes::from(format!("{name}-{id}")))?;
+                req.add_label(Label::new("bucket", name))?;
+                tx.append_request(req.into())?;
+            }
+            tx.execute()?;
+        }
+
+        let mut snapshot = vec![];
+        let written = source.snapshot_to(&mut snapshot)?;
+        assert_eq!(written, snapshot.len() as u64);
+
+        let restored = Mango::new_temp()?;
+        assert!(restored.restore_from(snapshot.as_slice())? > 0);
+        assert_eq!(restored.list_buckets()?, vec!["alpha", "beta"]);
+        for name in ["alpha", "beta"] {
+            let bucket = restored.get_bucket(name)?;
+            for id in 0..3 {
+                assert_eq!(
+                    bucket.get_object(id)?,
+                    Some(Bytes::from(format!("{name}-{id}")))
+                );
+            }
+            let mut ids = bucket.object_ids_for_label(&Label::new("bucket", name))?;
+            ids.sort();
+            assert_eq!(ids, vec![0, 1, 2]);
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_find_any_of() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("any_of")?;
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![Label::new("content_type", "library")]),
+                (2, vec![Label::new("content_type", "module")]),
+                (3, vec![Label::new("content_type", "cargo")]),
+                (4, vec![Label::new("content_type", "none")]),
+                (
+                    5,
+                    vec![
+                        Label::new("content_type", "library"),
+                        Label::new("skip", "yes"),
+                    ],
+                ),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.any_of("content_type", &["library", "module", "cargo"])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 3, 5]);
+
+        let find = FindRequest::new()?;
+        find.any_of("content_type", &["library", "module", "cargo"])?;
+        find.add_exclude_group(vec![Label::new("skip", "yes")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 3]);
+        Ok(())
+    }
+    #[test]
+    fn test_find_all_of() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("all_of")?;
+        let dog = Label::new("animal", "dog");
+        let good = Label::new("temperament", "good");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![dog.clone(), good.clone()]),
+                (2, vec![dog.clone()]),
+                (3, vec![good.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.all_of(&[dog, good])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        Ok(())
+    }
+    #[test]
+    fn test_label_cardinality() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("cardinality")?;
+        let common = Label::new("color", "blue");
+        let uncommon = Label::new("color", "green");
+        let rare = Label::new("color", "mauve");
+        insert_labeled(
+            &bucket,
+            (0..20)
+                .map(|id| {
+                    let mut labels = vec![common.clone()];
+                    if id % 4 == 0 {
+                        labels.push(uncommon.clone());
+                    }
+                    if id == 7 {
+                        labels.push(rare.clone());
+                    }
+                    (id, labels)
+                })
+                .collect(),
+        )?;
+
+        assert_eq!(bucket.label_cardinality(&common)?, 20);
+        assert_eq!(bucket.label_cardinality(&uncommon)?, 5);
+        assert_eq!(bucket.label_cardinality(&rare)?, 1);
+        assert_eq!(bucket.label_cardinality(&Label::new("color", "none"))?, 0);
+        assert_eq!(
+            bucket.rarest_label(&[common.clone(), rare.clone(), uncommon])?,
+            Some(rare)
+        );
+        assert_eq!(bucket.rarest_label(&[])?, None);
+        Ok(())
+    }
+    #[test]
+    fn test_mango_move_object() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let from = mango.get_bucket("inbox")?;
+        let to = mango.get_bucket("archive")?;
+        let filed = Label::new("status", "filed");
+        insert_labeled(
+            &from,
+            vec![
+                (1, vec![filed.clone()]),
+                (2, vec![Label::new("status", "new")]),
+            ],
+        )?;
+
+        mango.move_object(&from, &to, 1)?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![filed.clone()])?;
+        assert_eq!(find_ids(&to, find)?, vec![1]);
+        assert_eq!(to.get_object(1)?, Some(Bytes::from("object 1")));
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![filed.clone()])?;
+        assert!(find_ids(&from, find)?.is_empty());
+        assert_eq!(from.get_object(1)?, None);
+        assert_eq!(from.label_cardinality(&filed)?, 0);
+        assert!(!from.t_labels.contains_key(filed.key_ltr()?)?);
+        assert_eq!(from.get_object(2)?, Some(Bytes::from("object 2")));
+        Ok(())
+    }
+    #[test]
+    fn test_mango_move_object_rejected() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let from = mango.get_bucket("inbox")?;
+        let to = mango.get_bucket("archive")?;
+        insert_labeled(&from, vec![(1, vec![Label::new("status", "new")])])?;
+        insert_labeled(&to, vec![(1, vec![Label::new("status", "old")])])?;
+
+        // Moving within one bucket would delete the object
+        let err = mango.move_object(&from, &from.clone(), 1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::SameBucket(name)) if name == "inbox"
+        ));
+        assert_eq!(from.get_object(1)?, Some(Bytes::from("object 1")));
+
+        // The object already stored at the id in `to` is not overwritten
+        let err = mango.move_object(&from, &to, 1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::ObjectExists(name, 1)) if name == "archive"
+        ));
+        assert_eq!(to.labels(1)?, vec![Label::new("status", "old")]);
+        assert_eq!(from.labels(1)?, vec![Label::new("status", "new")]);
+
+        // A payload shared through dedup would stay in `from`
+        let payload = Bytes::from_static(b"shared");
+        let first = InsertRequest::new_static_id(2, payload.clone())?;
+        let second = InsertRequest::new_static_id(3, payload)?;
+        second.dedupe_by_content(true)?;
+        from.run(vec![first.into(), second.into()])?;
+        let err = mango.move_object(&from, &to, 2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::SharedObject(2, 2))
+        ));
+        assert_eq!(from.get_object(2)?, Some(Bytes::from_static(b"shared")));
+        assert_eq!(to.get_object(2)?, None);
+        Ok(())
+    }
+    #[test]
+    fn test_find_order_by() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("order")?;
+        let all = Label::new("set", "all");
+        insert_labeled(
+            &bucket,
+            vec![
+                (3, vec![all.clone(), Label::new("name", "alice")]),
+                (1, vec![all.clone(), Label::new("name", "carol")]),
+                (4, vec![all.clone()]),
+                (2, vec![all.clone(), Label::new("name", "bob")]),
+            ],
+        )?;
+
+        let ordered = |key: OrderKey| -> Result<Vec<ObjectID>> {
+            let find = FindRequest::new()?;
+            find.add_include_group(vec![all.clone()])?;
+            find.order_by(key)?;
+            let tx: Transaction = (&bucket).into();
+            tx.append_request(find.into())?;
+            tx.execute()?;
+            Ok(tx.find_results()?[0].iter().map(|(id, _)| *id).collect())
+        };
+
+        assert_eq!(ordered(OrderKey::IdAsc)?, vec![1, 2, 3, 4]);
+        assert_eq!(ordered(OrderKey::IdDesc)?, vec![4, 3, 2, 1]);
+        assert_eq!(
+            ordered(OrderKey::LabelValue("name".to_string()))?,
+            vec![3, 2, 1, 4]
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_bucket_run() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("run")?;
+        let label = Label::new("via", "run");
+
+        let insert = InsertRequest::new_static_id(8, Bytes::from_static(b"eight"))?;
+        insert.add_label(label.clone())?;
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![label.clone()])?;
+
+        let results = bucket.run(vec![insert.into(), find.into()])?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_insert_id(), Some(8));
+        assert_eq!(results[1].as_find(), Some(&vec![(8, vec![label])]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_refcount() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("refcount")?;
+        let payload = Bytes::from_static(b"shared");
+
+        let first = InsertRequest::new_static_id(1, payload.clone())?;
+        first.add_label(Label::new("source", "a"))?;
+        let second = InsertRequest::new_static_id(2, payload)?;
+        second.add_label(Label::new("source", "b"))?;
+        second.dedupe_by_content(true)?;
+        let results = bucket.run(vec![first.into(), second.into()])?;
+        assert_eq!(results[1].as_insert_id(), Some(1));
+
+        // The first delete only releases a reference, labels from both inserts stay
+        let results = bucket.run(vec![DeleteRequest::new(vec![1]).into()])?;
+        assert_eq!(results[0].as_delete(), Some(&vec![(1, false)]));
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"shared")));
+        for value in ["a", "b"] {
+            let find = FindRequest::new()?;
+            find.add_include_group(vec![Label::new("source", value)])?;
+            assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        }
+
+        let results = bucket.run(vec![DeleteRequest::new(vec![1]).into()])?;
+        assert_eq!(results[0].as_delete(), Some(&vec![(1, true)]));
+        assert_eq!(bucket.get_object(1)?, None);
+        assert!(bucket.t_content_hashes.is_empty());
+        assert!(bucket.t_content_refcount.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_labels() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("typed")?;
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![Label::int("n", 13)]),
+                (2, vec![Label::new("n", "13.0")]),
+                (3, vec![Label::float("n", 13.0)]),
+                (4, vec![Label::bool("flag", true)]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::int("n", 13)])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("n", "13.0")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![2]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::float("n", 13.00)])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![3]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::bool("flag", true)])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![4]);
+
+        assert_eq!(Label::int("n", 13).value(), LabelValue::Int(13));
+        assert_eq!(Label::float("n", -0.0), Label::float("n", 0.0));
+        assert_eq!(
+            Label::new("n", "13").value(),
+            LabelValue::Str("13".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_bucket_transaction() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("dropped")?;
+        bucket.empty()?;
+
+        let insert = InsertRequest::new_static_id(1, Bytes::from_static(b"late"))?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(insert.into())?;
+        let err = tx
+            .execute()
+            .expect_err("dropped bucket accepted a transaction");
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::BucketDropped(name)) if name == "dropped"
+        ));
+        assert!(mango.list_buckets()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_bucket_shared_by_handles() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("dropped")?;
+        let clone = bucket.clone();
+        let other = mango.get_bucket("dropped")?;
+        mango.empty_bucket("dropped")?;
+        assert!(!bucket.check()?);
+        assert!(!clone.check()?);
+        assert!(!other.check()?);
+
+        let insert = InsertRequest::new_static_id(1, Bytes::from_static(b"late"))?;
+        let tx: Transaction = (&clone).into();
+        tx.append_request(insert.into())?;
+        let err = tx
+            .execute()
+            .expect_err("dropped bucket accepted a transaction");
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::BucketDropped(name)) if name == "dropped"
+        ));
+
+        // Opening the bucket again creates it anew without reviving the old handles
+        let reopened = mango.get_bucket("dropped")?;
+        assert!(reopened.check()?);
+        assert!(!clone.check()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_reads() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("parallel")?;
+        let objects = (0..64)
+            .map(|id| (id, vec![Label::new("parity", &(id % 2).to_string())]))
+            .collect();
+        insert_labeled(&bucket, objects)?;
+
+        let mut reads: Vec<Request> = (0..64)
+            .rev()
+            .map(|id| Ok(GetRequest::new(vec![id])?.into()))
+            .collect::<Result<_>>()?;
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("parity", "1")])?;
+        find.order_by(OrderKey::IdAsc)?;
+        reads.push(find.into());
+
+        let parallel = bucket.run(reads.clone())?;
+
+        // An insert forces the whole batch through the serial transaction
+        let mut serial_reqs = reads;
+        serial_reqs.push(InsertRequest::new_static_id(100, Bytes::from_static(b"x"))?.into());
+        let serial = bucket.run(serial_reqs)?;
+
+        assert_eq!(parallel.len(), 65);
+        for (id, (p, s)) in (0..64).rev().zip(parallel.iter().zip(serial.iter())) {
+            let expected = vec![(id, Bytes::from(format!("object {id}")))];
+            assert_eq!(p.as_get(), Some(&expected));
+            assert_eq!(s.as_get(), Some(&expected));
+        }
+        assert_eq!(parallel[64].as_find(), serial[64].as_find());
+        assert_eq!(parallel[64].as_find().map(|r| r.len()), Some(32));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reinsert_diffs_labels() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("reinsert")?;
+        let kept = Label::new("color", "red");
+        let dropped = Label::new("size", "large");
+        insert_labeled(&bucket, vec![(1, vec![kept.clone(), dropped.clone()])])?;
+        insert_labeled(&bucket, vec![(1, vec![kept.clone()])])?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![dropped.clone()])?;
+        assert!(find_ids(&bucket, find)?.is_empty());
+        assert_eq!(bucket.label_cardinality(&dropped)?, 0);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![kept.clone()])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        assert_eq!(bucket.label_cardinality(&kept)?, 1);
+        assert_eq!(bucket.labels(1)?, vec![kept]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_config_persists() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        let config = BucketConfig {
+            dedupe_by_content: true,
+            ..Default::default()
+        };
+
+        {
+            let mango = Mango::new(&path)?;
+            let bucket = mango.get_bucket_with_config("configured", config.clone())?;
+            assert_eq!(bucket.config()?, config);
+        }
+
+        let mango = reopen(&path)?;
+        let bucket = mango.get_bucket("configured")?;
+        assert_eq!(bucket.config()?, config);
+
+        // Dedup applies without the request asking for it
+        let payload = Bytes::from_static(b"twice");
+        let results = bucket.run(vec![
+            InsertRequest::new_static_id(1, payload.clone())?.into(),
+            InsertRequest::new_static_id(2, payload)?.into(),
+        ])?;
+        assert_eq!(results[1].as_insert_id(), Some(1));
+        assert_eq!(bucket.t_objects.len(), 1);
+        assert_eq!(
+            mango.get_bucket("plain")?.config()?,
+            BucketConfig::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_value_equals() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("values")?;
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![Label::new("animal", "dog")]),
+                (2, vec![Label::new("pet", "dog")]),
+                (3, vec![Label::new("animal", "cat")]),
+                (4, vec![Label::new("breed", "dogue")]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.value_equals("dog")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2]);
+
+        let find = FindRequest::new()?;
+        find.value_equals("dog")?;
+        find.add_exclude_group(vec![Label::new("pet", "dog")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+
+        // Labels inserted earlier in the same transaction match too
+        let insert = InsertRequest::new_static_id(5, Bytes::from_static(b"wolf"))?;
+        insert.add_label(Label::new("wild", "dog"))?;
+        let find = FindRequest::new()?;
+        find.value_equals("dog")?;
+        let results = bucket.run(vec![insert.into(), find.into()])?;
+        let mut ids: Vec<ObjectID> = results[1]
+            .as_find()
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 5]);
+
+        // Inverse keys written as serialized strings are rewritten when the bucket opens
+        for kv in bucket.t_labels_invert.iter() {
+            let (k, v) = kv?;
+            let label: Label = de(v.to_vec().into())?;
+            bucket.t_labels_invert.remove(k)?;
+            bucket
+                .t_labels_invert
+                .insert(&ser(label.to_string_rtl())?, v)?;
+        }
+        let bucket = mango.get_bucket("values")?;
+        assert!(bucket
+            .t_labels_invert
+            .contains_key(Label::new("pet", "dog").key_rtl())?);
+        let find = FindRequest::new()?;
+        find.value_equals("dog")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_from_reader() -> Result<()> {
+        let payload = b"streamed payload".to_vec();
+        let object = Object::from_reader(std::io::Cursor::new(payload.clone()))?;
+        assert_eq!(object.get_inner(), Bytes::from(payload.clone()));
+
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("reader")?;
+        let req = InsertRequest::from_reader(std::io::Cursor::new(payload.clone()))?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(req.into())?;
+        tx.execute()?;
+        assert_eq!(
+            bucket.get_object(object.hash_id())?,
+            Some(Bytes::from(payload))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_rollback() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("rollback")?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(InsertRequest::new_static_id(1, Bytes::from_static(b"one"))?.into())?;
+        tx.append_request(InsertRequest::new_static_id(2, Bytes::from_static(b"two"))?.into())?;
+        assert_eq!(tx.len()?, 2);
+
+        tx.rollback()?;
+        assert_eq!(tx.len()?, 0);
+        tx.execute()?;
+        assert!(bucket.get_object(1)?.is_none());
+
+        let err = tx
+            .rollback()
+            .expect_err("rolled back an executed transaction");
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::AlreadyExecuted)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_keys() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let config = BucketConfig {
+            normalize_keys: true,
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("normalized", config)?;
+        insert_labeled(
+            &bucket,
+            vec![(1, vec![Label::new(" Content_Type", "library")])],
+        )?;
+        assert_eq!(
+            bucket.labels(1)?,
+            vec![Label::new("content_type", "library")]
+        );
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("content_type", "library")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("CONTENT_TYPE", "library")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        assert_eq!(
+            bucket.label_cardinality(&Label::new("Content_Type", "library"))?,
+            1
+        );
+        let rarest = bucket.rarest_label(&[
+            Label::new("Content_Type", "library"),
+            Label::new("content_type", "binary"),
+        ])?;
+        assert_eq!(rarest, Some(Label::new("content_type", "binary")));
+
+        // Values keep their case
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("content_type", "Library")])?;
+        assert!(find_ids(&bucket, find)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_bucket_if_exists() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let trees = mango.inner.tree_names().len();
+        assert!(mango.open_bucket_if_exists("missing")?.is_none());
+        assert_eq!(mango.inner.tree_names().len(), trees);
+
+        mango.get_bucket("present")?;
+        let bucket = mango.open_bucket_if_exists("present")?;
+        assert_eq!(bucket.map(|b| b.name), Some("present".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_by_content_hash() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("content")?;
+        let payload = Bytes::from_static(b"addressed by content");
+        let hash = Object::new(payload.clone()).hash_id();
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(InsertRequest::new(payload.clone())?.into())?;
+        tx.append_request(InsertRequest::new_static_id(7, Bytes::from_static(b"by id"))?.into())?;
+        tx.execute()?;
+
+        assert_eq!(bucket.get_by_content_hash(hash)?, Some(payload));
+        assert!(bucket.get_by_content_hash(7)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_label_cache() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("cache")?;
+        let red = Label::new("color", "red");
+        let round = Label::new("shape", "round");
+        let small = Label::new("size", "small");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), round.clone()]),
+                (2, vec![red.clone(), small.clone()]),
+                (3, vec![round.clone()]),
+                (4, vec![red.clone(), round.clone(), small.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red.clone(), round.clone()])?;
+        find.add_include_all_group(vec![red.clone(), small.clone()])?;
+        find.add_exclude_group(vec![small.clone(), round.clone()])?;
+
+        let trees = (
+            &bucket.t_labels_objects,
+            &bucket.t_objects_created,
+            &bucket.t_objects_updated,
+        );
+        let reads = trees
+            .transaction(|(t, created, updated)| {
+                let mut cache = LabelCache::new(t, SerFormat::default());
+                let times = ObjectTimes { created, updated };
+                find.matching_ids(&mut cache, &times, false, &[])?;
+                Ok::<_, sled::transaction::ConflictableTransactionError<()>>(cache.reads)
+            })
+            .map_err(|e| anyhow!("{e:?}"))?;
+        assert_eq!(reads, 3);
+        assert!(find_ids(&bucket, find)?.is_empty());
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red.clone()])?;
+        find.add_include_all_group(vec![red, round.clone()])?;
+        find.add_exclude_group(vec![small])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_ser_formats() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        for format in [SerFormat::Bincode, SerFormat::Json] {
+            let config = BucketConfig {
+                format,
+                ..Default::default()
+            };
+            let bucket = mango.get_bucket_with_config(&format!("{format:?}"), config)?;
+            assert_eq!(bucket.config()?.format, format);
+
+            let payload = Bytes::from_static(b"encoded payload");
+            let labels = vec![Label::new("codec", "any"), Label::int("n", 3)];
+            let req = InsertRequest::new_static_id(1, payload.clone())?;
+            req.add_labels(labels.clone())?;
+            req.set_attr("source", "test")?;
+            let tx: Transaction = (&bucket).into();
+            tx.append_request(req.into())?;
+            tx.execute()?;
+
+            assert_eq!(bucket.get_object(1)?, Some(payload.clone()));
+            assert_eq!(bucket.get_ivec(1)?.as_deref(), Some(payload.as_ref()));
+            assert_eq!(bucket.labels(1)?, labels);
+            assert_eq!(bucket.attrs(1)?["source"], "test");
+            assert_eq!(bucket.label_cardinality(&labels[0])?, 1);
+
+            let results = bucket.run(vec![GetRequest::new(vec![1])?.into()])?;
+            match results.first() {
+                Some(RequestResult::Get(_, Ok(res))) => assert_eq!(res, &vec![(1, payload)]),
+                _ => return Err(anyhow!("missing get result")),
+            }
+
+            let find = FindRequest::new()?;
+            find.add_include_group(vec![Label::int("n", 3)])?;
+            assert_eq!(find_ids(&bucket, find)?, vec![1]);
+            let find = FindRequest::new()?;
+            find.value_equals("any")?;
+            assert_eq!(find_ids(&bucket, find)?, vec![1]);
+
+            bucket.run(vec![DeleteRequest::new(vec![1]).into()])?;
+            assert!(bucket.get_object(1)?.is_none());
+            assert_eq!(bucket.label_cardinality(&labels[0])?, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_display_from_str() -> Result<()> {
+        let label = Label::new("k", "v");
+        assert_eq!(format!("{label}"), "k=v");
+        assert_eq!("k=v".parse::<Label>()?, label);
+
+        let label: Label = "url=http://host/?a=b".parse()?;
+        assert_eq!(label, Label::new("url", "http://host/?a=b"));
+        assert_eq!(label.to_string().parse::<Label>()?, label);
+
+        // Typed values display decoded and parse back as strings
+        let typed = Label::int("n", 13);
+        assert_eq!(typed.to_string(), "n=13");
+        assert_eq!(typed.to_string().parse::<Label>()?, Label::new("n", "13"));
+        assert_eq!(Label::bool("ok", true).to_string(), "ok=true");
+
+        assert!("novalue".parse::<Label>().is_err());
+        assert!("=v".parse::<Label>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_cache() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let config = BucketConfig {
+            object_cache_bytes: Some(1024),
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("cached", config)?;
+        let stats = |bucket: &Bucket| -> Result<(u64, u64)> {
+            let cache = bucket.cache.as_ref().ok_or_else(|| anyhow!("no cache"))?;
+            let cache = cache.lock().map_err(|e| anyhow!("{e}"))?;
+            Ok((cache.hits, cache.misses))
+        };
+
+        bucket.run(vec![InsertRequest::new_static_id(
+            1,
+            Bytes::from_static(b"hot"),
+        )?
+        .into()])?;
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"hot")));
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"hot")));
+        assert_eq!(stats(&bucket)?, (1, 1));
+
+        // Other handles share the cache, and GetRequest reads through it
+        let other = mango.get_bucket("cached")?;
+        let results = other.run(vec![GetRequest::new(vec![1])?.into()])?;
+        assert_eq!(
+            results[0].as_get(),
+            Some(&vec![(1, Bytes::from_static(b"hot"))])
+        );
+        assert_eq!(stats(&bucket)?, (2, 1));
+
+        // Replacing and deleting invalidate the cached payload
+        other.run(vec![InsertRequest::new_static_id(
+            1,
+            Bytes::from_static(b"new"),
+        )?
+        .into()])?;
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"new")));
+        other.run(vec![DeleteRequest::new(vec![1]).into()])?;
+        assert!(bucket.get_object(1)?.is_none());
+
+        assert!(mango.get_bucket("uncached")?.cache.is_none());
+
+        // Reading past the byte bound evicts the least recently used payload
+        let config = BucketConfig {
+            object_cache_bytes: Some(8),
+            ..Default::default()
+        };
+        let small = mango.get_bucket_with_config("small", config)?;
+        small.run(vec![
+            InsertRequest::new_static_id(1, Bytes::from_static(b"aaaaa"))?.into(),
+            InsertRequest::new_static_id(2, Bytes::from_static(b"bbbbb"))?.into(),
+        ])?;
+        for id in [1, 2, 2, 1] {
+            small.get_object(id)?;
+        }
+        assert_eq!(stats(&small)?, (1, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cswap_request() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("cswap")?;
+        let v1 = Bytes::from_static(b"v1");
+        let v2 = Bytes::from_static(b"v2");
+        bucket.run(vec![InsertRequest::new_static_id(1, v1.clone())?.into()])?;
+
+        // A matching swap commits along with the rest of the transaction
+        let results = bucket.run(vec![
+            CswapRequest::new(1, Some(v1.clone()), v2.clone())?.into(),
+            InsertRequest::new_static_id(2, Bytes::from_static(b"two"))?.into(),
+        ])?;
+        assert_eq!(results[0].as_cswap_id(), Some(1));
+        assert_eq!(bucket.get_object(1)?, Some(v2.clone()));
+        assert!(bucket.get_object(2)?.is_some());
+
+        // A stale expectation aborts every request in the transaction
+        let stale = bucket.run(vec![
+            InsertRequest::new_static_id(3, Bytes::from_static(b"three"))?.into(),
+            CswapRequest::new(1, Some(v1.clone()), Bytes::from_static(b"v3"))?.into(),
+        ]);
+        assert!(matches!(
+            stale.unwrap_err().downcast_ref::<TransactionError>(),
+            Some(TransactionError::CompareAndSwapFailed(1))
+        ));
+        assert_eq!(bucket.get_object(1)?, Some(v2));
+        assert!(bucket.get_object(3)?.is_none());
+
+        // None expects the object to be absent
+        bucket.run(vec![CswapRequest::new(4, None, v1.clone())?.into()])?;
+        assert_eq!(bucket.get_object(4)?, Some(v1.clone()));
+        let taken = bucket.run(vec![CswapRequest::new(4, None, v1)?.into()]);
+        assert!(matches!(
+            taken.unwrap_err().downcast_ref::<TransactionError>(),
+            Some(TransactionError::CompareAndSwapFailed(4))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_skip_label_index() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("appendlog")?;
+        let label = Label::new("stream", "events");
+
+        let req = InsertRequest::new_static_id(1, Bytes::from_static(b"entry"))?;
+        req.add_label(label.clone())?;
+        req.skip_label_index(true)?;
+        bucket.run(vec![req.into()])?;
+
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"entry")));
+        assert_eq!(bucket.labels(1)?, vec![label.clone()]);
+        assert!(bucket.t_labels.is_empty());
+        assert!(bucket.t_labels_invert.is_empty());
+        assert!(bucket.t_labels_objects.is_empty());
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![label])?;
+        assert!(find_ids(&bucket, find)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_label_key() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("rename")?;
+        insert_labeled(
+            &bucket,
+            vec![
+                (
+                    1,
+                    vec![Label::new("filetype", "png"), Label::new("size", "big")],
+                ),
+                (2, vec![Label::new("filetype", "jpg")]),
+                (
+                    3,
+                    vec![
+                        Label::new("filetype", "png"),
+                        Label::new("extension", "png"),
+                    ],
+                ),
+                (4, vec![Label::new("size", "big")]),
+            ],
+        )?;
+
+        assert_eq!(bucket.rename_label_key("filetype", "extension")?, 2);
+        assert_eq!(bucket.rename_label_key("filetype", "extension")?, 0);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("extension", "png")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 3]);
+
+        let find = FindRequest::new()?;
+        find.any_of("filetype", &["png", "jpg"])?;
+        assert!(find_ids(&bucket, find)?.is_empty());
+
+        let mut labels = bucket.labels(1)?;
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec![Label::new("extension", "png"), Label::new("size", "big")]
+        );
+        assert_eq!(bucket.labels(3)?, vec![Label::new("extension", "png")]);
+        assert_eq!(
+            bucket.label_cardinality(&Label::new("extension", "png"))?,
+            2
+        );
+        assert_eq!(bucket.labels(4)?, vec![Label::new("size", "big")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_bucket_names() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let err = mango.get_bucket("namespaces").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::ReservedBucketName(name)) if name == "namespaces"
+        ));
+        assert!(mango
+            .get_bucket_with_config("ext", BucketConfig::default())
+            .is_err());
+
+        mango.set_reserved_bucket_names(&["namespaces", "system"])?;
+        let err = mango.clone().get_bucket("system").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::ReservedBucketName(_))
+        ));
+        assert!(mango.get_bucket("namespaces").is_err());
+        mango.get_bucket("ext")?;
+        assert_eq!(mango.list_buckets()?, vec!["ext".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_chunked() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("chunked")?;
+        let blob: Bytes = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        let req = InsertRequest::new_static_id(7, blob.clone())?;
+        req.add_label(Label::new("kind", "blob"))?;
+        assert!(req.chunked(0).is_err());
+        req.chunked(4096)?;
+        bucket.run(vec![req.into()])?;
+
+        // 3 chunks and a manifest
+        assert_eq!(bucket.t_chunks.len(), 4);
+        assert_eq!(bucket.get_object(7)?, Some(blob.clone()));
+        let results = bucket.run(vec![GetRequest::new(vec![7])?.into()])?;
+        assert_eq!(results[0].as_get(), Some(&vec![(7, blob.clone())]));
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("kind", "blob")])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![7]);
+
+        // Rewriting the object unchunked drops its chunks
+        bucket.run(vec![InsertRequest::new_static_id(7, blob.clone())?.into()])?;
+        assert!(bucket.t_chunks.is_empty());
+        assert_eq!(bucket.get_object(7)?, Some(blob.clone()));
+
+        let req = InsertRequest::new_static_id(7, blob.clone())?;
+        req.chunked(1000)?;
+        bucket.run(vec![req.into()])?;
+        assert_eq!(bucket.t_chunks.len(), 11);
+        bucket.run(vec![DeleteRequest::new(vec![7]).into()])?;
+        assert!(bucket.t_chunks.is_empty());
+        assert_eq!(bucket.get_object(7)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_sample() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("sample")?;
+        assert!(bucket.sample(5)?.is_empty());
+        insert_labeled(&bucket, (1..=50).map(|id| (id, vec![])).collect())?;
+
+        for n in [0, 1, 10, 50, 80] {
+            let mut ids = bucket.sample(n)?;
+            assert_eq!(ids.len(), n.min(50));
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids.len(), n.min(50));
+            assert!(ids.iter().all(|id| (1..=50).contains(id)));
+        }
+
+        assert_eq!(bucket.sample_seeded(10, 42)?, bucket.sample_seeded(10, 42)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_explain() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("explain")?;
+        let red = Label::new("color", "red");
+        let blue = Label::new("color", "blue");
+        let big = Label::new("size", "big");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone()]),
+                (2, vec![red.clone()]),
+                (3, vec![blue.clone(), big.clone()]),
+                (4, vec![blue.clone()]),
+                (5, vec![Label::new("shade", "red")]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red.clone(), blue.clone()])?;
+        find.add_exclude_group(vec![big.clone()])?;
+        find.value_equals("red")?;
+        find.add_include_all_group(vec![blue.clone(), big.clone()])?;
+
+        let plan = find.explain(&bucket)?;
+        assert_eq!(
+            plan.steps,
+            vec![
+                PlanStep {
+                    group: LabelGroup::Include(vec![red.clone(), blue.clone()]),
+                    matched: 4,
+                    running_total: 4,
+                },
+                PlanStep {
+                    group: LabelGroup::Exclude(vec![big.clone()]),
+                    matched: 2,
+                    running_total: 2,
+                },
+                PlanStep {
+                    group: LabelGroup::ValueEquals("red".to_string()),
+                    matched: 3,
+                    running_total: 4,
+                },
+                PlanStep {
+                    group: LabelGroup::IncludeAll(vec![blue, big]),
+                    matched: 1,
+                    running_total: 5,
+                },
+            ]
+        );
+        assert_eq!(plan.label_reads, 4);
+
+        // Explaining leaves the request ready to execute
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 3, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many_batched() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("batched")?;
+        let even = Label::new("parity", "even");
+        let items: Vec<(Bytes, Vec<Label>)> = (0..200u32)
+            .map(|i| {
+                let parity = Label::new("parity", if i % 2 == 0 { "even" } else { "odd" });
+                (
+                    Bytes::from(format!("item {i}")),
+                    vec![parity, Label::int("n", i as i64)],
+                )
+            })
+            .collect();
+
+        let ids = bucket.insert_many_batched(items.clone(), true)?;
+        assert_eq!(ids.len(), 200);
+        for ((payload, labels), id) in items.iter().zip(ids.iter()) {
+            assert_eq!(bucket.get_object(*id)?.as_ref(), Some(payload));
+            let mut expected = labels.clone();
+            expected.sort();
+            assert_eq!(&bucket.labels(*id)?, &expected);
+        }
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![even.clone()])?;
+        assert_eq!(find_ids(&bucket, find)?.len(), 100);
+
+        // A second load adds to the labels of objects already stored
+        let ids = bucket.insert_many_batched(
+            vec![(Bytes::from("item 0"), vec![Label::new("first", "yes")])],
+            false,
+        )?;
+        assert_eq!(bucket.labels(ids[0])?.len(), 3);
+        assert_eq!(bucket.label_cardinality(&even)?, 100);
+        let find = FindRequest::new()?;
+        find.all_of(&[even, Label::new("first", "yes")])?;
+        assert_eq!(find_ids(&bucket, find)?, ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_unique_keys() -> Result<()> {
+        let pugsly = Label::new("name", "Pugsly");
+        let doggo = Label::new("name", "Doggo");
+
+        let req = InsertRequest::new(Bytes::from_static(b"dog"))?;
+        req.add_labels(vec![pugsly.clone(), doggo.clone()])?;
+        assert_eq!(req.labels.borrow().len(), 2);
+        let err = req.unique_keys(true).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::DuplicateLabelKey(key)) if key == "name"
+        ));
+
+        let req = InsertRequest::new(Bytes::from_static(b"dog"))?;
+        req.unique_keys(true)?;
+        req.add_label(pugsly.clone())?;
+        req.add_label(pugsly.clone())?;
+        let err = req.add_label(doggo.clone()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::DuplicateLabelKey(key)) if key == "name"
+        ));
+        let err = req
+            .add_labels(vec![Label::new("kind", "dog"), Label::new("kind", "cat")])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::DuplicateLabelKey(key)) if key == "kind"
+        ));
+        assert_eq!(*req.labels.borrow(), vec![pugsly.clone(), pugsly]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mango_verify() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        assert!(mango.verify("missing").is_err());
+        let bucket = mango.get_bucket("verify")?;
+        let red = Label::new("color", "red");
+        let blue = Label::new("color", "blue");
+        insert_labeled(
+            &bucket,
+            vec![(1, vec![red.clone()]), (2, vec![red.clone(), blue.clone()])],
+        )?;
+        assert!(mango.verify("verify")?.is_consistent());
+
+        // Object 2 loses its labels entry, and blue's object list names a missing object
+        bucket.t_objects_labels.remove(id_key(2)?)?;
+        bucket
+            .t_labels_objects
+            .insert(blue.key_ltr()?, ser(vec![2u64, 9])?.to_vec())?;
+        // Object 1 carries a label that was never indexed
+        let green = Label::new("color", "green");
+        bucket
+            .t_objects_labels
+            .insert(id_key(1)?, ser(vec![green.clone(), red.clone()])?.to_vec())?;
+
+        let report = mango.verify("verify")?;
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report,
+            VerifyReport {
+                objects_without_labels: vec![2],
+                labels_without_objects: vec![],
+                missing_labels: vec![green.clone()],
+                unindexed: vec![(1, green)],
+                dangling: vec![(blue.clone(), 2), (blue, 9), (red, 2)],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_insert_many() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("trymany")?;
+
+        // Occupy the hash id of "b" with another payload so a rejecting insert of it fails
+        let taken = Object::new(Bytes::from_static(b"b")).hash_id();
+        bucket.run(vec![InsertRequest::new_static_id(
+            taken,
+            Bytes::from_static(b"squatter"),
+        )?
+        .into()])?;
+
+        let items: Vec<InsertRequest> = [b"a", b"b", b"c"]
+            .iter()
+            .map(|p| {
+                let req = InsertRequest::new(Bytes::from_static(*p))?;
+                req.add_label(Label::new("batch", "1"))?;
+                req.collision_policy(CollisionPolicy::Reject)?;
+                Ok(req)
+            })
+            .collect::<Result<_>>()?;
+
+        let results = bucket.try_insert_many(items)?;
+        assert_eq!(results.len(), 3);
+        let a = *results[0].as_ref().unwrap();
+        let c = *results[2].as_ref().unwrap();
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(err.id, taken);
+
+        assert_eq!(bucket.get_object(a)?, Some(Bytes::from_static(b"a")));
+        assert_eq!(bucket.get_object(c)?, Some(Bytes::from_static(b"c")));
+        assert_eq!(
+            bucket.get_object(taken)?,
+            Some(Bytes::from_static(b"squatter"))
+        );
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("batch", "1")])?;
+        let mut expected = vec![a, c];
+        expected.sort();
+        assert_eq!(find_ids(&bucket, find)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_keep_versions() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("versions")?;
+        let write = |payload: &'static [u8]| -> Result<()> {
+            let req = InsertRequest::new_static_id(1, Bytes::from_static(payload))?;
+            req.keep_versions(2)?;
+            bucket.run(vec![req.into()])?;
+            Ok(())
+        };
+
+        write(b"v1")?;
+        assert_eq!(bucket.latest_version(1)?, None);
+        write(b"v2")?;
+        write(b"v3")?;
+        // Rewriting the same payload is not a new version
+        write(b"v3")?;
+
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"v3")));
+        assert_eq!(bucket.latest_version(1)?, Some(2));
+        assert_eq!(bucket.get_version(1, 1)?, Some(Bytes::from_static(b"v1")));
+        assert_eq!(bucket.get_version(1, 2)?, Some(Bytes::from_static(b"v2")));
+
+        write(b"v4")?;
+        assert_eq!(bucket.get_version(1, 1)?, None);
+        assert_eq!(bucket.get_version(1, 2)?, Some(Bytes::from_static(b"v2")));
+        assert_eq!(bucket.get_version(1, 3)?, Some(Bytes::from_static(b"v3")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_ids_stream() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("stream")?;
+        let even = Label::new("parity", "even");
+        let odd = Label::new("parity", "odd");
+        let small = Label::new("size", "small");
+        insert_labeled(
+            &bucket,
+            (1..=100)
+                .map(|id| {
+                    let parity = if id % 2 == 0 {
+                        even.clone()
+                    } else {
+                        odd.clone()
+                    };
+                    match id <= 30 {
+                        true => (id, vec![parity, small.clone()]),
+                        false => (id, vec![parity]),
+                    }
+                })
+                .collect(),
+        )?;
+
+        let make_find = || -> Result<FindRequest> {
+            let find = FindRequest::new()?;
+            find.add_include_group(vec![even.clone()])?;
+            find.add_exclude_group(vec![small.clone()])?;
+            Ok(find)
+        };
+
+        let buffered = find_ids(&bucket, make_find()?)?;
+        assert_eq!(buffered.len(), 35);
+        assert_eq!(
+            bucket.query_ids_stream(make_find()?).count(),
+            buffered.len()
+        );
+
+        let find = make_find()?;
+        find.order_by(OrderKey::IdDesc)?;
+        let streamed = bucket.query_ids_stream(find).collect::<Result<Vec<_>>>()?;
+        let mut expected = buffered.clone();
+        expected.reverse();
+        assert_eq!(streamed, expected);
+
+        let find = make_find()?;
+        find.order_by(OrderKey::LabelValue("parity".to_string()))?;
+        let mut stream = bucket.query_ids_stream(find);
+        assert!(stream.next().is_some_and(|r| r.is_err()));
+        assert!(stream.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_serde() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("serde")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone()]),
+                (2, vec![red.clone()]),
+                (3, vec![Label::new("shade", "red")]),
+                (4, vec![big.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red.clone()])?;
+        find.value_equals("red")?;
+        find.add_exclude_group(vec![big.clone()])?;
+        find.order_by(OrderKey::IdDesc)?;
+        let json = serde_json::to_string(&find)?;
+        let restored: FindRequest = serde_json::from_str(&json)?;
+
+        let run = |find: FindRequest| -> Result<Vec<ObjectID>> {
+            let results = bucket.run(vec![find.into()])?;
+            Ok(results[0]
+                .as_find()
+                .unwrap()
+                .iter()
+                .map(|(id, _)| *id)
+                .collect())
+        };
+        assert_eq!(run(find)?, vec![3, 2]);
+        assert_eq!(run(restored)?, vec![3, 2]);
+
+        let delete = DeleteRequest::new(vec![4]);
+        delete.prune(false)?;
+        let restored: DeleteRequest = serde_json::from_str(&serde_json::to_string(&delete)?)?;
+        bucket.run(vec![restored.into()])?;
+        assert_eq!(bucket.get_object(4)?, None);
+        assert_eq!(bucket.object_ids_for_label(&big)?, vec![1]);
+
+        let get = GetRequest::new(vec![1, 2])?;
+        let restored: GetRequest = serde_json::from_str(&serde_json::to_string(&get)?)?;
+        let results = bucket.run(vec![restored.into()])?;
+        assert_eq!(results[0].as_get().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_touch() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("ttl")?;
+        for id in [1, 2, 3] {
+            let req = InsertRequest::new_static_id(id, Bytes::from(format!("blob {id}")))?;
+            req.add_label(Label::new("kind", "blob"))?;
+            if id != 3 {
+                req.ttl(0)?;
+            }
+            bucket.run(vec![req.into()])?;
+        }
+        assert!(bucket.object_meta(1)?.is_expired());
+        assert_eq!(bucket.object_meta(3)?, Default::default());
+
+        let payload = bucket.t_objects.get(id_key(1)?)?;
+        assert!(bucket.touch(1, 3600)?);
+        assert!(!bucket.touch(9, 3600)?);
+        assert_eq!(bucket.t_objects.get(id_key(1)?)?, payload);
+        assert!(!bucket.object_meta(1)?.is_expired());
+
+        assert_eq!(bucket.purge_expired()?, vec![2]);
+        assert_eq!(bucket.get_object(2)?, None);
+        assert!(bucket.t_objects_meta.get(id_key(2)?)?.is_none());
+        assert_eq!(bucket.get_object(1)?, Some(Bytes::from("blob 1")));
+        assert_eq!(
+            bucket
+                .object_ids_for_label(&Label::new("kind", "blob"))?
+                .len(),
+            2
+        );
+        assert!(bucket.purge_expired()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_value_prefix() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("prefix")?;
+        let mut objects: Vec<(ObjectID, Vec<Label>)> = (0..200)
+            .map(|id| (id, vec![Label::new(&format!("unrelated{id}"), "png")]))
+            .collect();
+        objects.push((1000, vec![Label::new("ext", "png")]));
+        objects.push((1001, vec![Label::new("ext", "pdf")]));
+        objects.push((1002, vec![Label::new("ext", "jpg")]));
+        objects.push((1003, vec![Label::new("extension", "png")]));
+        insert_labeled(&bucket, objects)?;
+
+        // Only the values of `ext` starting with `p` are scanned
+        let labels = FindRequest::labels_with_prefix(
+            &bucket.t_label_keys,
+            "ext",
+            "p",
+            SerFormat::default(),
+        )?;
+        assert_eq!(
+            labels,
+            vec![Label::new("ext", "pdf"), Label::new("ext", "png")]
+        );
+
+        let find = FindRequest::new()?;
+        find.value_prefix("ext", "p")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1000, 1001]);
+
+        let find = FindRequest::new()?;
+        find.value_prefix("ext", "")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1000, 1001, 1002]);
+
+        // Labels inserted earlier in the same transaction match too
+        let insert = InsertRequest::new_static_id(1004, Bytes::from_static(b"ppm"))?;
+        insert.add_label(Label::new("ext", "ppm"))?;
+        let find = FindRequest::new()?;
+        find.value_prefix("ext", "p")?;
+        let results = bucket.run(vec![insert.into(), find.into()])?;
+        let mut ids: Vec<ObjectID> = results[1]
+            .as_find()
+            .into_iter()
+            .flatten()
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1000, 1001, 1004]);
+        bucket.run(vec![DeleteRequest::new(vec![1004]).into()])?;
+
+        // Pruned labels leave the key index too
+        bucket.run(vec![DeleteRequest::new(vec![1001]).into()])?;
+        assert_eq!(
+            FindRequest::labels_with_prefix(
+                &bucket.t_label_keys,
+                "ext",
+                "p",
+                SerFormat::default()
+            )?,
+            vec![Label::new("ext", "png")]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() -> Result<()> {
+        use std::sync::Arc;
+        use tracing::{field::Field, span::Attributes, Id, Subscriber};
+        use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+        /// Records every span created as `name` or `name:kind`
+        struct SpanCapture(Arc<Mutex<Vec<String>>>);
+
+        struct KindVisitor(Option<String>);
+
+        impl tracing::field::Visit for KindVisitor {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "kind" {
+                    self.0 = Some(value.to_string());
+                }
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+        }
+
+        impl<S: Subscriber> Layer<S> for SpanCapture {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+                let mut kind = KindVisitor(None);
+                attrs.record(&mut kind);
+                let name = attrs.metadata().name();
+                self.0.lock().unwrap().push(match kind.0 {
+                    Some(kind) => format!("{name}:{kind}"),
+                    None => name.to_string(),
+                });
+            }
+        }
+
+        let spans = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(SpanCapture(spans.clone()));
+        tracing::subscriber::with_default(subscriber, || -> Result<()> {
+            let mango = Mango::new_temp()?;
+            let bucket = mango.get_bucket("tracing")?;
+            bucket.run(vec![
+                InsertRequest::new_static_id(1, Bytes::from_static(b"one"))?.into(),
+                InsertRequest::new_static_id(2, Bytes::from_static(b"two"))?.into(),
+                DeleteRequest::new(vec![2]).into(),
+            ])?;
+            Ok(())
+        })?;
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(
+            *spans,
+            vec![
+                "transaction",
+                "request:insert",
+                "request:insert",
+                "request:delete"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mango_compact() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mango = Mango::new(dir.path())?;
+        let bucket = mango.get_bucket("compact")?;
+        let mut rng = fastrand::Rng::with_seed(7);
+        let ids: Vec<ObjectID> = (0..16).collect();
+        for id in ids.iter() {
+            let payload: Bytes = (0..64 * 1024).map(|_| rng.u8(..)).collect();
+            bucket.run(vec![InsertRequest::new_static_id(*id, payload)?.into()])?;
+        }
+        mango.compact()?;
+        let full = mango.inner.size_on_disk()?;
+        assert!(full > 0);
+
+        bucket.run(vec![DeleteRequest::new(ids).into()])?;
+        mango.compact()?;
+
+        // With the deletes flushed, compacting again must not grow the files
+        let before = mango.inner.size_on_disk()?;
+        let reclaimed = mango.compact()?;
+        let after = mango.inner.size_on_disk()?;
+        assert!(after <= before);
+        assert_eq!(reclaimed, before - after);
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_length_limits() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket_with_config(
+            "limits",
+            BucketConfig {
+                max_label_key_len: 8,
+                max_label_value_len: 16,
+                ..Default::default()
+            },
+        )?;
+        let insert = |label: Label| -> Result<Vec<RequestResult>> {
+            let req = InsertRequest::new(Bytes::from_static(b"payload"))?;
+            req.add_label(label)?;
+            bucket.run(vec![req.into()])
+        };
+
+        insert(Label::new("name", "a normal value"))?;
+
+        let err = insert(Label::new("name", &"x".repeat(17))).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::LabelValueTooLong(key, 17, 16)) if key == "name"
+        ));
+        let err = insert(Label::new("longer_key", "v")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::LabelKeyTooLong(10, 8))
+        ));
+        assert!(bucket
+            .insert_many_batched(
+                vec![(Bytes::new(), vec![Label::new("k", &"x".repeat(17))])],
+                false
+            )
+            .is_err());
+
+        assert_eq!(BucketConfig::default().max_label_value_len, 4096);
+        assert_eq!(
+            bucket
+                .object_ids_for_label(&Label::new("name", "a normal value"))?
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_exclude_ids() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("excludeids")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone()]),
+                (2, vec![red.clone(), big.clone()]),
+                (3, vec![red.clone()]),
+                (4, vec![red.clone()]),
+            ],
+        )?;
+
+        let make_find = || -> Result<FindRequest> {
+            let find = FindRequest::new()?;
+            find.add_include_group(vec![red.clone()])?;
+            find.add_exclude_group(vec![big.clone()])?;
+            Ok(find)
+        };
+        let first = find_ids(&bucket, make_find()?)?;
+        assert_eq!(first, vec![1, 3, 4]);
+
+        let find = make_find()?;
+        assert_eq!(find.exclude_ids(vec![1, 4])?, 2);
+        assert_eq!(find.exclude_ids(vec![4, 9])?, 3);
+        assert_eq!(find_ids(&bucket, find)?, vec![3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_labels() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("scanlabels")?;
+        let objects = (1..=10)
+            .map(|id| {
+                let parity = Label::new("parity", if id % 2 == 0 { "even" } else { "odd" });
+                match id <= 3 {
+                    true => (id, vec![parity, Label::new("small", "yes")]),
+                    false => (id, vec![parity]),
+                }
+            })
+            .collect();
+        insert_labeled(&bucket, objects)?;
+
+        let mut counts = bucket.scan_labels().collect::<Result<Vec<_>>>()?;
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![
+                (Label::new("parity", "even"), 5),
+                (Label::new("parity", "odd"), 5),
+                (Label::new("small", "yes"), 3),
+            ]
+        );
+
+        // A label missing from one tree is still reported
+        let odd = Label::new("parity", "odd");
+        bucket.t_labels_objects.remove(odd.key_ltr()?)?;
+        let small = Label::new("small", "yes");
+        bucket.t_labels.remove(small.key_ltr()?)?;
+        let mut counts = bucket.scan_labels().collect::<Result<Vec<_>>>()?;
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![(Label::new("parity", "even"), 5), (odd, 0), (small, 3)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_preflight_labels() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("preflight")?;
+        let red = Label::new("color", "red");
+        let blue = Label::new("color", "blue");
+        insert_labeled(&bucket, vec![(1, vec![red.clone(), blue.clone()])])?;
+
+        let batch = vec![
+            red.clone(),
+            Label::new("color", "green"),
+            blue.clone(),
+            Label::new("size", "big"),
+            red,
+        ];
+        assert_eq!(bucket.preflight_labels(&batch)?, (2, 2));
+        assert_eq!(bucket.preflight_labels(&[])?, (0, 0));
+        assert_eq!(bucket.t_labels.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_budget() -> Result<()> {
+        let mango = Mango::new_temp()?.with_config(MangoConfig {
+            retry_budget: Some(3),
+            ..Default::default()
+        });
+        let bucket = mango.get_bucket("retries")?;
+        let insert = |id: ObjectID, conflicts: usize| -> Result<Transaction> {
+            let tx: Transaction = (&bucket).into();
+            *tx.simulated_conflicts.borrow_mut() = conflicts;
+            let req = InsertRequest::new_static_id(id, Bytes::from(format!("object {id}")))?;
+            req.add_label(Label::new("retried", "yes"))?;
+            tx.append_request(req.into())?;
+            Ok(tx)
+        };
+
+        // Conflicts within the Mango's budget are retried until the transaction commits
+        let tx = insert(1, 3)?;
+        tx.execute()?;
+        assert_eq!(tx.attempts()?, 4);
+        assert_eq!(tx.results()?.len(), 1);
+        assert!(bucket.get_object(1)?.is_some());
+
+        // One conflict too many fails with a typed error and writes nothing
+        let tx = insert(2, 4)?;
+        let err = tx.execute().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::RetryBudgetExhausted(3))
+        ));
+        assert!(bucket.get_object(2)?.is_none());
+
+        // A transaction can raise or drop its own budget
+        let tx = insert(3, 4)?;
+        assert_eq!(tx.retry_budget(Some(5))?, Some(5));
+        tx.execute()?;
+        assert_eq!(tx.attempts()?, 5);
+        let tx = insert(4, 10)?;
+        tx.retry_budget(None)?;
+        tx.execute()?;
+        assert_eq!(tx.attempts()?, 11);
+
+        // The default is sled's own, retrying until it commits
+        let tx: Transaction = (&Mango::new_temp()?.get_bucket("retries")?).into();
+        assert_eq!(*tx.retry_budget.borrow(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_equality() -> Result<()> {
+        use std::collections::HashSet;
+
+        let a = Object::new(Bytes::from_static(b"same bytes"));
+        let b = Object::from_reader(&b"same bytes"[..])?;
+        let other = Object::new(Bytes::from_static(b"other bytes"));
+        assert_eq!(a, b);
+        assert_ne!(a, other);
+        assert_eq!(a.hash_id(), b.hash_id());
+        assert!(a.content_eq(&Bytes::from_static(b"same bytes")));
+        assert!(!a.content_eq(&other.get_inner()));
+
+        let set: HashSet<Object> = [a, b, other].into_iter().collect();
+        assert_eq!(set.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_range_order() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("range")?;
+        let pinned = Label::new("pinned", "yes");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![Label::new("size", "10")]),
+                (2, vec![Label::new("size", "3")]),
+                (3, vec![Label::new("size", "25")]),
+                (4, vec![Label::int("size", -7)]),
+                (5, vec![Label::new("size", "big"), pinned.clone()]),
+                (6, vec![pinned.clone()]),
+            ],
+        )?;
+
+        let ordered = |key: Option<OrderKey>| -> Result<Vec<ObjectID>> {
+            let find = FindRequest::new()?;
+            find.add_range_group("size", -10, 20)?;
+            find.add_include_group(vec![pinned.clone()])?;
+            if let Some(key) = key {
+                find.order_by(key)?;
+            }
+            let tx: Transaction = (&bucket).into();
+            tx.append_request(find.into())?;
+            tx.execute()?;
+            Ok(tx.find_results()?[0].iter().map(|(id, _)| *id).collect())
+        };
+
+        let mut matched = ordered(None)?;
+        matched.sort();
+        assert_eq!(matched, vec![1, 2, 4, 5, 6]);
+        assert_eq!(
+            ordered(Some(OrderKey::NumericAsc("size".to_string())))?,
+            vec![4, 2, 1, 5, 6]
+        );
+        assert_eq!(
+            ordered(Some(OrderKey::NumericDesc("size".to_string())))?,
+            vec![1, 2, 4, 5, 6]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_buckets() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        assert!(mango.iter_buckets()?.is_empty());
+        for name in ["gamma", "alpha", "beta"] {
+            mango.get_bucket(name)?;
+        }
+
+        let buckets = mango.iter_buckets()?;
+        let names: Vec<&str> = buckets.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "beta", "gamma"]);
+        insert_labeled(&buckets[1], vec![(1, vec![Label::new("via", "iter")])])?;
+        assert_eq!(
+            mango.get_bucket("beta")?.get_object(1)?,
+            Some(Bytes::from("object 1"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_durable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        let crashed = dir.path().join("crashed");
+        let mango = Mango::new(&path)?;
+        let bucket = mango.get_bucket("durable")?;
+
+        let insert = InsertRequest::new_static_id(1, Bytes::from_static(b"kept"))?;
+        assert!(insert.durable(true)?);
+        insert.add_label(Label::new("durable", "yes"))?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(insert.into())?;
+        tx.execute()?;
+
+        // Copy the files while the original is still open, as a crash would leave them
+        for entry in WalkDir::new(&path) {
+            let entry = entry?;
+            let target = crashed.join(entry.path().strip_prefix(&path)?);
+            match entry.file_type().is_dir() {
+                true => std::fs::create_dir_all(&target)?,
+                false => {
+                    std::fs::copy(entry.path(), &target)?;
+                }
+            }
+        }
+
+        let recovered = Mango::new(&crashed)?.get_bucket("durable")?;
+        assert_eq!(recovered.get_object(1)?, Some(Bytes::from_static(b"kept")));
+        assert_eq!(recovered.labels(1)?, vec![Label::new("durable", "yes")]);
+        drop(mango);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_attribute() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("attribute")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        let old = Label::new("age", "old");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone()]),
+                (2, vec![red.clone()]),
+                (3, vec![big.clone(), old.clone()]),
+                (4, vec![old.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group_named("red", vec![red])?;
+        find.add_include_group(vec![old.clone()])?;
+        find.add_include_group_named("big", vec![big])?;
+        find.add_exclude_group(vec![Label::new("id", "none")])?;
+        find.exclude_ids(vec![4])?;
+
+        let attribution = find.attribute(&bucket)?;
+        let mut ids: Vec<ObjectID> = attribution.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, find_ids(&bucket, find.clone())?);
+        assert_eq!(attribution[&1], vec!["red", "big"]);
+        assert_eq!(attribution[&2], vec!["red"]);
+        assert_eq!(attribution[&3], vec!["big"]);
+        assert!(!attribution.contains_key(&4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_payload_format() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("legacy")?;
+        let payload = Bytes::from_static(b"written by an older version");
+
+        // Stored with bincode in a flexbuffers bucket, bypassing InsertRequest
+        bucket
+            .t_objects
+            .insert(id_key(1)?, bincode::serialize(&payload)?)?;
+        assert_eq!(bucket.get_object(1)?, Some(payload.clone()));
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(GetRequest::new(vec![1])?.into())?;
+        tx.execute()?;
+        assert_eq!(
+            tx.results()?[0].as_get().cloned(),
+            Some(vec![(1, payload.clone())])
+        );
+
+        // And the other way round, in a bincode bucket
+        let config = BucketConfig {
+            format: SerFormat::Bincode,
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("legacy-bincode", config)?;
+        bucket
+            .t_objects
+            .insert(id_key(2)?, ser(payload.clone())?.to_vec())?;
+        assert_eq!(bucket.get_object(2)?, Some(payload));
+
+        bucket
+            .t_objects
+            .insert(id_key(3)?, b"not a payload".to_vec())?;
+        assert!(bucket.get_object(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_labels_csv() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("csv")?;
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![Label::new("name", "plain"), Label::int("n", 3)]),
+                (2, vec![Label::new("note", "a, \"quoted\"\nvalue")]),
+                (3, vec![]),
+            ],
+        )?;
+
+        let mut out = vec![];
+        assert_eq!(bucket.export_labels_csv(&mut out)?, 3);
+        let csv = String::from_utf8(out)?;
+
+        // Split into records, honouring quoted fields
+        let mut records = vec![];
+        let (mut record, mut field, mut quoted) = (vec![], String::new(), false);
+        let mut chars = csv.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                ('"', _) => quoted = !quoted,
+                (',', false) => record.push(std::mem::take(&mut field)),
+                ('\n', false) => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                (c, _) => field.push(c),
+            }
+        }
+        assert!(!quoted && field.is_empty() && record.is_empty());
+        assert_eq!(records[0], vec!["object_id", "label_key", "label_value"]);
+        let mut rows = records[1..].to_vec();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["1", "n", "3"],
+                vec!["1", "name", "plain"],
+                vec!["2", "note", "a, \"quoted\"\nvalue"],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_size_guard() -> Result<()> {
+        assert_eq!(MangoConfig::default().max_transaction_writes, None);
+        let mango = Mango::new_temp()?.with_config(MangoConfig {
+            max_transaction_writes: Some(10),
+            ..Default::default()
+        });
+        let bucket = mango.get_bucket("guarded")?;
+        let labels = vec![
+            Label::new("a", "1"),
+            Label::new("b", "2"),
+            Label::new("c", "3"),
+        ];
+        let inserts = || -> Result<Transaction> {
+            let tx: Transaction = (&bucket).into();
+            for id in 1..=3 {
+                let req = InsertRequest::new_static_id(id, Bytes::from(format!("object {id}")))?;
+                req.add_labels(labels.clone())?;
+                tx.append_request(req.into())?;
+            }
+            Ok(tx)
+        };
+
+        let err = inserts()?.execute().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::TooLarge(12, 10))
+        ));
+        assert!(bucket.t_objects.is_empty());
+
+        let tx = inserts()?;
+        assert_eq!(tx.max_writes(None)?, None);
+        tx.execute()?;
+        assert_eq!(bucket.t_objects.len(), 3);
+
+        // Deletes count a fixed cost per id
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(DeleteRequest::new(vec![1, 2, 3]).into())?;
+        let err = tx.execute().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::TooLarge(12, 10))
+        ));
+
+        // Objects a delete's find matches are counted inside the transaction
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("a", "1")])?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(DeleteRequest::from_find(find).into())?;
+        let err = tx.execute().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::TooLarge(12, 10))
+        ));
+        assert_eq!(bucket.t_objects.len(), 3);
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(DeleteRequest::new(vec![1, 2]).into())?;
+        tx.execute()?;
+        assert_eq!(bucket.t_objects.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_with_scope() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("tenants")?;
+        let acme = Label::new("tenant", "acme");
+        let color = Label::new("color", "red");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![acme.clone(), color.clone()]),
+                (2, vec![Label::new("tenant", "other"), color.clone()]),
+                (3, vec![color.clone()]),
+            ],
+        )?;
+
+        let scoped = bucket.with_scope(acme.clone());
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![color.clone()])?;
+        assert_eq!(find_ids(&scoped, find.clone())?, vec![1]);
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 3]);
+
+        // Inserts through the scoped handle carry the scope label
+        let tx: Transaction = (&scoped).into();
+        let insert = InsertRequest::new_static_id(4, Bytes::from_static(b"four"))?;
+        insert.add_label(color.clone())?;
+        tx.append_request(insert.into())?;
+        tx.execute()?;
+        assert_eq!(bucket.labels(4)?, vec![color.clone(), acme.clone()]);
+
+        // Objects outside the scope can't be read, swapped, deleted or overwritten
+        let refused = |req: Request| -> Result<()> {
+            let err = scoped.run(vec![req]).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<TransactionError>(),
+                Some(TransactionError::OutOfScope(2, _))
+            ));
+            Ok(())
+        };
+        refused(GetRequest::new(vec![1, 2])?.into())?;
+        refused(DeleteRequest::new(vec![2]).into())?;
+        refused(CswapRequest::new(2, None, Bytes::from_static(b"x"))?.into())?;
+        refused(InsertRequest::new_static_id(2, Bytes::from_static(b"x"))?.into())?;
+        assert_eq!(bucket.get_object(2)?, Some(Bytes::from("object 2")));
+
+        let results = scoped.run(vec![GetRequest::new(vec![1])?.into()])?;
+        assert_eq!(
+            results[0].as_get().cloned(),
+            Some(vec![(1, Bytes::from("object 1"))])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_dedupe() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let config = BucketConfig {
+            dedupe_by_content: true,
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("tenants", config)?;
+        let acme = Label::new("tenant", "acme");
+        let other = Label::new("tenant", "other");
+        let payload = Bytes::from_static(b"same bytes");
+
+        let insert = |scope: &Label, id: ObjectID, owner: &str| -> Result<Option<ObjectID>> {
+            let req = InsertRequest::new_static_id(id, payload.clone())?;
+            req.set_attr("owner", owner)?;
+            let results = bucket.with_scope(scope.clone()).run(vec![req.into()])?;
+            Ok(results[0].as_insert_id())
+        };
+        assert_eq!(insert(&acme, 1, "acme")?, Some(1));
+        assert_eq!(insert(&other, 2, "other")?, Some(2));
+        assert_eq!(insert(&acme, 3, "acme")?, Some(1));
+
+        // Each tenant keeps its own copy, labels and attributes
+        assert_eq!(bucket.labels(1)?, vec![acme.clone()]);
+        assert_eq!(bucket.labels(2)?, vec![other.clone()]);
+        assert_eq!(bucket.attrs(1)?["owner"], "acme");
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![acme.clone(), other.clone()])?;
+        assert_eq!(find_ids(&bucket.with_scope(acme), find.clone())?, vec![1]);
+        assert_eq!(find_ids(&bucket.with_scope(other), find)?, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_batched_insert_and_purge() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("tenants")?;
+        let acme = Label::new("tenant", "acme");
+        let other = Label::new("tenant", "other");
+        let scoped = bucket.with_scope(acme.clone());
+
+        let ids = scoped.insert_many_batched(
+            vec![(
+                Bytes::from_static(b"batched"),
+                vec![Label::new("kind", "bulk")],
+            )],
+            false,
+        )?;
+        assert_eq!(
+            bucket.labels(ids[0])?,
+            vec![Label::new("kind", "bulk"), acme.clone()]
+        );
+
+        // A payload another tenant already stores under the same hash id is refused
+        let foreign = bucket.with_scope(other.clone());
+        let shared = Bytes::from_static(b"foreign");
+        foreign.insert_many_batched(vec![(shared.clone(), vec![])], false)?;
+        let err = scoped
+            .insert_many_batched(vec![(shared, vec![])], false)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransactionError>(),
+            Some(TransactionError::OutOfScope(..))
+        ));
+
+        for (id, tenant) in [(10, &acme), (11, &other)] {
+            let req = InsertRequest::new_static_id(id, Bytes::from(format!("blob {id}")))?;
+            req.add_label(tenant.clone())?;
+            req.ttl(0)?;
+            bucket.run(vec![req.into()])?;
+        }
+        assert_eq!(scoped.purge_expired()?, vec![10]);
+        assert_eq!(bucket.get_object(11)?, Some(Bytes::from("blob 11")));
+        assert_eq!(foreign.purge_expired()?, vec![11]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_range() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("ranges")?;
+        let payload: Bytes = (0..100u8).collect::<Vec<u8>>().into();
+        let plain = InsertRequest::new_static_id(1, payload.clone())?;
+        let chunked = InsertRequest::new_static_id(2, payload.clone())?;
+        chunked.chunked(30)?;
+        bucket.run(vec![plain.into(), chunked.into()])?;
+
+        let read = |id: ObjectID, offset: usize, len: usize| -> Result<Bytes> {
+            let get = GetRequest::new(vec![])?;
+            get.with_range(id, offset, len)?;
+            let results = bucket.run(vec![get.into()])?;
+            Ok(results[0]
+                .as_get()
+                .map(|r| r[0].1.clone())
+                .unwrap_or_default())
+        };
+
+        for id in [1, 2] {
+            assert_eq!(read(id, 10, 10)?, payload.slice(10..20));
+            assert_eq!(read(id, 25, 40)?, payload.slice(25..65));
+            assert_eq!(read(id, 90, 50)?, payload.slice(90..));
+            assert_eq!(read(id, 0, 100)?, payload);
+            assert!(read(id, 150, 10)?.is_empty());
+        }
+        assert!(read(3, 0, 10)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_to_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let backup = dir.path().join("backup");
+        let mango = Mango::new(dir.path().join("db"))?;
+        let red = Label::new("color", "red");
+        insert_labeled(
+            &mango.get_bucket("first")?,
+            vec![(1, vec![red.clone()]), (2, vec![red.clone()])],
+        )?;
+        let config = BucketConfig {
+            format: SerFormat::Bincode,
+            ..Default::default()
+        };
+        insert_labeled(
+            &mango.get_bucket_with_config("second", config.clone())?,
+            vec![(3, vec![Label::new("shape", "round")])],
+        )?;
+
+        mango.backup_to_dir(&backup)?;
+        assert!(mango.backup_to_dir(&backup).is_err());
+
+        let restored = Mango::restore_from_dir(&backup, dir.path().join("restored"))?;
+        assert_eq!(restored.list_buckets()?, vec!["first", "second"]);
+        let first = restored.get_bucket("first")?;
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red])?;
+        assert_eq!(find_ids(&first, find)?, vec![1, 2]);
+        assert_eq!(first.get_object(2)?, Some(Bytes::from("object 2")));
+        let second = restored.get_bucket("second")?;
+        assert_eq!(second.config()?, config);
+        assert_eq!(second.labels(3)?, vec![Label::new("shape", "round")]);
+
+        // The original database is in use and holds data, and a plain directory has no backup
+        assert!(Mango::restore_from_dir(&backup, dir.path().join("db")).is_err());
+        assert!(Mango::restore_from_dir(dir.path(), dir.path().join("none")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_exclude_all() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("exclude_all")?;
+        let all = Label::new("set", "all");
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![all.clone(), red.clone()]),
+                (2, vec![all.clone(), red.clone(), big.clone()]),
+                (3, vec![all.clone(), big.clone()]),
+                (4, vec![all.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![all.clone()])?;
+        find.add_exclude_all_group(vec![red.clone(), big.clone()])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 3, 4]);
+
+        // Unlike an exclude group, which drops objects with either label
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![all])?;
+        find.add_exclude_group(vec![red, big])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_deriver() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("derived")?;
+        let registered = bucket.add_label_deriver(Box::new(|payload: &Bytes| {
+            let size = match payload.len() {
+                0..=8 => "small",
+                _ => "large",
+            };
+            vec![Label::new("size_bucket", size)]
+        }))?;
+        assert_eq!(registered, 1);
+
+        // Other handles to the bucket use the deriver too
+        let handle = mango.get_bucket("derived")?;
+        let small = InsertRequest::new_static_id(1, Bytes::from_static(b"tiny"))?;
+        small.add_label(Label::new("kind", "note"))?;
+        let large = InsertRequest::new_static_id(2, Bytes::from_static(b"a much longer payload"))?;
+        let labeled = InsertRequest::new_static_id(3, Bytes::from_static(b"tiny too"))?;
+        labeled.add_label(Label::new("size_bucket", "custom"))?;
+        handle.run(vec![small.into(), large.into(), labeled.into()])?;
+        handle.insert_many_batched(
+            vec![(Bytes::from_static(b"batched and large"), vec![])],
+            false,
+        )?;
+
+        let find = FindRequest::new()?;
+        find.any_of("size_bucket", &["large"])?;
+        let large_ids = find_ids(&bucket, find)?;
+        assert_eq!(large_ids.len(), 2);
+        assert!(large_ids.contains(&2));
+        assert_eq!(
+            bucket.labels(1)?,
+            vec![
+                Label::new("kind", "note"),
+                Label::new("size_bucket", "small")
+            ]
+        );
+        assert_eq!(bucket.labels(3)?, vec![Label::new("size_bucket", "custom")]);
+        assert!(mango
+            .get_bucket("plain")?
+            .derived_labels(&Bytes::new(), &[])?
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_project() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("project")?;
+        let all = Label::new("set", "all");
+        insert_labeled(
+            &bucket,
+            vec![
+                (
+                    1,
+                    vec![
+                        all.clone(),
+                        Label::new("name", "a"),
+                        Label::new("size", "1"),
+                    ],
+                ),
+                (
+                    2,
+                    vec![
+                        all.clone(),
+                        Label::new("name", "b"),
+                        Label::new("color", "red"),
+                    ],
+                ),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![all])?;
+        find.order_by(OrderKey::LabelValue("color".to_string()))?;
+        find.project(vec!["name".to_string(), "size".to_string()])?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(find.into())?;
+        tx.execute()?;
+        assert_eq!(
+            tx.find_results()?[0],
+            vec![
+                (2, vec![Label::new("name", "b")]),
+                (1, vec![Label::new("name", "a"), Label::new("size", "1")]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserve_ids() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let mut ranges = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let mango = mango.clone();
+                    s.spawn(move || -> Result<Vec<std::ops::Range<u64>>> {
+                        (0..50).map(|_| mango.reserve_ids(10)).collect()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().map_err(|_| anyhow!("reserving thread panicked"))?)
+                .collect::<Result<Vec<_>>>()
+        })?
+        .concat();
+        assert_eq!(ranges.len(), 400);
+        ranges.sort_by_key(|r| r.start);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end - pair[0].start, 10);
+            assert!(pair[0].end <= pair[1].start);
+        }
+
+        let last = ranges.last().map_or(0, |r| r.end);
+        let id = *InsertRequest::new_monotonic_id(&mango, Bytes::from_static(b"next"))?
+            .id
+            .borrow();
+        assert!(id >= last);
+        let empty = mango.reserve_ids(0)?;
+        assert!(empty.is_empty() && empty.start > id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_from_find() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("delete_find")?;
+        let red = Label::new("color", "red");
+        let blue = Label::new("color", "blue");
+        let big = Label::new("size", "big");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone()]),
+                (2, vec![red.clone()]),
+                (3, vec![blue.clone(), big.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red.clone()])?;
+        let results = bucket.run(vec![DeleteRequest::from_find(find).into()])?;
+        let mut deleted = results[0].as_delete().cloned().unwrap_or_default();
+        deleted.sort();
+        assert_eq!(deleted, vec![(1, true), (2, true)]);
+
+        assert_eq!(bucket.get_object(1)?, None);
+        assert_eq!(bucket.get_object(2)?, None);
+        assert!(bucket.object_ids_for_label(&red)?.is_empty());
+        assert!(!bucket.t_labels.contains_key(red.key_ltr()?)?);
+        assert_eq!(bucket.object_ids_for_label(&big)?, vec![3]);
+        assert_eq!(bucket.labels(3)?, vec![blue, big]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_ids_for_label() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("ids_for_label")?;
+        let red = Label::new("color", "red");
+        insert_labeled(
+            &bucket,
+            vec![(3, vec![red.clone()]), (1, vec![red.clone()])],
+        )?;
+        insert_labeled(&bucket, vec![(2, vec![Label::new("color", "blue")])])?;
+        insert_labeled(&bucket, vec![(7, vec![red.clone()])])?;
+
+        let mut ids = bucket.object_ids_for_label(&red)?;
+        ids.sort();
+        assert_eq!(ids, vec![1, 3, 7]);
+        assert!(bucket
+            .object_ids_for_label(&Label::new("color", "green"))?
+            .is_empty());
+
+        let config = BucketConfig {
+            normalize_keys: true,
+            ..Default::default()
+        };
+        let normalized = mango.get_bucket_with_config("ids_normalized", config)?;
+        insert_labeled(&normalized, vec![(1, vec![Label::new("Color", "red")])])?;
+        assert_eq!(
+            normalized.object_ids_for_label(&Label::new(" COLOR ", "red"))?,
+            vec![1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_borrow() -> Result<()> {
+        use crate::label::LabelParts;
+        use std::collections::{hash_map::DefaultHasher, HashMap};
+        use std::hash::{Hash, Hasher};
+
+        let mut counts: HashMap<Label, usize> = HashMap::new();
+        counts.insert(Label::new("color", "red"), 2);
+        counts.insert(Label::int("n", 1), 5);
+
+        let red = Label::new("color", "red");
+        assert_eq!(counts.get(&red), Some(&2));
+        assert_eq!(counts.get(&("color", "red") as &dyn LabelParts), Some(&2));
+        assert_eq!(counts.get(&("color", "blue") as &dyn LabelParts), None);
+        let encoded = Label::int("n", 1);
+        assert_eq!(counts.get(&encoded.parts() as &dyn LabelParts), Some(&5));
+
+        let hash = |h: &dyn Fn(&mut DefaultHasher)| {
+            let mut hasher = DefaultHasher::new();
+            h(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(
+            hash(&|s| red.hash(s)),
+            hash(&|s| (&("color", "red") as &dyn LabelParts).hash(s))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_tree_raw() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let aux = mango.open_tree_raw("aux")?;
+        aux.insert(b"cursor", b"42".to_vec())?;
+        assert_eq!(
+            mango.open_tree_raw("aux")?.get(b"cursor")?,
+            Some(b"42".as_ref().into())
+        );
+        assert!(mango.list_buckets()?.is_empty());
+
+        // Every bucket tree suffix is refused
+        let bucket = mango.get_bucket("b")?;
+        let suffixes: Vec<String> = bucket
+            .trees()
+            .iter()
+            .map(|t| String::from_utf8_lossy(&t.name())[2..].to_string())
+            .collect();
+        assert_eq!(suffixes, crate::bucket::TREE_SUFFIXES);
+
+        for name in ["", "objects", "labels", "a\u{001F}objects"] {
+            assert!(mango.open_tree_raw(name).is_err(), "{name:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_match_counts() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("match_counts")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        let old = Label::new("age", "old");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone(), old.clone()]),
+                (2, vec![red.clone()]),
+                (3, vec![big.clone(), old.clone()]),
+                (4, vec![old.clone()]),
+                (5, vec![red.clone(), big.clone()]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![red])?;
+        find.add_include_group(vec![big.clone()])?;
+        find.add_include_all_group(vec![big, old.clone()])?;
+        find.add_include_group(vec![old])?;
+        find.exclude_ids(vec![5])?;
+
+        let counts = find.match_counts(&bucket)?;
+        assert_eq!(counts, vec![(1, 4), (3, 3), (2, 1), (4, 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_on_drop() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db");
+        assert!(MangoConfig::default().flush_on_drop);
+
+        let mango = Mango::new(&path)?;
+        let bucket = mango.get_bucket("flushed")?;
+        let insert = InsertRequest::new_static_id(1, Bytes::from_static(b"kept"))?;
+        insert.add_label(Label::new("flushed", "yes"))?;
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(insert.into())?;
+        tx.execute()?;
+        drop(tx);
+        drop(bucket);
+
+        // Whichever of two clones dropped together goes last flushes
+        let clone = mango.clone();
+        let threads = [
+            std::thread::spawn(move || drop(mango)),
+            std::thread::spawn(move || drop(clone)),
+        ];
+        for thread in threads {
+            thread.join().expect("drop thread panicked");
+        }
+
+        let reopened = reopen(&path)?.get_bucket("flushed")?;
+        assert_eq!(reopened.get_object(1)?, Some(Bytes::from_static(b"kept")));
+        assert_eq!(reopened.labels(1)?, vec![Label::new("flushed", "yes")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_any_match() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("any_match")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        let old = Label::new("age", "old");
+        let mut items = vec![(1, vec![red.clone(), big.clone()])];
+        items.extend((2..200).map(|id| (id, vec![red.clone()])));
+        items.push((200, vec![old.clone()]));
+        insert_labeled(&bucket, items)?;
+
+        assert!(bucket.any_match(vec![red.clone(), big.clone()])?);
+        assert!(bucket.any_match(vec![old.clone()])?);
+        assert!(!bucket.any_match(vec![red, old])?);
+        assert!(!bucket.any_match(vec![big, Label::new("color", "blue")])?);
+        assert!(!bucket.any_match(vec![])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_created_between() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("created")?;
+        let tag = Label::new("kind", "event");
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(20));
+
+        insert_labeled(&bucket, vec![(1, vec![tag.clone()])])?;
+        pause();
+        let start = crate::object::unix_millis();
+        insert_labeled(&bucket, vec![(2, vec![tag.clone()])])?;
+        let batched = bucket.insert_many_batched(
+            vec![(Bytes::from_static(b"batched"), vec![tag.clone()])],
+            false,
+        )?;
+        let end = crate::object::unix_millis();
+        pause();
+        insert_labeled(&bucket, vec![(3, vec![tag.clone()])])?;
+
+        // Reinserting keeps the original timestamp
+        let first = bucket.created_at(1)?;
+        insert_labeled(&bucket, vec![(1, vec![tag.clone()])])?;
+        assert_eq!(bucket.created_at(1)?, first);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![tag])?;
+        find.created_between(start, end)?;
+        let mut expected = vec![2, batched[0]];
+        expected.sort();
+        assert_eq!(find_ids(&bucket, find.clone())?, expected);
+
+        let tx: Transaction = (&bucket).into();
+        tx.append_request(DeleteRequest::new(vec![2]).into())?;
+        tx.execute()?;
+        assert_eq!(bucket.created_at(2)?, None);
+        assert_eq!(find_ids(&bucket, find)?, vec![batched[0]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_updated_at() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("updated")?;
+        let tag = Label::new("kind", "note");
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(20));
+
+        insert_labeled(
+            &bucket,
+            vec![(1, vec![tag.clone()]), (2, vec![tag.clone()])],
+        )?;
+        let created = bucket.created_at(1)?.expect("created_at");
+        assert_eq!(bucket.updated_at(1)?, Some(created));
+
+        // Reads leave it alone
+        pause();
+        bucket.get_object(1)?;
+        bucket.labels(1)?;
+        assert_eq!(bucket.updated_at(1)?, Some(created));
+
+        let since = crate::object::unix_millis();
+        insert_labeled(&bucket, vec![(1, vec![tag.clone()])])?;
+        let overwritten = bucket.updated_at(1)?.expect("updated_at");
+        assert!(overwritten > created);
+        assert_eq!(bucket.created_at(1)?, Some(created));
+
+        pause();
+        assert!(bucket.touch(1, 60)?);
+        let touched = bucket.updated_at(1)?.expect("updated_at");
+        assert!(touched > overwritten);
+
+        pause();
+        bucket.rename_label_key("kind", "type")?;
+        assert!(bucket.updated_at(1)? > Some(touched));
+        assert_eq!(bucket.created_at(1)?, Some(created));
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("type", "note")])?;
+        find.updated_between(since, crate::object::unix_millis())?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![Label::new("type", "note")])?;
+        find.updated_between(0, since - 1)?;
+        assert!(find_ids(&bucket, find)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_with() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("execute_with")?;
+        let tag = Label::new("kind", "streamed");
+
+        let tx: Transaction = (&bucket).into();
+        for id in 1..=10 {
+            let insert = InsertRequest::new_static_id(id, Bytes::from(format!("object {id}")))?;
+            insert.add_label(tag.clone())?;
+            tx.append_request(insert.into())?;
+        }
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![tag.clone()])?;
+        tx.append_request(find.into())?;
+
+        let mut seen = vec![];
+        tx.execute_with(|n, result| {
+            seen.push(n);
+            if n == 10 {
+                assert_eq!(result.as_find().map(Vec::len), Some(10));
+            }
+        })?;
+        assert_eq!(seen, (0..11).collect::<Vec<_>>());
+        assert!(tx.completed()?);
+        assert!(tx.results()?.is_empty());
+        assert_eq!(bucket.object_ids_for_label(&tag)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_exclude_glob() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("exclude_glob")?;
+        let tag = Label::new("kind", "doc");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![tag.clone(), Label::new("status", "temp-1")]),
+                (2, vec![tag.clone(), Label::new("status", "temporary")]),
+                (3, vec![tag.clone(), Label::new("status", "final")]),
+                (4, vec![tag.clone()]),
+                (5, vec![tag.clone(), Label::new("owner", "temp-1")]),
+            ],
+        )?;
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![tag.clone()])?;
+        find.exclude_glob("status", "temp*")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![3, 4, 5]);
+
+        let find = FindRequest::new()?;
+        find.add_include_group(vec![tag])?;
+        find.exclude_glob("status", "*a?")?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1, 2, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_with_mode() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for (n, mode) in [sled::Mode::LowSpace, sled::Mode::HighThroughput]
+            .into_iter()
+            .enumerate()
+        {
+            let path = dir.path().join(format!("db{n}"));
+            {
+                let mango = Mango::open_with_mode(&path, mode, Some(16 * 1024 * 1024))?;
+                let bucket = mango.get_bucket("mode")?;
+                insert_labeled(&bucket, vec![(1, vec![Label::new("mode", "set")])])?;
+                assert_eq!(
+                    bucket.object_ids_for_label(&Label::new("mode", "set"))?,
+                    vec![1]
+                );
+            }
+
+            let bucket =
+                reopen_with(|| Mango::open_with_mode(&path, mode, None))?.get_bucket("mode")?;
+            assert_eq!(bucket.get_object(1)?, Some(Bytes::from_static(b"object 1")));
+        }
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"not a database")?;
+        let err = Mango::open_with_mode(&file, sled::Mode::LowSpace, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MangoError>(),
+            Some(MangoError::PathNotAccessible(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_diff() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let left = mango.get_bucket("left")?;
+        let right = mango.get_bucket_with_config(
+            "right",
+            BucketConfig {
+                format: SerFormat::Bincode,
+                ..Default::default()
+            },
+        )?;
+        let tag = Label::new("side", "any");
+        insert_labeled(&left, (1..=5).map(|id| (id, vec![tag.clone()])).collect())?;
+        insert_labeled(&right, (3..=8).map(|id| (id, vec![tag.clone()])).collect())?;
+
+        // Same id, different payload
+        let tx: Transaction = (&right).into();
+        tx.append_request(InsertRequest::new_static_id(4, Bytes::from_static(b"changed"))?.into())?;
+        tx.execute()?;
+
+        // Different labels alone are not a difference
+        insert_labeled(&left, vec![(5, vec![Label::new("side", "left")])])?;
+
+        let diff = left.diff(&right)?;
+        assert_eq!(
+            diff,
+            BucketDiff {
+                only_in_self: vec![1, 2],
+                only_in_other: vec![6, 7, 8],
+                differing: vec![4],
+            }
+        );
+        assert_eq!(right.diff(&left)?.only_in_self, vec![6, 7, 8]);
+        assert_eq!(left.diff(&left)?, BucketDiff::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_co_occurring_labels() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("co_occurring")?;
+        let dog = Label::new("animal", "dog");
+        let brown = Label::new("color", "brown");
+        let black = Label::new("color", "black");
+        let park = Label::new("place", "park");
+        let beach = Label::new("place", "beach");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![dog.clone(), brown.clone(), park.clone()]),
+                (2, vec![dog.clone(), brown.clone(), beach.clone()]),
+                (3, vec![dog.clone(), black.clone(), park.clone()]),
+                (4, vec![dog.clone(), brown.clone()]),
+                (
+                    5,
+                    vec![Label::new("animal", "cat"), black.clone(), park.clone()],
+                ),
+            ],
+        )?;
+
+        assert_eq!(
+            bucket.co_occurring_labels(&dog, 3)?,
+            vec![(brown, 3), (park, 2), (black.clone(), 1)]
+        );
+        assert_eq!(bucket.co_occurring_labels(&dog, 10)?.len(), 4);
+        assert!(bucket
+            .co_occurring_labels(&Label::new("animal", "bird"), 3)?
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_at_least() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let bucket = mango.get_bucket("at_least")?;
+        let red = Label::new("color", "red");
+        let big = Label::new("size", "big");
+        let old = Label::new("age", "old");
+        insert_labeled(
+            &bucket,
+            vec![
+                (1, vec![red.clone(), big.clone(), old.clone()]),
+                (2, vec![red.clone(), big.clone()]),
+                (3, vec![old.clone()]),
+                (4, vec![big.clone(), old.clone()]),
+                (5, vec![Label::new("color", "blue")]),
+            ],
+        )?;
+        let labels = vec![red.clone(), big.clone(), old.clone()];
+        let at_least = |k: usize| -> Result<Vec<ObjectID>> {
+            let find = FindRequest::new()?;
+            find.at_least(k, labels.clone())?;
+            find_ids(&bucket, find)
+        };
+
+        assert_eq!(at_least(1)?, vec![1, 2, 3, 4]);
+        assert_eq!(at_least(2)?, vec![1, 2, 4]);
+        assert_eq!(at_least(labels.len())?, vec![1]);
+        assert!(at_least(4)?.is_empty());
+        assert!(FindRequest::new()?.at_least(0, labels.clone()).is_err());
+
+        // Repeating a label does not count it twice
+        let find = FindRequest::new()?;
+        find.at_least(2, vec![old.clone(), old, red])?;
+        assert_eq!(find_ids(&bucket, find)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_cache_aborted_transaction() -> Result<()> {
+        let mango = Mango::new_temp()?;
+        let config = BucketConfig {
+            object_cache_bytes: Some(1024),
+            ..Default::default()
+        };
+        let bucket = mango.get_bucket_with_config("cached", config)?;
+        let old = Bytes::from_static(b"old");
+        let new = Bytes::from_static(b"new");
+        bucket.run(vec![InsertRequest::new_static_id(1, old.clone())?.into()])?;
+        assert_eq!(bucket.get_object(1)?, Some(old.clone()));
+
+        // Overwrite and read back 1, and read 2 that nothing cached yet, then abort
+        let aborted = bucket.run(vec![
+            InsertRequest::new_static_id(1, new.clone())?.into(),
+            GetRequest::new(vec![1])?.into(),
+            InsertRequest::new_static_id(2, new.clone())?.into(),
+            GetRequest::new(vec![2])?.into(),
+            CswapRequest::new(3, Some(Bytes::from_static(b"missing")), new)?.into(),
+        ]);
+        assert!(aborted.is_err());
+
+        assert_eq!(bucket.get_object(1)?, Some(old.clone()));
+        let results = bucket.run(vec![GetRequest::new(vec![1, 2])?.into()])?;
+        assert_eq!(
+            results[0].as_get(),
+            Some(&vec![(1, old), (2, Bytes::new())])
+        );
+        assert!(bucket.get_object(2)?.is_none());
+
+        // A payload read before a concurrent invalidation is not cached
+        let cache = bucket.cache.as_ref().ok_or_else(|| anyhow!("no cache"))?;
+        let mut cache = cache.lock().map_err(|e| anyhow!("{e}"))?;
+        let epoch = cache.epoch();
+        cache.remove(4);
+        cache.fill(4, Bytes::from_static(b"stale"), epoch);
+        assert!(cache.get(4).is_none());
+        Ok(())
+    }
 }
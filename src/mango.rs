@@ -1,23 +1,701 @@
-use anyhow::Result;
-use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use super::bucket::Bucket;
+use super::bucket::{Bucket, BucketConfig, LabelDeriver, SEPARATOR, TREE_SUFFIXES};
+use super::cache::{ObjectCache, SharedObjectCache};
+use super::error::MangoError;
+use super::label::Label;
+use super::object::ObjectID;
+use super::query::{delete::DeleteRequest, insert::InsertRequest, transaction::Transaction};
+
+/// Written at the start of every snapshot
+const SNAPSHOT_MAGIC: &[u8; 8] = b"MANGOSN1";
+
+/// Written at the start of every file of a backup directory
+const BACKUP_MAGIC: &[u8; 8] = b"MANGOBK1";
+
+/// Key of the next unreserved object id in sled's default tree, see `Mango::reserve_ids`
+const NEXT_ID_KEY: &[u8] = b"mango_chainsaw/next_id";
+
+/// Bucket names refused by `Mango::get_bucket` unless reconfigured
+pub const RESERVED_BUCKET_NAMES: &[&str] = &["ext", "namespace", "namespaces"];
+
+/// One key/value pair of one bucket tree in a snapshot
+#[derive(Serialize, Deserialize)]
+struct SnapshotRecord {
+    bucket: String,
+    tree: String,
+    key: Bytes,
+    value: Bytes,
+}
+
+/// Dangling references between a bucket's trees, found by `Mango::verify`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Objects with no entry in the objects labels tree
+    pub objects_without_labels: Vec<ObjectID>,
+
+    /// Objects labels entries for objects that are not stored
+    pub labels_without_objects: Vec<ObjectID>,
+
+    /// Labels carried by an object but missing from the labels or labels invert tree
+    pub missing_labels: Vec<Label>,
+
+    /// Labels carried by an object whose object list does not include it. Objects inserted
+    /// with `skip_label_index` are reported here too.
+    pub unindexed: Vec<(ObjectID, Label)>,
+
+    /// Object lists naming an object that is not stored or does not carry the label
+    pub dangling: Vec<(Label, ObjectID)>,
+}
+
+impl VerifyReport {
+    /// Whether no dangling references were found
+    pub fn is_consistent(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Settings shared by every bucket and transaction of a Mango
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MangoConfig {
+    /// How many times a transaction is retried after conflicting with a concurrent one
+    /// before it fails with `TransactionError::RetryBudgetExhausted`. None retries until
+    /// it commits, which is sled's own behaviour. `Transaction::retry_budget` overrides it.
+    ///
+    /// Default: None
+    pub retry_budget: Option<usize>,
+
+    /// Transactions estimated to write more keys than this fail with
+    /// `TransactionError::TooLarge` before they start, or once a delete's find has matched
+    /// too many objects. None allows any size.
+    /// `Transaction::max_writes` overrides it.
+    ///
+    /// Default: None
+    pub max_transaction_writes: Option<usize>,
+
+    /// Flush sled when the last handle to this Mango, including its buckets, is dropped,
+    /// so recent writes survive the process exiting right after. Errors are logged.
+    /// Turn it off where shutdown speed matters more, such as tests.
+    ///
+    /// Default: true
+    pub flush_on_drop: bool,
+}
+
+impl Default for MangoConfig {
+    fn default() -> Self {
+        Self {
+            retry_budget: None,
+            max_transaction_writes: None,
+            flush_on_drop: true,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Mango {
     pub(crate) inner: sled::Db,
     path: PathBuf,
+
+    /// Object caches by bucket name, so every handle to a bucket sees its invalidations
+    caches: Arc<Mutex<HashMap<String, SharedObjectCache>>>,
+
+    /// Names `get_bucket` refuses, shared by every clone of this Mango
+    reserved: Arc<Mutex<HashSet<String>>>,
+
+    /// Label derivers by bucket name, see `Bucket::add_label_deriver`
+    derivers: Arc<Mutex<HashMap<String, Vec<LabelDeriver>>>>,
+
+    /// Whether each bucket is still open by name, so `Bucket::empty` reaches every handle
+    open_flags: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+
+    config: MangoConfig,
+
+    /// Shared by every clone, so the database is flushed once the last one is dropped
+    flusher: Arc<FlushOnDrop>,
+}
+
+/// Flushes the database when dropped, which happens exactly once, with the last clone of
+/// the Mango holding it. See `MangoConfig::flush_on_drop`.
+#[derive(Debug)]
+struct FlushOnDrop {
+    db: sled::Db,
+    path: PathBuf,
+    enabled: AtomicBool,
+}
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = self.db.flush() {
+            log::error!("failed to flush {} on drop: {e}", self.path.display());
+        }
+    }
 }
 
 impl Mango {
+    /// Open (or create) a Mango at `path`.
+    ///
+    /// Errors are a `MangoError` telling an inaccessible path apart from a corrupt database.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        path.as_ref().to_path_buf().try_into()
+    }
+
     pub fn open(path: &Path) -> Result<Self> {
-        path.to_path_buf().try_into()
+        Self::new(path)
+    }
+
+    /// Open (or create) a Mango at `path` with sled tuned by `mode`: `LowSpace` compacts
+    /// more eagerly to use less disk, `HighThroughput` (what `new` uses) favours write
+    /// speed. `cache_capacity` bounds sled's page cache in bytes, None for sled's default.
+    ///
+    /// Errors are a `MangoError`, as for `new`.
+    pub fn open_with_mode(
+        path: impl AsRef<Path>,
+        mode: sled::Mode,
+        cache_capacity: Option<u64>,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() && !path.is_dir() {
+            return Err(MangoError::PathNotAccessible(
+                path.clone(),
+                std::io::Error::other("not a directory"),
+            )
+            .into());
+        }
+
+        let mut config = sled::Config::new()
+            .path(path.clone())
+            .compression_factor(16)
+            .mode(mode)
+            .idgen_persist_interval(5000)
+            .use_compression(true);
+        if let Some(capacity) = cache_capacity {
+            config = config.cache_capacity(capacity);
+        }
+        let this = config
+            .open()
+            .map_err(|e| MangoError::from_open(path.clone(), e))?;
+        Ok(Self::from_db(this, path))
+    }
+
+    fn from_db(inner: sled::Db, path: PathBuf) -> Self {
+        Self {
+            inner: inner.clone(),
+            path: path.clone(),
+            caches: Default::default(),
+            reserved: Arc::new(Mutex::new(
+                RESERVED_BUCKET_NAMES
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect(),
+            )),
+            derivers: Default::default(),
+            open_flags: Default::default(),
+            config: MangoConfig::default(),
+            flusher: Arc::new(FlushOnDrop {
+                db: inner,
+                path,
+                enabled: AtomicBool::new(MangoConfig::default().flush_on_drop),
+            }),
+        }
+    }
+
+    /// Use `config` for every bucket opened from this Mango from now on. `flush_on_drop`
+    /// applies to every clone, since the flush happens once for all of them.
+    pub fn with_config(mut self, config: MangoConfig) -> Self {
+        self.config = config;
+        self.flusher
+            .enabled
+            .store(config.flush_on_drop, Ordering::SeqCst);
+        self
+    }
+
+    pub fn config(&self) -> MangoConfig {
+        self.config
     }
 
+    /// Reserve `n` consecutive object ids for a worker to hand out itself, without going
+    /// back to the Mango for each insert.
+    ///
+    /// Ranges never overlap, across threads and clones of this Mango, with each other or
+    /// with ids from `InsertRequest::new_monotonic_id`. The counter is stored in the
+    /// database, so it survives reopens and `backup_to_dir`.
+    pub fn reserve_ids(&self, n: u64) -> Result<std::ops::Range<u64>> {
+        // Databases from before the counter existed continue past sled's generated ids
+        let floor = match self.inner.contains_key(NEXT_ID_KEY)? {
+            true => 0,
+            false => self.inner.generate_id()?,
+        };
+        let decode = |old: Option<&[u8]>| -> u64 {
+            old.and_then(|b| b.try_into().ok())
+                .map_or(floor, u64::from_be_bytes)
+        };
+        let old = self.inner.fetch_and_update(NEXT_ID_KEY, |old| {
+            let start = decode(old);
+            Some(start.checked_add(n).unwrap_or(start).to_be_bytes().to_vec())
+        })?;
+        let start = decode(old.as_deref());
+        let end = start
+            .checked_add(n)
+            .ok_or_else(|| anyhow!("cannot reserve {n} more object ids"))?;
+        Ok(start..end)
+    }
+
+    /// Open a bucket, creating it if it does not exist yet.
+    ///
+    /// Fails with `MangoError::ReservedBucketName` for a reserved name.
     pub fn get_bucket(&self, name: &str) -> Result<Bucket> {
+        self.check_bucket_name(name)?;
         Bucket::open(name, self.clone())
     }
 
+    /// Open a bucket, creating it with `config` if it does not exist yet
+    pub fn get_bucket_with_config(&self, name: &str, config: BucketConfig) -> Result<Bucket> {
+        self.check_bucket_name(name)?;
+        Bucket::open_with_config(name, self.clone(), config)
+    }
+
+    /// Replace the bucket names `get_bucket` refuses, `RESERVED_BUCKET_NAMES` by default.
+    ///
+    /// Existing buckets with a newly reserved name can still be listed and snapshotted.
+    pub fn set_reserved_bucket_names(&self, names: &[&str]) -> Result<()> {
+        let mut reserved = self.reserved.lock().map_err(|e| anyhow!("{e}"))?;
+        *reserved = names.iter().map(|n| n.to_string()).collect();
+        Ok(())
+    }
+
+    fn check_bucket_name(&self, name: &str) -> Result<()> {
+        let reserved = self.reserved.lock().map_err(|e| anyhow!("{e}"))?;
+        if reserved.contains(name) {
+            log::warn!("rejected reserved bucket name {name:?}");
+            return Err(MangoError::ReservedBucketName(name.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Open a sled tree of your own for data kept alongside the buckets, creating it if
+    /// needed.
+    ///
+    /// The tree is namespaced apart from bucket trees, so it never shows up as a bucket,
+    /// but nothing in it is indexed or visible to queries, snapshots or `verify`. Names
+    /// that are empty, contain SEPARATOR or match a bucket tree suffix such as `objects`
+    /// are refused.
+    pub fn open_tree_raw(&self, name: &str) -> Result<sled::Tree> {
+        if name.is_empty() || name.contains(SEPARATOR) || TREE_SUFFIXES.contains(&name) {
+            return Err(anyhow!("{name:?} is not allowed as a raw tree name"));
+        }
+        Ok(self
+            .inner
+            .open_tree(format!("{SEPARATOR}raw{SEPARATOR}{name}"))?)
+    }
+
+    /// Open a bucket only if it already exists, without creating any trees
+    pub fn open_bucket_if_exists(&self, name: &str) -> Result<Option<Bucket>> {
+        let objects = format!("{name}{SEPARATOR}objects");
+        match self
+            .inner
+            .tree_names()
+            .iter()
+            .any(|t| t == objects.as_bytes())
+        {
+            true => Ok(Some(Bucket::open(name, self.clone())?)),
+            false => Ok(None),
+        }
+    }
+
+    /// The object cache for bucket `name`, created with `capacity` bytes on first use
+    pub(crate) fn object_cache(&self, name: &str, capacity: usize) -> Result<SharedObjectCache> {
+        let mut caches = self.caches.lock().map_err(|e| anyhow!("{e}"))?;
+        let cache = caches
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(ObjectCache::new(capacity))));
+        Ok(cache.clone())
+    }
+
+    pub(crate) fn add_label_deriver(&self, name: &str, deriver: LabelDeriver) -> Result<usize> {
+        let mut derivers = self.derivers.lock().map_err(|e| anyhow!("{e}"))?;
+        let bucket_derivers = derivers.entry(name.to_string()).or_default();
+        bucket_derivers.push(deriver);
+        Ok(bucket_derivers.len())
+    }
+
+    /// The open flag shared by every handle to bucket `name`. A bucket emptied earlier is
+    /// created again by the open, so it gets a new flag and older handles stay dropped.
+    pub(crate) fn bucket_open_flag(&self, name: &str) -> Result<Arc<AtomicBool>> {
+        let mut flags = self.open_flags.lock().map_err(|e| anyhow!("{e}"))?;
+        let flag = flags
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(true)));
+        if !flag.load(Ordering::SeqCst) {
+            *flag = Arc::new(AtomicBool::new(true));
+        }
+        Ok(flag.clone())
+    }
+
+    pub(crate) fn label_derivers(&self, name: &str) -> Result<Vec<LabelDeriver>> {
+        let derivers = self.derivers.lock().map_err(|e| anyhow!("{e}"))?;
+        Ok(derivers.get(name).cloned().unwrap_or_default())
+    }
+
+    /// The names of every bucket in this Mango
+    pub fn list_buckets(&self) -> Result<Vec<String>> {
+        let suffix = format!("{SEPARATOR}objects");
+        let mut names = vec![];
+        for tree in self.inner.tree_names() {
+            let tree = String::from_utf8(tree.to_vec())?;
+            if let Some(name) = tree.strip_suffix(&suffix) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Open every bucket in this Mango, sorted by name.
+    ///
+    /// Buckets that fail to open are logged and skipped. Reserved names are not refused.
+    pub fn iter_buckets(&self) -> Result<Vec<Bucket>> {
+        let mut buckets = vec![];
+        for name in self.list_buckets()? {
+            match Bucket::open(&name, self.clone()) {
+                Ok(bucket) => buckets.push(bucket),
+                Err(e) => log::warn!("skipping bucket {name:?} that failed to open: {e}"),
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// Find the objects described by `label` in every bucket, keyed by bucket name.
+    ///
+    /// Buckets are independent trees, so they are searched in parallel.
+    pub fn query_all_buckets(&self, label: Label) -> Result<HashMap<String, Vec<ObjectID>>> {
+        self.list_buckets()?
+            .into_par_iter()
+            .map(|name| {
+                let mut ids = Bucket::open(&name, self.clone())?.object_ids_for_label(&label)?;
+                ids.sort();
+                Ok((name, ids))
+            })
+            .collect()
+    }
+
+    /// Approximate bytes used by each bucket, keyed by bucket name.
+    ///
+    /// sled only reports `size_on_disk` for the whole database, so this sums the key and
+    /// value lengths across each bucket's trees. Compression and sled's own overhead mean
+    /// it will not match what the files occupy on disk.
+    pub fn space_usage(&self) -> Result<HashMap<String, u64>> {
+        let mut usage = HashMap::new();
+        for name in self.list_buckets()? {
+            let bucket = Bucket::open(&name, self.clone())?;
+            let mut total = 0u64;
+            for tree in bucket.trees() {
+                for kv in tree.iter() {
+                    let (k, v) = kv?;
+                    total += (k.len() + v.len()) as u64;
+                }
+            }
+            usage.insert(name, total);
+        }
+        Ok(usage)
+    }
+
+    /// Flush to disk and report roughly how many bytes the files shrank by.
+    ///
+    /// sled 0.34 has no explicit compaction; segments freed by deletes are reclaimed as
+    /// writes are flushed. This flushes and compares `size_on_disk` before and after,
+    /// returning 0 if the files did not shrink.
+    pub fn compact(&self) -> Result<u64> {
+        let before = self.inner.size_on_disk()?;
+        self.inner.flush()?;
+        let after = self.inner.size_on_disk()?;
+        log::debug!("compacted {before} bytes on disk to {after}");
+        Ok(before.saturating_sub(after))
+    }
+
+    /// Write every bucket's trees to `w`, returning the number of bytes written.
+    ///
+    /// Bucket names and object ids are preserved. Restore with `Mango::restore_from`.
+    pub fn snapshot_to<W: Write>(&self, mut w: W) -> Result<u64> {
+        w.write_all(SNAPSHOT_MAGIC)?;
+        let mut written = SNAPSHOT_MAGIC.len() as u64;
+
+        for name in self.list_buckets()? {
+            let bucket = Bucket::open(&name, self.clone())?;
+            let prefix = format!("{name}{SEPARATOR}");
+            for tree in bucket.trees() {
+                let tree_name = String::from_utf8(tree.name().to_vec())?;
+                let suffix = tree_name
+                    .strip_prefix(&prefix)
+                    .ok_or_else(|| anyhow!("tree {tree_name} does not belong to {name}"))?;
+                for kv in tree.iter() {
+                    let (key, value) = kv?;
+                    let record = SnapshotRecord {
+                        bucket: name.clone(),
+                        tree: suffix.to_string(),
+                        key: Bytes::copy_from_slice(&key),
+                        value: Bytes::copy_from_slice(&value),
+                    };
+                    let mut s = flexbuffers::FlexbufferSerializer::new();
+                    record.serialize(&mut s)?;
+                    let buf = s.view();
+                    w.write_all(&(buf.len() as u64).to_le_bytes())?;
+                    w.write_all(buf)?;
+                    written += 8 + buf.len() as u64;
+                }
+            }
+        }
+
+        w.flush()?;
+        Ok(written)
+    }
+
+    /// Load a snapshot written by `Mango::snapshot_to`, returning the number of entries.
+    ///
+    /// Entries overwrite any existing keys. Ids handed out by `generate_id` are not part of
+    /// the snapshot, so prefer restoring into a fresh Mango.
+    pub fn restore_from<R: Read>(&self, mut r: R) -> Result<u64> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(anyhow!("not a mango_chainsaw snapshot"));
+        }
+
+        // Restored entries bypass transactions, so nothing cached can be trusted
+        for cache in self.caches.lock().map_err(|e| anyhow!("{e}"))?.values() {
+            cache.lock().map_err(|e| anyhow!("{e}"))?.clear();
+        }
+
+        let mut restored = 0u64;
+        let mut len_buf = [0u8; 8];
+        loop {
+            match r.read_exact(&mut len_buf) {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let mut buf = vec![0u8; u64::from_le_bytes(len_buf) as usize];
+            r.read_exact(&mut buf)?;
+            let record: SnapshotRecord = flexbuffers::from_slice(&buf)?;
+
+            let tree = self
+                .inner
+                .open_tree(format!("{}{SEPARATOR}{}", record.bucket, record.tree))?;
+            tree.insert(record.key.as_ref(), record.value.as_ref())?;
+            restored += 1;
+        }
+
+        self.inner.flush()?;
+        Ok(restored)
+    }
+
+    /// Back up the whole database to `dir` with sled's export, one file per tree.
+    ///
+    /// Unlike `snapshot_to` this covers every tree sled holds, not only bucket trees, in
+    /// a form that survives sled upgrades, including the counter behind `reserve_ids`.
+    /// The directory is created if needed and must not already hold a backup.
+    pub fn backup_to_dir(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        self.inner.flush()?;
+        for (n, (kind, name, entries)) in self.inner.export().into_iter().enumerate() {
+            let path = dir.join(format!("{n:05}.mangobk"));
+            let mut w = std::io::BufWriter::new(
+                std::fs::File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?,
+            );
+            w.write_all(BACKUP_MAGIC)?;
+            write_field(&mut w, &kind)?;
+            write_field(&mut w, &name)?;
+            for kv in entries {
+                for field in kv {
+                    write_field(&mut w, &field)?;
+                }
+            }
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Create a Mango at `path` holding a backup written by `backup_to_dir`.
+    ///
+    /// `path` must not hold a database with any data yet. Each tree is read into memory
+    /// whole before it is imported.
+    pub fn restore_from_dir(dir: &Path, path: impl AsRef<Path>) -> Result<Self> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "mangobk") {
+                files.push(path);
+            }
+        }
+        if files.is_empty() {
+            return Err(anyhow!("no mango_chainsaw backup in {}", dir.display()));
+        }
+        files.sort();
+
+        let mut export = vec![];
+        for file in files {
+            let mut r = std::io::BufReader::new(std::fs::File::open(&file)?);
+            let mut magic = [0u8; 8];
+            r.read_exact(&mut magic)?;
+            if &magic != BACKUP_MAGIC {
+                return Err(anyhow!("{} is not a mango_chainsaw backup", file.display()));
+            }
+            let kind =
+                read_field(&mut r)?.ok_or_else(|| anyhow!("{} is truncated", file.display()))?;
+            let name =
+                read_field(&mut r)?.ok_or_else(|| anyhow!("{} is truncated", file.display()))?;
+            let mut entries = vec![];
+            while let Some(key) = read_field(&mut r)? {
+                let value = read_field(&mut r)?
+                    .ok_or_else(|| anyhow!("{} is truncated", file.display()))?;
+                entries.push(vec![key, value]);
+            }
+            export.push((kind, name, entries.into_iter()));
+        }
+
+        // sled panics when an imported key already exists
+        let this = Self::new(path)?;
+        let occupied = this.inner.tree_names().iter().any(|name| {
+            this.inner
+                .open_tree(name)
+                .map_or(true, |tree| !tree.is_empty())
+        });
+        if occupied {
+            return Err(anyhow!("can only restore a backup into an empty database"));
+        }
+        this.inner.import(export);
+        this.inner.flush()?;
+        Ok(this)
+    }
+
+    /// Cross-check the trees of bucket `name` and report every dangling reference between
+    /// objects and the label indexes, without fixing anything.
+    ///
+    /// Reads every tree outside a transaction, so concurrent writes can show up as
+    /// inconsistencies. Fails if the bucket does not exist.
+    pub fn verify(&self, name: &str) -> Result<VerifyReport> {
+        let bucket = self
+            .open_bucket_if_exists(name)?
+            .ok_or_else(|| anyhow!("bucket {name} does not exist"))?;
+        let format = bucket.format;
+        let mut report = VerifyReport::default();
+
+        let mut objects = BTreeSet::new();
+        for key in bucket.t_objects.iter().keys() {
+            objects.insert(flexbuffers::from_slice::<ObjectID>(&key?)?);
+        }
+
+        let mut object_labels: HashMap<ObjectID, Vec<Label>> = HashMap::new();
+        for kv in bucket.t_objects_labels.iter() {
+            let (k, v) = kv?;
+            let id: ObjectID = flexbuffers::from_slice(&k)?;
+            match objects.contains(&id) {
+                true => {
+                    object_labels.insert(id, format.de(&v)?);
+                }
+                false => report.labels_without_objects.push(id),
+            }
+        }
+        report.objects_without_labels = objects
+            .iter()
+            .filter(|id| !object_labels.contains_key(id))
+            .copied()
+            .collect();
+
+        let mut label_objects: HashMap<Label, HashSet<ObjectID>> = HashMap::new();
+        for kv in bucket.t_labels_objects.iter() {
+            let (k, v) = kv?;
+            let ids: Vec<ObjectID> = format.de(&v)?;
+            label_objects.insert(Label::from_key_ltr(&k)?, ids.into_iter().collect());
+        }
+
+        let mut missing = BTreeSet::new();
+        for (id, labels) in object_labels.iter() {
+            for label in labels {
+                if !bucket.t_labels.contains_key(label.key_ltr()?)?
+                    || !bucket.t_labels_invert.contains_key(label.key_rtl())?
+                {
+                    missing.insert(label.clone());
+                }
+                if !label_objects.get(label).is_some_and(|ids| ids.contains(id)) {
+                    report.unindexed.push((*id, label.clone()));
+                }
+            }
+        }
+        report.missing_labels = missing.into_iter().collect();
+
+        for (label, ids) in label_objects.iter() {
+            for id in ids {
+                if !object_labels.get(id).is_some_and(|l| l.contains(label)) {
+                    report.dangling.push((label.clone(), *id));
+                }
+            }
+        }
+
+        report.labels_without_objects.sort();
+        report.unindexed.sort();
+        report.dangling.sort();
+        Ok(report)
+    }
+
+    /// Move an object, with its labels and attributes, from one bucket to another.
+    ///
+    /// sled cannot run one transaction across both buckets, so the object is first
+    /// committed to `to` and only then deleted (with label pruning) from `from`. If the
+    /// delete fails the object is left in both buckets, never in neither.
+    ///
+    /// Fails with `MangoError::SameBucket` when `from` and `to` are the same bucket,
+    /// `MangoError::ObjectExists` when `to` already holds `id`, and
+    /// `MangoError::SharedObject` when dedup shares the payload between several inserts
+    /// in `from`, since deleting it there would only release one reference.
+    pub fn move_object(&self, from: &Bucket, to: &Bucket, id: ObjectID) -> Result<()> {
+        if from.name == to.name && Arc::ptr_eq(&from.parent.caches, &to.parent.caches) {
+            return Err(MangoError::SameBucket(from.name.clone()).into());
+        }
+        let payload = from
+            .get_object(id)?
+            .ok_or_else(|| anyhow!("object {id} not found"))?;
+        if to.get_object(id)?.is_some() {
+            return Err(MangoError::ObjectExists(to.name.clone(), id).into());
+        }
+        let refs = from.content_refs(id, &payload)?;
+        if refs > 1 {
+            return Err(MangoError::SharedObject(id, refs).into());
+        }
+
+        let insert = InsertRequest::new_static_id(id, payload)?;
+        insert.add_labels(from.labels(id)?)?;
+        for (key, value) in from.attrs(id)? {
+            insert.set_attr(&key, &value)?;
+        }
+        let tx: Transaction = to.into();
+        tx.append_request(insert.into())?;
+        tx.execute()?;
+
+        let tx: Transaction = from.into();
+        tx.append_request(DeleteRequest::new(vec![id]).into())?;
+        tx.execute()?;
+        Ok(())
+    }
+
     pub fn empty_bucket(&self, name: &str) -> Result<()> {
         let b = Bucket::open(name, self.clone())?;
         b.empty()?;
@@ -32,28 +710,47 @@ impl Mango {
             .idgen_persist_interval(5000)
             .use_compression(true)
             .open()?;
-        Ok(Self {
-            inner: this,
-            path: ".".into(),
-        })
+        Ok(Self::from_db(this, ".".into()))
+    }
+
+    /// Open a Mango that is never meant to be durable, for tests and caches.
+    ///
+    /// All data is lost when the last handle is dropped.
+    pub fn new_memory() -> Result<Self> {
+        let this = sled::Config::new()
+            .temporary(true)
+            .mode(sled::Mode::HighThroughput)
+            .flush_every_ms(None)
+            .open()?;
+        Ok(Self::from_db(this, ".".into()))
     }
 }
 
+/// Write `field` with its length in front, as backups store every key and value
+fn write_field<W: Write>(w: &mut W, field: &[u8]) -> Result<()> {
+    w.write_all(&(field.len() as u64).to_le_bytes())?;
+    w.write_all(field)?;
+    Ok(())
+}
+
+/// Read a field written by `write_field`, or None at the end of the file
+fn read_field<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 8];
+    match r.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut field = vec![0u8; u64::from_le_bytes(len_buf) as usize];
+    r.read_exact(&mut field)?;
+    Ok(Some(field))
+}
+
 impl TryFrom<PathBuf> for Mango {
     type Error = anyhow::Error;
 
     fn try_from(value: PathBuf) -> std::result::Result<Self, Self::Error> {
-        let this = sled::Config::new()
-            .path(value.clone())
-            .compression_factor(16)
-            .mode(sled::Mode::HighThroughput)
-            .idgen_persist_interval(5000)
-            .use_compression(true)
-            .open()?;
-        Ok(Self {
-            inner: this,
-            path: value,
-        })
+        Mango::open_with_mode(value, sled::Mode::HighThroughput, None)
     }
 }
 
@@ -2,16 +2,54 @@ use std::{
     collections::hash_map::DefaultHasher,
     fmt::Display,
     hash::{Hash, Hasher},
+    io::Read,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 use sled::IVec;
 
 pub type ObjectID = u64;
 
-#[derive(Clone, Debug, Hash, Serialize, Deserialize)]
+/// Bookkeeping stored for an object in the objects meta tree
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObjectMeta {
+    /// Seconds since the unix epoch after which `Bucket::purge_expired` removes the object
+    pub expires_at: Option<u64>,
+}
+
+impl ObjectMeta {
+    /// Metadata for an object expiring `ttl_seconds` from now
+    pub(crate) fn expiring_in(ttl_seconds: u64) -> Self {
+        Self {
+            expires_at: Some(unix_now().saturating_add(ttl_seconds)),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= unix_now())
+    }
+}
+
+/// Seconds since the unix epoch
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Milliseconds since the unix epoch
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Equality and hashing both look only at the payload bytes
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Object {
     inner: Bytes,
 }
@@ -21,10 +59,24 @@ impl Object {
         Self { inner: bs }
     }
 
+    /// Read the payload from `r` straight into the object's buffer
+    pub fn from_reader<R: Read>(mut r: R) -> anyhow::Result<Self> {
+        let mut writer = BytesMut::new().writer();
+        std::io::copy(&mut r, &mut writer)?;
+        Ok(Self {
+            inner: writer.into_inner().freeze(),
+        })
+    }
+
     pub fn get_inner(&self) -> Bytes {
         self.inner.clone()
     }
 
+    /// Whether this object's payload is exactly `other`
+    pub fn content_eq(&self, other: &Bytes) -> bool {
+        self.inner == *other
+    }
+
     pub fn hash_id(&self) -> ObjectID {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
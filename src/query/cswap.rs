@@ -0,0 +1,59 @@
+use crate::object::{Object, ObjectID};
+use anyhow::Result;
+use bytes::Bytes;
+use sled::transaction::UnabortableTransactionError;
+
+use super::error::TransactionError;
+use super::execute::{BucketTx, ExecuteTransaction};
+use super::insert::InsertRequest;
+
+/// Replace an object's payload only if it currently holds the expected payload.
+///
+/// A mismatch aborts the whole transaction with `TransactionError::CompareAndSwapFailed`,
+/// so every other request in it is discarded too. Labels and attributes are left as they
+/// are.
+#[derive(Clone, Debug)]
+pub struct CswapRequest {
+    id: ObjectID,
+
+    /// None when the object must not exist yet
+    expected: Option<Bytes>,
+    new: Object,
+}
+
+impl CswapRequest {
+    pub fn new(id: ObjectID, expected: Option<Bytes>, new: Bytes) -> Result<Self> {
+        Ok(Self {
+            id,
+            expected,
+            new: new.into(),
+        })
+    }
+
+    /// The id whose payload this request replaces
+    pub fn id(&self) -> ObjectID {
+        self.id
+    }
+}
+
+impl ExecuteTransaction for CswapRequest {
+    type Error = UnabortableTransactionError;
+    type Output = ObjectID;
+
+    fn execute(&self, tx: &BucketTx) -> Result<Self::Output, Self::Error> {
+        let id = self.id;
+        let current = InsertRequest::stored_object(tx, id)?.map(|o| o.get_inner());
+        if current != self.expected {
+            tx_log!(
+                tx,
+                trace,
+                "object with id {id} does not hold the expected payload"
+            );
+            return Err(tx.abort(TransactionError::CompareAndSwapFailed(id)));
+        }
+
+        InsertRequest::write_payload(tx, id, &self.new)?;
+        tx_log!(tx, trace, "swapped payload of object with id {id}");
+        Ok(id)
+    }
+}
@@ -1,9 +1,25 @@
-use crate::{label::Label, object::ObjectID, query::execute::*};
+use crate::{
+    label::Label,
+    object::{Object, ObjectID},
+    query::execute::*,
+    query::find::{FindRequest, LabelCache, ObjectTimes},
+    query::insert::InsertRequest,
+};
 use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
 use sled::transaction::UnabortableTransactionError;
 use std::cell::RefCell;
 
-#[derive(Clone, Debug)]
+/// Keys a deleted object is estimated to write for `Transaction::max_writes`, standing in
+/// for the labels it carries
+pub(crate) const WRITES_PER_DELETE: usize = 4;
+
+/// Delete objects and their labels.
+///
+/// Deleting an id that dedup shares between several inserts only releases one reference:
+/// the result reports `false` and the payload, along with the labels merged in from every
+/// reference, stays in place until the last reference is deleted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DeleteRequest {
     /// A List of ObjectIDs to delete
     objects: RefCell<Vec<ObjectID>>,
@@ -12,6 +28,12 @@ pub struct DeleteRequest {
     ///
     /// Default: true
     prune: RefCell<bool>,
+
+    /// Objects this find matches inside the transaction are deleted too
+    ///
+    /// Default: None
+    #[serde(default)]
+    pub(crate) find: RefCell<Option<FindRequest>>,
 }
 
 impl From<Vec<ObjectID>> for DeleteRequest {
@@ -19,6 +41,7 @@ impl From<Vec<ObjectID>> for DeleteRequest {
         Self {
             objects: RefCell::new(ids),
             prune: RefCell::new(true),
+            find: RefCell::new(None),
         }
     }
 }
@@ -28,6 +51,15 @@ impl DeleteRequest {
         ids.into()
     }
 
+    /// Delete every object `find` matches. The find is evaluated in the same transaction
+    /// as the delete, so objects labeled concurrently are either matched and deleted or
+    /// left alone, never half removed. Its ordering and projection are ignored.
+    pub fn from_find(find: FindRequest) -> Self {
+        let this = Self::new(vec![]);
+        *this.find.borrow_mut() = Some(find);
+        this
+    }
+
     pub fn add_id(&self, id: ObjectID) -> Result<usize> {
         let mut ids = self.objects.try_borrow_mut()?;
         ids.push(id);
@@ -42,6 +74,11 @@ impl DeleteRequest {
         Ok(my_ids.len())
     }
 
+    /// The ids this request deletes
+    pub fn ids(&self) -> Result<Vec<ObjectID>> {
+        Ok(self.objects.try_borrow()?.clone())
+    }
+
     pub fn prune(&self, yes: bool) -> Result<bool> {
         let mut prune = self.prune.try_borrow_mut()?;
         *prune = yes;
@@ -49,27 +86,99 @@ impl DeleteRequest {
     }
 }
 
+impl DeleteRequest {
+    /// Remove `id` from the objects described by `label`, pruning the label when no
+    /// objects are left and `prune` is set
+    pub(crate) fn unindex_label(
+        tx: &BucketTx,
+        id: ObjectID,
+        label: &Label,
+        prune: bool,
+    ) -> anyhow::Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::ser_label(label.clone())?;
+
+        // Get the list of objectIDs described by the label
+        match tx.lbl_obj.remove(key_bytes.to_vec())? {
+            Some(bytes) => {
+                let old = tx.de::<Vec<ObjectID>>(&bytes)?;
+                let new = old
+                    .into_iter()
+                    .filter(|i| i != &id)
+                    .collect::<Vec<ObjectID>>();
+
+                // Remove unused labels
+                if new.is_empty() && prune {
+                    let invert_key_bytes = Self::ser_label_invert(label.clone())?;
+                    tx.lbl.remove(key_bytes.to_vec())?;
+                    tx.lbl_invert.remove(invert_key_bytes.to_vec())?;
+                    tx.lbl_keys.remove(label.key_raw())?;
+                    tx_log!(tx, trace, "removed unused label {}", label.to_string_ltr());
+                    return Ok(());
+                }
+
+                // Add back the updated list with this objectID removed
+                let val_bytes = tx.ser(&new)?;
+                tx.lbl_obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                tx_log!(tx, trace, "updated label {}", label.to_string_ltr())
+            }
+            None => {
+                tx_log!(tx, error, "found no label {}", label.to_string_ltr());
+            }
+        }
+        Ok(())
+    }
+}
+
 impl ExecuteTransaction for DeleteRequest {
     type Error = UnabortableTransactionError;
+
+    /// Each object found, and whether it was physically removed. An object whose payload
+    /// is still referenced by other dedup inserts only has a reference released.
     type Output = Vec<(ObjectID, bool)>;
 
-    fn execute(
-        &self,
-        lbl: &sled::transaction::TransactionalTree,
-        lbl_invert: &sled::transaction::TransactionalTree,
-        obj: &sled::transaction::TransactionalTree,
-        obj_lbl: &sled::transaction::TransactionalTree,
-        lbl_obj: &sled::transaction::TransactionalTree,
-    ) -> anyhow::Result<Self::Output, Self::Error> {
+    fn execute(&self, tx: &BucketTx) -> anyhow::Result<Self::Output, Self::Error> {
+        let BucketTx {
+            obj,
+            obj_lbl,
+            content_hashes,
+            content_refcount,
+            obj_attrs,
+            ..
+        } = tx;
         let mut results = vec![];
 
-        let ids = self
+        let mut ids = self
             .objects
             .try_borrow()
             .map_err(|e| {
                 UnabortableTransactionError::Storage(sled::Error::Io(std::io::Error::other(e)))
             })?
             .clone();
+        let find = self.find.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(std::io::Error::other(e)))
+        })?;
+        if let Some(find) = find.as_ref() {
+            let normalize = tx.config()?.normalize_keys;
+            let mut matched: Vec<ObjectID> = find
+                .matching_ids(
+                    &mut LabelCache::of(tx),
+                    &ObjectTimes::of(tx),
+                    normalize,
+                    &tx.written_labels()?,
+                )?
+                .into_iter()
+                .filter(|id| !ids.contains(id))
+                .collect();
+            matched.sort();
+            tx_log!(
+                tx,
+                trace,
+                "find matched {} objects to delete",
+                matched.len()
+            );
+            tx.count_writes(matched.len() * WRITES_PER_DELETE)?;
+            ids.extend(matched);
+        }
 
         let prune = *self.prune.try_borrow().map_err(|e| {
             UnabortableTransactionError::Storage(sled::Error::Io(std::io::Error::other(e)))
@@ -77,45 +186,76 @@ impl ExecuteTransaction for DeleteRequest {
 
         for id in ids {
             let key_bytes = Self::transaction_ser(id)?;
-            // delete the object itself
-            let removed = {
-                match obj.remove(key_bytes.clone().to_vec()) {
-                    Ok(Some(old)) => {
-                        log::trace!("removed object with id {id} size: {}b", old.len());
-                        true
-                    }
-                    Ok(None) => {
-                        log::trace!("failed to remove object with id {id}: object not found");
-                        false
-                    }
-                    Err(e) => {
-                        log::error!("error removing object with id {id}: {e}");
-                        false
-                    }
-                }
+            let Some(stored) = obj.get(&key_bytes)? else {
+                tx_log!(
+                    tx,
+                    trace,
+                    "failed to remove object with id {id}: object not found"
+                );
+                continue;
             };
 
-            if !removed {
-                continue;
+            // Payloads shared through dedup are only removed with their last reference
+            if let Ok(object) = tx.de_payload(&stored).map(Object::new) {
+                let hash_key = Self::transaction_ser(object.hash_id())?;
+                if InsertRequest::content_owner(tx, &hash_key)? == Some(id) {
+                    let refs = InsertRequest::content_refs(tx, &hash_key)?;
+                    if refs > 1 {
+                        let val_bytes = tx.ser(&(refs - 1))?;
+                        content_refcount.insert(hash_key.to_vec(), val_bytes.to_vec())?;
+                        tx_log!(
+                            tx,
+                            trace,
+                            "released a reference to object with id {id}, {} left",
+                            refs - 1
+                        );
+                        results.push((id, false));
+                        continue;
+                    }
+                    content_hashes.remove(hash_key.to_vec())?;
+                    content_refcount.remove(hash_key.to_vec())?;
+                }
             }
 
+            // delete the object itself
+            let old = obj.remove(key_bytes.to_vec())?;
+            tx.invalidate(id)?;
+            tx_log!(
+                tx,
+                trace,
+                "removed object with id {id} size: {}b",
+                old.map_or(0, |o| o.len())
+            );
+
+            obj_attrs.remove(key_bytes.to_vec())?;
+            InsertRequest::remove_chunks(tx, id)?;
+            tx.obj_meta.remove(key_bytes.to_vec())?;
+            tx.created.remove(key_bytes.to_vec())?;
+            tx.updated.remove(key_bytes.to_vec())?;
+
             // if the object was removed, find its labels
             let labels = {
                 match obj_lbl.remove(key_bytes.clone().to_vec()) {
                     Ok(Some(thing)) => {
-                        let this = Self::transaction_de::<Vec<String>>(thing.to_vec().into())?;
-                        log::trace!(
+                        let this = tx.de::<Vec<Label>>(&thing)?;
+                        tx_log!(
+                            tx,
+                            trace,
                             "found list of {} labels for object with id {id}",
                             this.len()
                         );
                         this
                     }
                     Ok(None) => {
-                        log::trace!("found no labels for object with id {id}");
+                        tx_log!(tx, trace, "found no labels for object with id {id}");
                         vec![]
                     }
                     Err(e) => {
-                        log::error!("error getting labels for object with id {id}: {e}");
+                        tx_log!(
+                            tx,
+                            error,
+                            "error getting labels for object with id {id}: {e}"
+                        );
                         return Err(e);
                     }
                 }
@@ -124,42 +264,7 @@ impl ExecuteTransaction for DeleteRequest {
             // Remove the object id from the label
             // Optionally remove the label if it is no longer being used (default: true)
             for label in labels {
-                let label = match Label::try_from(label) {
-                    Ok(this) => this,
-                    Err(e) => {
-                        return Err(UnabortableTransactionError::Storage(
-                            sled::Error::Unsupported(e.to_string()),
-                        ))
-                    }
-                };
-                let key_bytes = Self::ser_label(label.clone())?;
-
-                // Get the list of objectIDs described by the label
-                match lbl_obj.remove(key_bytes.to_vec())? {
-                    Some(bytes) => {
-                        let old = Self::transaction_de::<Vec<ObjectID>>(bytes.to_vec().into())?;
-                        let new = old
-                            .into_iter()
-                            .filter(|i| i != &id)
-                            .collect::<Vec<ObjectID>>();
-
-                        // Remove unused labels
-                        if new.is_empty() && prune {
-                            let _ = lbl.remove(key_bytes.to_vec())?;
-                            let _ = lbl_invert.remove(key_bytes.to_vec())?;
-                            log::trace!("removed unused label {}", label.to_string_ltr());
-                            continue;
-                        }
-
-                        // Add back the updated list with this objectID removed
-                        let val_bytes = Self::transaction_ser(new)?;
-                        lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-                        log::trace!("updated label {}", label.to_string_ltr())
-                    }
-                    None => {
-                        log::error!("found no label {}", label.to_string_ltr());
-                    }
-                }
+                Self::unindex_label(tx, id, &label, prune)?;
             }
 
             results.push((id, true))
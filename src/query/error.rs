@@ -2,11 +2,40 @@ use std::cell::BorrowError;
 
 use thiserror::Error;
 
+use crate::object::ObjectID;
+
 #[derive(Error, Debug)]
 pub enum TransactionError {
     #[error("transaction already executed")]
     AlreadyExecuted,
 
+    #[error("bucket {0} was dropped")]
+    BucketDropped(String),
+
+    #[error("object id {0} already holds a different object")]
+    ObjectCollision(ObjectID),
+
+    #[error("object id {0} does not hold the expected payload")]
+    CompareAndSwapFailed(ObjectID),
+
+    #[error("label key {0} is already set on this object")]
+    DuplicateLabelKey(String),
+
+    #[error("label key of {0} bytes is over the limit of {1}")]
+    LabelKeyTooLong(usize, usize),
+
+    #[error("value of label {0} is {1} bytes, over the limit of {2}")]
+    LabelValueTooLong(String, usize, usize),
+
+    #[error("transaction still conflicted after {0} retries")]
+    RetryBudgetExhausted(usize),
+
+    #[error("object id {0} does not carry the scope label {1}")]
+    OutOfScope(ObjectID, String),
+
+    #[error("transaction would write about {0} keys, over the limit of {1}; split it or use Bucket::insert_many_batched")]
+    TooLarge(usize, usize),
+
     #[error("serialization error: {0}")]
     SerializationError(#[from] flexbuffers::SerializationError),
 
@@ -29,6 +58,15 @@ pub enum TransactionError {
     Anyhow(#[from] anyhow::Error),
 }
 
+/// Why one item of `Bucket::try_insert_many` was not stored
+#[derive(Error, Debug)]
+#[error("insert of object id {id} failed: {error}")]
+pub struct InsertError {
+    /// The id the request would have been stored under
+    pub id: ObjectID,
+    pub error: anyhow::Error,
+}
+
 impl From<TransactionError> for sled::transaction::UnabortableTransactionError {
     fn from(value: TransactionError) -> Self {
         sled::transaction::UnabortableTransactionError::Storage(sled::Error::Unsupported(
@@ -1,12 +1,276 @@
 use bytes::Bytes;
 use flexbuffers::FlexbufferSerializer;
 use serde::{de::DeserializeOwned, Serialize};
-use sled::transaction::TransactionalTree;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
 
+use std::cell::{Cell, RefCell};
+use std::sync::Mutex;
+
+use crate::bucket::{BucketConfig, CONFIG_KEY};
+use crate::cache::{CacheOps, ObjectCache};
+use crate::format::SerFormat;
 use crate::label::Label;
+use crate::object::ObjectID;
 
 use super::error::TransactionError;
 
+/// The transactional view of a Bucket's trees handed to each request.
+pub struct BucketTx<'a> {
+    /// See `Bucket::t_labels`
+    pub lbl: &'a TransactionalTree,
+
+    /// See `Bucket::t_labels_invert`
+    pub lbl_invert: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects`
+    pub obj: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_labels`
+    pub obj_lbl: &'a TransactionalTree,
+
+    /// See `Bucket::t_labels_objects`
+    pub lbl_obj: &'a TransactionalTree,
+
+    /// See `Bucket::t_content_hashes`
+    pub content_hashes: &'a TransactionalTree,
+
+    /// See `Bucket::t_content_refcount`
+    pub content_refcount: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_attrs`
+    pub obj_attrs: &'a TransactionalTree,
+
+    /// See `Bucket::t_meta`
+    pub meta: &'a TransactionalTree,
+
+    /// See `Bucket::t_chunks`
+    pub chunks: &'a TransactionalTree,
+
+    /// See `Bucket::t_object_versions`
+    pub versions: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_meta`
+    pub obj_meta: &'a TransactionalTree,
+
+    /// See `Bucket::t_label_keys`
+    pub lbl_keys: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_created`
+    pub created: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_updated`
+    pub updated: &'a TransactionalTree,
+
+    /// The codec for values in every tree
+    pub(crate) format: SerFormat,
+
+    /// See `Bucket::cache`
+    pub(crate) cache: Option<&'a Mutex<ObjectCache>>,
+
+    /// Cache changes held back until the transaction commits
+    pub(crate) cache_ops: RefCell<CacheOps>,
+
+    /// Why a request aborted the transaction, see `BucketTx::abort`
+    pub(crate) aborted: RefCell<Option<TransactionError>>,
+
+    /// Keys this run is estimated to write so far and the most it may, when the
+    /// transaction has a `max_writes` limit
+    pub(crate) writes: Cell<Option<(usize, usize)>>,
+
+    /// Labels indexed by this run, which finds resolving values beforehand cannot see
+    pub(crate) labels_written: RefCell<Vec<Label>>,
+
+    /// The id of the transaction running, for `tx_log!`
+    pub(crate) correlation_id: Option<u64>,
+}
+
+impl<'a> BucketTx<'a> {
+    /// Name the transactional trees, given in the order of `Bucket::trees`
+    pub(crate) fn from_trees(
+        trees: &'a [TransactionalTree],
+        format: SerFormat,
+        cache: Option<&'a Mutex<ObjectCache>>,
+        writes: Option<(usize, usize)>,
+        correlation_id: Option<u64>,
+    ) -> Option<Self> {
+        match trees {
+            [lbl, lbl_invert, obj, obj_lbl, lbl_obj, content_hashes, content_refcount, obj_attrs, meta, chunks, versions, obj_meta, lbl_keys, created, updated] => {
+                Some(Self {
+                    lbl,
+                    lbl_invert,
+                    obj,
+                    obj_lbl,
+                    lbl_obj,
+                    content_hashes,
+                    content_refcount,
+                    obj_attrs,
+                    meta,
+                    chunks,
+                    versions,
+                    obj_meta,
+                    lbl_keys,
+                    created,
+                    updated,
+                    format,
+                    cache,
+                    cache_ops: RefCell::new(CacheOps::new(
+                        cache.and_then(|c| c.lock().ok().map(|c| c.epoch())),
+                    )),
+                    aborted: RefCell::new(None),
+                    writes: Cell::new(writes),
+                    labels_written: RefCell::new(vec![]),
+                    correlation_id,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl BucketTx<'_> {
+    /// The settings of the bucket this transaction runs against
+    pub(crate) fn config(&self) -> Result<BucketConfig, UnabortableTransactionError> {
+        match self.meta.get(CONFIG_KEY)? {
+            Some(bytes) => BucketConfig::decode(&bytes).map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+            }),
+            None => Ok(BucketConfig::default()),
+        }
+    }
+
+    /// Note that `label` was added to the labels trees by this run
+    pub(crate) fn wrote_label(&self, label: &Label) -> Result<(), UnabortableTransactionError> {
+        let mut written = self.labels_written.try_borrow_mut().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })?;
+        if !written.contains(label) {
+            written.push(label.clone());
+        }
+        Ok(())
+    }
+
+    /// The labels added to the labels trees by this run so far
+    pub(crate) fn written_labels(&self) -> Result<Vec<Label>, UnabortableTransactionError> {
+        self.labels_written
+            .try_borrow()
+            .map(|written| written.clone())
+            .map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+            })
+    }
+
+    /// Count `n` more estimated writes, aborting with `TransactionError::TooLarge` once
+    /// they pass the transaction's limit
+    pub(crate) fn count_writes(&self, n: usize) -> Result<(), UnabortableTransactionError> {
+        if let Some((estimated, limit)) = self.writes.get() {
+            let estimated = estimated + n;
+            self.writes.set(Some((estimated, limit)));
+            if estimated > limit {
+                return Err(self.abort(TransactionError::TooLarge(estimated, limit)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Abort the whole transaction with `error`, which callers of `Transaction::execute`
+    /// can downcast to. Return the result from the request so the transaction stops.
+    pub(crate) fn abort(&self, error: TransactionError) -> UnabortableTransactionError {
+        let message = error.to_string();
+        match self.aborted.try_borrow_mut() {
+            Ok(mut aborted) => *aborted = Some(error),
+            Err(e) => {
+                return UnabortableTransactionError::Storage(sled::Error::Unsupported(
+                    e.to_string(),
+                ))
+            }
+        }
+        UnabortableTransactionError::Storage(sled::Error::Unsupported(message))
+    }
+
+    /// The error a request aborted the transaction with, if any
+    pub(crate) fn take_abort(&self) -> Option<TransactionError> {
+        self.aborted.try_borrow_mut().ok()?.take()
+    }
+
+    /// The cached payload of `id`, unless there is no cache or this transaction wrote
+    /// the object, in which case the cache no longer matches what it sees
+    pub(crate) fn cached(
+        &self,
+        id: ObjectID,
+    ) -> Result<Option<Bytes>, UnabortableTransactionError> {
+        let Some(cache) = self.cache else {
+            return Ok(None);
+        };
+        if self.cache_ops()?.wrote(id) {
+            return Ok(None);
+        }
+        match cache.lock() {
+            Ok(mut cache) => Ok(cache.get(id)),
+            Err(e) => Err(UnabortableTransactionError::Storage(
+                sled::Error::Unsupported(e.to_string()),
+            )),
+        }
+    }
+
+    /// Cache a payload read from sled once the transaction commits
+    pub(crate) fn fill_cache(
+        &self,
+        id: ObjectID,
+        payload: Bytes,
+    ) -> Result<(), UnabortableTransactionError> {
+        if self.cache.is_some() {
+            self.cache_ops()?.fill(id, payload);
+        }
+        Ok(())
+    }
+
+    /// Drop `id` from the cache once the transaction commits
+    pub(crate) fn invalidate(&self, id: ObjectID) -> Result<(), UnabortableTransactionError> {
+        if self.cache.is_some() {
+            self.cache_ops()?.invalidate(id);
+        }
+        Ok(())
+    }
+
+    /// The cache changes made so far, leaving none behind
+    pub(crate) fn take_cache_ops(&self) -> Result<CacheOps, UnabortableTransactionError> {
+        Ok(std::mem::take(&mut *self.cache_ops()?))
+    }
+
+    fn cache_ops(&self) -> Result<std::cell::RefMut<'_, CacheOps>, UnabortableTransactionError> {
+        self.cache_ops.try_borrow_mut().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })
+    }
+
+    /// Encode a value for any of the bucket's trees
+    pub(crate) fn ser<T: Serialize>(&self, item: &T) -> Result<Bytes, UnabortableTransactionError> {
+        match self.format.ser(item) {
+            Ok(bytes) => Ok(bytes.into()),
+            Err(e) => Err(UnabortableTransactionError::Storage(
+                sled::Error::Unsupported(e.to_string()),
+            )),
+        }
+    }
+
+    /// Decode a value read from any of the bucket's trees
+    pub(crate) fn de<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T, UnabortableTransactionError> {
+        self.format.de(bytes).map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })
+    }
+
+    /// Decode an object payload, tolerating payloads stored in another format
+    pub(crate) fn de_payload(&self, bytes: &[u8]) -> Result<Bytes, UnabortableTransactionError> {
+        self.format.de_payload(bytes).map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })
+    }
+}
+
 pub trait ExecuteTransaction {
     type Error: std::error::Error + From<TransactionError>;
     type Output;
@@ -26,19 +290,12 @@ pub trait ExecuteTransaction {
     }
 
     fn ser_label_invert(label: Label) -> Result<Bytes, Self::Error> {
-        Self::transaction_ser(label.to_string_rtl())
+        Ok(label.key_rtl().into())
     }
 
     fn de_label(bytes: Bytes) -> Result<Label, Self::Error> {
         Self::transaction_de(bytes)
     }
 
-    fn execute(
-        &self,
-        lbl: &TransactionalTree,
-        lbl_invert: &TransactionalTree,
-        obj: &TransactionalTree,
-        obj_lbl: &TransactionalTree,
-        lbl_obj: &TransactionalTree,
-    ) -> Result<Self::Output, Self::Error>;
+    fn execute(&self, tx: &BucketTx) -> Result<Self::Output, Self::Error>;
 }
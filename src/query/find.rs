@@ -1,103 +1,847 @@
-use crate::{label::Label, object::ObjectID};
-use anyhow::Result;
+use crate::{
+    bucket::Bucket,
+    format::SerFormat,
+    label::{Label, SEPARATOR},
+    object::ObjectID,
+};
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
 
-use sled::transaction::UnabortableTransactionError;
-use std::{cell::RefCell, collections::HashSet};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree, UnabortableTransactionError},
+    Transactional, Tree,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
 
-use super::execute::ExecuteTransaction;
+use super::error::TransactionError;
+use super::execute::{BucketTx, ExecuteTransaction};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum LabelGroup {
+    /// Objects described by any of the labels
     Include(Vec<Label>),
+
+    /// Objects described by every one of the labels
+    IncludeAll(Vec<Label>),
+
+    /// Remove objects described by any of the labels
     Exclude(Vec<Label>),
+
+    /// Remove objects described by every one of the labels
+    ExcludeAll(Vec<Label>),
+
+    /// Objects with any label holding this value, whatever its key
+    ValueEquals(String),
+
+    /// Objects with a label of this key whose value starts with the prefix
+    ValuePrefix(String, String),
+
+    /// Objects with a label of this key whose value is an integer within the inclusive
+    /// bounds
+    Range(String, i64, i64),
+
+    /// Remove objects with a label of this key whose value matches the glob pattern, where
+    /// `*` matches any run of characters and `?` any one character
+    ExcludeGlob(String, String),
+
+    /// Objects described by at least this many of the labels
+    AtLeast(usize, Vec<Label>),
 }
 
-#[derive(Clone, Debug)]
+impl LabelGroup {
+    /// This group with every label key trimmed and lowercased
+    fn normalized(&self) -> Self {
+        let normalize = |labels: &[Label]| labels.iter().map(Label::normalized).collect();
+        match self {
+            LabelGroup::Include(labels) => LabelGroup::Include(normalize(labels)),
+            LabelGroup::IncludeAll(labels) => LabelGroup::IncludeAll(normalize(labels)),
+            LabelGroup::Exclude(labels) => LabelGroup::Exclude(normalize(labels)),
+            LabelGroup::ExcludeAll(labels) => LabelGroup::ExcludeAll(normalize(labels)),
+            LabelGroup::AtLeast(k, labels) => LabelGroup::AtLeast(*k, normalize(labels)),
+            LabelGroup::ValueEquals(value) => LabelGroup::ValueEquals(value.clone()),
+            LabelGroup::ValuePrefix(key, prefix) => {
+                LabelGroup::ValuePrefix(key.trim().to_lowercase(), prefix.clone())
+            }
+            LabelGroup::Range(key, min, max) => {
+                LabelGroup::Range(key.trim().to_lowercase(), *min, *max)
+            }
+            LabelGroup::ExcludeGlob(key, pattern) => {
+                LabelGroup::ExcludeGlob(key.trim().to_lowercase(), pattern.clone())
+            }
+        }
+    }
+
+    /// Whether `label` is one of the labels this group resolves to before the
+    /// transaction, for labels the transaction writes itself
+    fn resolves_to(&self, label: &Label) -> bool {
+        match self {
+            LabelGroup::ValueEquals(value) => label.1 == *value,
+            LabelGroup::ValuePrefix(key, prefix) => label.0 == *key && label.1.starts_with(prefix),
+            LabelGroup::Range(key, min, max) => {
+                label.0 == *key && label.as_int().is_some_and(|v| (*min..=*max).contains(&v))
+            }
+            LabelGroup::ExcludeGlob(key, pattern) => {
+                label.0 == *key && glob_match(pattern, &label.1)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// One group of a FindRequest as `FindRequest::explain` evaluated it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanStep {
+    /// The group, with keys normalized if the bucket normalizes them
+    pub group: LabelGroup,
+
+    /// How many objects the group's labels describe
+    pub matched: usize,
+
+    /// How many objects the request matches once this group is applied
+    pub running_total: usize,
+}
+
+/// How a FindRequest is evaluated against a bucket, see `FindRequest::explain`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryPlan {
+    /// Every group, in evaluation order
+    pub steps: Vec<PlanStep>,
+
+    /// How many distinct labels were read from sled
+    pub label_reads: usize,
+}
+
+/// The indexes of the include groups that matched each object, see
+/// `FindRequest::attribute` and `FindRequest::match_counts`
+type Attribution = HashMap<ObjectID, Vec<usize>>;
+
+/// The trees holding each object's timestamps, read by `FindRequest::created_between`
+/// and `FindRequest::updated_between`
+pub(crate) struct ObjectTimes<'a> {
+    /// See `Bucket::t_objects_created`
+    pub(crate) created: &'a TransactionalTree,
+
+    /// See `Bucket::t_objects_updated`
+    pub(crate) updated: &'a TransactionalTree,
+}
+
+impl<'a> ObjectTimes<'a> {
+    pub(crate) fn of(tx: &BucketTx<'a>) -> Self {
+        Self {
+            created: tx.created,
+            updated: tx.updated,
+        }
+    }
+}
+
+/// How to sort the results of a FindRequest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderKey {
+    IdAsc,
+    IdDesc,
+
+    /// By the value of the label with this key. Objects without it sort last.
+    LabelValue(String),
+
+    /// By the integer value of the label with this key, smallest first. Objects without
+    /// it, or whose value is not an integer, sort last.
+    NumericAsc(String),
+
+    /// Like `NumericAsc` but largest first. Objects without the label still sort last.
+    NumericDesc(String),
+}
+
+/// The object ids for each label, read from sled at most once per execute
+pub(crate) struct LabelCache<'a> {
+    lbl_obj: &'a TransactionalTree,
+    format: SerFormat,
+    ids: HashMap<Label, HashSet<ObjectID>>,
+
+    /// How many labels were read from sled rather than the cache
+    pub(crate) reads: usize,
+
+    /// The id of the transaction reading, for `tx_log!`
+    pub(crate) correlation_id: Option<u64>,
+}
+
+impl<'a> LabelCache<'a> {
+    pub(crate) fn new(lbl_obj: &'a TransactionalTree, format: SerFormat) -> Self {
+        Self {
+            lbl_obj,
+            format,
+            ids: HashMap::new(),
+            reads: 0,
+            correlation_id: None,
+        }
+    }
+
+    /// A cache over the labels objects tree of `tx`
+    pub(crate) fn of(tx: &BucketTx<'a>) -> Self {
+        Self {
+            correlation_id: tx.correlation_id,
+            ..Self::new(tx.lbl_obj, tx.format)
+        }
+    }
+
+    /// The objects described by `label`
+    pub(crate) fn objects(
+        &mut self,
+        label: &Label,
+    ) -> std::result::Result<&HashSet<ObjectID>, UnabortableTransactionError> {
+        if !self.ids.contains_key(label) {
+            let key_bytes = FindRequest::ser_label(label.clone())?;
+            let ids = match self.lbl_obj.get(&key_bytes)? {
+                Some(bytes) => self
+                    .format
+                    .de::<Vec<ObjectID>>(&bytes)
+                    .map_err(|e| {
+                        UnabortableTransactionError::Storage(sled::Error::Unsupported(
+                            e.to_string(),
+                        ))
+                    })?
+                    .into_iter()
+                    .collect(),
+                None => HashSet::new(),
+            };
+            self.reads += 1;
+            self.ids.insert(label.clone(), ids);
+        }
+        Ok(&self.ids[label])
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FindRequest {
     groups: RefCell<Vec<LabelGroup>>,
+
+    /// Applied to the results after all groups are evaluated
+    ///
+    /// Default: None (unordered)
+    order: RefCell<Option<OrderKey>>,
+
+    /// Removed from the result after every group is applied
+    #[serde(default)]
+    excluded_ids: RefCell<HashSet<ObjectID>>,
+
+    /// Only objects carrying this label match, set by a scoped bucket. See
+    /// `Bucket::with_scope`.
+    #[serde(skip)]
+    scope: RefCell<Option<Label>>,
+
+    /// Only labels with these keys are returned with each object
+    ///
+    /// Default: None (every label)
+    #[serde(default)]
+    projection: RefCell<Option<Vec<String>>>,
+
+    /// Only objects first inserted within this window, in milliseconds since the unix
+    /// epoch, inclusive
+    ///
+    /// Default: None (any time)
+    #[serde(default)]
+    created: RefCell<Option<(u64, u64)>>,
+
+    /// Only objects last changed within this window, like `created`
+    ///
+    /// Default: None (any time)
+    #[serde(default)]
+    updated: RefCell<Option<(u64, u64)>>,
+
+    /// Names of the named groups, by their index in `groups`
+    #[serde(default)]
+    names: RefCell<HashMap<usize, String>>,
+
+    /// The labels matched by each `ValueEquals` and `ValuePrefix` group, resolved before
+    /// the transaction opens
+    #[serde(skip)]
+    values: RefCell<HashMap<LabelGroup, Vec<Label>>>,
 }
 
 impl FindRequest {
     pub fn new() -> Result<Self> {
         Ok(Self {
             groups: RefCell::new(vec![]),
+            order: RefCell::new(None),
+            excluded_ids: RefCell::new(HashSet::new()),
+            scope: RefCell::new(None),
+            projection: RefCell::new(None),
+            created: RefCell::new(None),
+            updated: RefCell::new(None),
+            names: RefCell::new(HashMap::new()),
+            values: RefCell::new(HashMap::new()),
         })
     }
 
+    pub fn order_by(&self, key: OrderKey) -> Result<()> {
+        let mut order = self.order.try_borrow_mut()?;
+        *order = Some(key);
+        Ok(())
+    }
+
     pub fn add_include_group(&self, labels: Vec<Label>) -> Result<()> {
         let mut label_groups = self.groups.try_borrow_mut()?;
         label_groups.push(LabelGroup::Include(labels));
         Ok(())
     }
 
+    /// Like `add_include_group`, but `FindRequest::attribute` reports `name` for every
+    /// object this group matched
+    pub fn add_include_group_named(&self, name: &str, labels: Vec<Label>) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        self.names
+            .try_borrow_mut()?
+            .insert(label_groups.len(), name.to_string());
+        label_groups.push(LabelGroup::Include(labels));
+        Ok(())
+    }
+
+    /// Include objects whose `key` label has any of `values`
+    pub fn any_of(&self, key: &str, values: &[&str]) -> Result<()> {
+        self.add_include_group(values.iter().map(|v| Label::new(key, v)).collect())
+    }
+
+    /// Include objects described by every one of `labels`
+    pub fn all_of(&self, labels: &[Label]) -> Result<()> {
+        self.add_include_all_group(labels.to_vec())
+    }
+
+    /// Include objects described by at least `k` of `labels`, so a `k` of 1 is
+    /// `add_include_group` and a `k` of `labels.len()` is `add_include_all_group`. Repeated
+    /// labels count once. A `k` of 0 is refused.
+    pub fn at_least(&self, k: usize, labels: Vec<Label>) -> Result<()> {
+        if k == 0 {
+            return Err(anyhow!("at_least needs k of at least 1"));
+        }
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::AtLeast(k, labels));
+        Ok(())
+    }
+
+    pub fn add_include_all_group(&self, labels: Vec<Label>) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::IncludeAll(labels));
+        Ok(())
+    }
+
     pub fn add_exclude_group(&self, labels: Vec<Label>) -> Result<()> {
         let mut label_groups = self.groups.try_borrow_mut()?;
         label_groups.push(LabelGroup::Exclude(labels));
         Ok(())
     }
+
+    /// Remove only the objects described by every one of `labels`, keeping those that
+    /// carry just some of them
+    pub fn add_exclude_all_group(&self, labels: Vec<Label>) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::ExcludeAll(labels));
+        Ok(())
+    }
+
+    /// Include objects with any label whose value is `value`, regardless of key
+    pub fn value_equals(&self, value: &str) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::ValueEquals(value.to_string()));
+        Ok(())
+    }
+
+    /// Return only the labels whose key is one of `keys` with each object, instead of all
+    /// of them. Objects lacking a key simply have no label for it. Matching and ordering
+    /// still see every label.
+    pub fn project(&self, keys: Vec<String>) -> Result<()> {
+        *self.projection.try_borrow_mut()? = Some(keys);
+        Ok(())
+    }
+
+    /// Leave `ids` out of the result whatever labels they carry, for example ids already
+    /// processed from an earlier find. Returns how many ids are excluded in total.
+    pub fn exclude_ids(&self, ids: Vec<ObjectID>) -> Result<usize> {
+        let mut excluded_ids = self.excluded_ids.try_borrow_mut()?;
+        excluded_ids.extend(ids);
+        Ok(excluded_ids.len())
+    }
+
+    /// Keep only objects first inserted from `start` to `end` inclusive, in milliseconds
+    /// since the unix epoch. Objects inserted before timestamps were kept never match.
+    pub fn created_between(&self, start: u64, end: u64) -> Result<()> {
+        *self.created.try_borrow_mut()? = Some((start, end));
+        Ok(())
+    }
+
+    /// Keep only objects whose payload or labels last changed from `start` to `end`
+    /// inclusive, in milliseconds since the unix epoch. See `Bucket::updated_at`.
+    pub fn updated_between(&self, start: u64, end: u64) -> Result<()> {
+        *self.updated.try_borrow_mut()? = Some((start, end));
+        Ok(())
+    }
+
+    /// Include objects with a `key` label holding an integer from `min` to `max` inclusive.
+    /// Values that are not integers never match.
+    pub fn add_range_group(&self, key: &str, min: i64, max: i64) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::Range(key.to_string(), min, max));
+        Ok(())
+    }
+
+    /// Remove objects whose `key` label value matches the glob `pattern`, for example
+    /// `temp*`. `*` matches any run of characters and `?` any one character. Objects
+    /// without a `key` label are unaffected.
+    pub fn exclude_glob(&self, key: &str, pattern: &str) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::ExcludeGlob(
+            key.to_string(),
+            pattern.to_string(),
+        ));
+        Ok(())
+    }
+
+    /// Include objects with a `key` label whose value starts with `prefix`
+    pub fn value_prefix(&self, key: &str, prefix: &str) -> Result<()> {
+        let mut label_groups = self.groups.try_borrow_mut()?;
+        label_groups.push(LabelGroup::ValuePrefix(key.to_string(), prefix.to_string()));
+        Ok(())
+    }
 }
 
-impl ExecuteTransaction for FindRequest {
-    type Error = UnabortableTransactionError;
-    type Output = Vec<(ObjectID, Vec<Label>)>;
+impl FindRequest {
+    /// Intersect the objects described by every label.
+    ///
+    /// With `plan` set, labels are evaluated from the smallest object list to the largest
+    /// so the working set shrinks as early as possible. Returns the intersection and the
+    /// number of membership checks performed.
+    pub(crate) fn intersect(
+        cache: &mut LabelCache,
+        labels: &[Label],
+        plan: bool,
+    ) -> std::result::Result<(HashSet<ObjectID>, usize), UnabortableTransactionError> {
+        for label in labels {
+            cache.objects(label)?;
+        }
+        let mut lists: Vec<&HashSet<ObjectID>> =
+            labels.iter().filter_map(|l| cache.ids.get(l)).collect();
+        if plan {
+            lists.sort_by_key(|ids| ids.len());
+        }
 
-    fn execute(
+        let mut checks = 0;
+        let mut lists = lists.into_iter();
+        let mut acc = match lists.next() {
+            Some(ids) => ids.clone(),
+            None => return Ok((HashSet::new(), checks)),
+        };
+        for ids in lists {
+            if acc.is_empty() {
+                break;
+            }
+            checks += acc.len();
+            acc.retain(|id| ids.contains(id));
+        }
+        Ok((acc, checks))
+    }
+
+    /// Evaluate every group in order, reading each distinct label through `cache`.
+    /// `written` are the labels indexed earlier in the same transaction, which the values
+    /// resolved beforehand cannot include.
+    pub(crate) fn matching_ids(
         &self,
-        _lbl: &sled::transaction::TransactionalTree,
-        _ilbl: &sled::transaction::TransactionalTree,
-        _obj: &sled::transaction::TransactionalTree,
-        objlbl: &sled::transaction::TransactionalTree,
-        objilbl: &sled::transaction::TransactionalTree,
-    ) -> std::prelude::v1::Result<Self::Output, Self::Error> {
-        let groups = self
-            .groups
+        cache: &mut LabelCache,
+        times: &ObjectTimes,
+        normalize: bool,
+        written: &[Label],
+    ) -> std::result::Result<HashSet<ObjectID>, UnabortableTransactionError> {
+        let mut values = self
+            .values
             .try_borrow()
             .map_err(|e| {
-                sled::transaction::UnabortableTransactionError::Storage(sled::Error::Unsupported(
-                    e.to_string(),
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+            })?
+            .clone();
+        for (group, labels) in values.iter_mut() {
+            for label in written {
+                if group.resolves_to(label) && !labels.contains(label) {
+                    labels.push(label.clone());
+                }
+            }
+        }
+        Ok(self.evaluate(cache, times, normalize, &values, false)?.0)
+    }
+
+    /// Report each group's object count and the running result size without executing
+    /// the request. Labels are read as they are at the time of the call.
+    ///
+    /// Groups are applied in the order they were added: includes add to the result and
+    /// excludes remove from what has been included so far.
+    pub fn explain(&self, bucket: &Bucket) -> Result<QueryPlan> {
+        Ok(self.evaluate_in(bucket, false)?.1)
+    }
+
+    /// The names of the named groups that matched each object in the result, in the
+    /// order the groups were added. Objects matched only by unnamed groups map to an
+    /// empty list. Labels are read as they are at the time of the call.
+    pub fn attribute(&self, bucket: &Bucket) -> Result<HashMap<ObjectID, Vec<String>>> {
+        let names = self.names.try_borrow()?;
+        Ok(self
+            .evaluate_in(bucket, true)?
+            .2
+            .into_iter()
+            .map(|(id, groups)| {
+                let matched = groups.iter().filter_map(|n| names.get(n).cloned());
+                (id, matched.collect())
+            })
+            .collect())
+    }
+
+    /// Each object in the result once, with the number of include groups that contained
+    /// it, best matched first. Ties are broken by id. Labels are read as they are at the
+    /// time of the call.
+    pub fn match_counts(&self, bucket: &Bucket) -> Result<Vec<(ObjectID, usize)>> {
+        let mut counts: Vec<(ObjectID, usize)> = self
+            .evaluate_in(bucket, true)?
+            .2
+            .into_iter()
+            .map(|(id, groups)| (id, groups.len()))
+            .collect();
+        counts.sort_by_key(|(id, count)| (std::cmp::Reverse(*count), *id));
+        Ok(counts)
+    }
+
+    /// The ids this request matches in `bucket`, in the requested id order, without
+    /// reading any object's labels. See `Bucket::query_ids_stream`.
+    pub(crate) fn ids_in(&self, bucket: &Bucket) -> Result<Vec<ObjectID>> {
+        if !bucket.check()? {
+            return Err(TransactionError::BucketDropped(bucket.name.clone()).into());
+        }
+        let mut ids: Vec<ObjectID> = self.evaluate_in(bucket, false)?.0.into_iter().collect();
+        match &*self.order.try_borrow()? {
+            Some(OrderKey::IdAsc) => ids.sort(),
+            Some(OrderKey::IdDesc) => ids.sort_by_key(|id| std::cmp::Reverse(*id)),
+            Some(OrderKey::LabelValue(_) | OrderKey::NumericAsc(_) | OrderKey::NumericDesc(_)) => {
+                return Err(anyhow!(
+                    "ordering by label value needs each object's labels, use execute"
                 ))
+            }
+            None => (),
+        }
+        Ok(ids)
+    }
+
+    /// Evaluate every group against `bucket` in a read-only transaction over its labels
+    /// objects tree, leaving this request untouched
+    fn evaluate_in(
+        &self,
+        bucket: &Bucket,
+        attribute: bool,
+    ) -> Result<(HashSet<ObjectID>, QueryPlan, Attribution)> {
+        let normalize = bucket.config()?.normalize_keys;
+        let values = self.resolved_values(bucket)?;
+        let trees = (
+            &bucket.t_labels_objects,
+            &bucket.t_objects_created,
+            &bucket.t_objects_updated,
+        );
+        trees
+            .transaction(|(lbl_obj, created, updated)| {
+                let mut cache = LabelCache::new(lbl_obj, bucket.format);
+                let times = ObjectTimes { created, updated };
+                let (ids, steps, attribution) =
+                    self.evaluate(&mut cache, &times, normalize, &values, attribute)?;
+                let plan = QueryPlan {
+                    steps,
+                    label_reads: cache.reads,
+                };
+                Ok::<_, ConflictableTransactionError<String>>((ids, plan, attribution))
+            })
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Apply every group in order, returning the matching ids and a step per group.
+    /// With `attribute` set, also the include groups that matched each id.
+    fn evaluate(
+        &self,
+        cache: &mut LabelCache,
+        times: &ObjectTimes,
+        normalize: bool,
+        values: &HashMap<LabelGroup, Vec<Label>>,
+        attribute: bool,
+    ) -> std::result::Result<
+        (HashSet<ObjectID>, Vec<PlanStep>, Attribution),
+        UnabortableTransactionError,
+    > {
+        let mut groups = self
+            .groups
+            .try_borrow()
+            .map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
             })?
             .clone();
+        if normalize {
+            groups = groups.iter().map(LabelGroup::normalized).collect();
+        }
+
+        let mut acc = HashSet::new();
+        let mut steps = vec![];
+        let mut attribution = Attribution::new();
+        for (n, group) in groups.into_iter().enumerate() {
+            let (objects, include) = match &group {
+                LabelGroup::IncludeAll(labels) => (Self::intersect(cache, labels, true)?.0, true),
+                LabelGroup::Include(labels) => (Self::union(cache, labels), true),
+                LabelGroup::Exclude(labels) => (Self::union(cache, labels), false),
+                LabelGroup::ExcludeAll(labels) => (Self::intersect(cache, labels, true)?.0, false),
+                LabelGroup::AtLeast(k, labels) => (Self::at_least_k(cache, *k, labels)?, true),
+                LabelGroup::ValueEquals(_)
+                | LabelGroup::ValuePrefix(..)
+                | LabelGroup::Range(..) => {
+                    let labels = values.get(&group).map(Vec::as_slice).unwrap_or_default();
+                    (Self::union(cache, labels), true)
+                }
+                LabelGroup::ExcludeGlob(..) => {
+                    let labels = values.get(&group).map(Vec::as_slice).unwrap_or_default();
+                    (Self::union(cache, labels), false)
+                }
+            };
+
+            if attribute && include {
+                for id in objects.iter() {
+                    attribution.entry(*id).or_default().push(n);
+                }
+            }
+            if include {
+                acc.extend(objects.iter())
+            } else {
+                acc.retain(|id| !objects.contains(id))
+            }
+            steps.push(PlanStep {
+                group,
+                matched: objects.len(),
+                running_total: acc.len(),
+            });
+        }
 
-        let mut group_results = vec![];
-        for group in groups {
-            let (labels, include) = match group.clone() {
-                LabelGroup::Include(labels) => (labels, true),
-                LabelGroup::Exclude(labels) => (labels, false),
+        let excluded_ids = self.excluded_ids.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })?;
+        acc.retain(|id| !excluded_ids.contains(id));
+        let scope = self.scope.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })?;
+        if let Some(scope) = scope.as_ref() {
+            let scope = match normalize {
+                true => scope.normalized(),
+                false => scope.clone(),
             };
+            let scoped = cache.objects(&scope)?;
+            acc.retain(|id| scoped.contains(id));
+        }
+        for (window, tree) in [
+            (&self.created, times.created),
+            (&self.updated, times.updated),
+        ] {
+            let window = *window.try_borrow().map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+            })?;
+            if let Some((start, end)) = window {
+                acc = Self::stamped_within(tree, cache.format, acc, start, end)?;
+            }
+        }
+        attribution.retain(|id, _| acc.contains(id));
+        Ok((acc, steps, attribution))
+    }
 
-            let mut objects: HashSet<ObjectID> = HashSet::new();
-            for label in labels {
-                let key_bytes = Self::ser_label(label.clone())?;
-                match objilbl.get(&key_bytes) {
-                    Ok(Some(bytes)) => {
-                        let ids: Vec<ObjectID> = Self::transaction_de(bytes.to_vec().into())?;
-                        objects.extend(ids);
-                    }
-                    Ok(None) => (),
-                    Err(e) => {
-                        log::error!(
-                            "Error in Find request for label {}: {e}",
-                            label.to_string_ltr()
-                        );
-                    }
+    /// The ids in `ids` whose timestamp in `tree` is from `start` to `end` inclusive.
+    /// Ids without one are dropped.
+    fn stamped_within(
+        tree: &TransactionalTree,
+        format: SerFormat,
+        ids: HashSet<ObjectID>,
+        start: u64,
+        end: u64,
+    ) -> std::result::Result<HashSet<ObjectID>, UnabortableTransactionError> {
+        let mut within = HashSet::new();
+        for id in ids {
+            if let Some(bytes) = tree.get(Self::transaction_ser(id)?)? {
+                let at: u64 = format.de(&bytes).map_err(|e| {
+                    UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+                })?;
+                if (start..=end).contains(&at) {
+                    within.insert(id);
                 }
             }
-            group_results.push((group, objects, include));
         }
+        Ok(within)
+    }
 
-        let objects = group_results
+    /// The objects described by any of `labels`. Labels that fail to read are logged
+    /// and skipped.
+    fn union(cache: &mut LabelCache, labels: &[Label]) -> HashSet<ObjectID> {
+        let mut objects: HashSet<ObjectID> = HashSet::new();
+        for label in labels {
+            match cache.objects(label) {
+                Ok(ids) => objects.extend(ids),
+                Err(e) => {
+                    tx_log!(
+                        cache,
+                        error,
+                        "Error in Find request for label {}: {e}",
+                        label.to_string_ltr()
+                    );
+                }
+            }
+        }
+        objects
+    }
+
+    /// The objects described by at least `k` of the distinct `labels`
+    fn at_least_k(
+        cache: &mut LabelCache,
+        k: usize,
+        labels: &[Label],
+    ) -> std::result::Result<HashSet<ObjectID>, UnabortableTransactionError> {
+        let labels: HashSet<&Label> = labels.iter().collect();
+        let mut counts: HashMap<ObjectID, usize> = HashMap::new();
+        for label in labels {
+            for id in cache.objects(label)? {
+                *counts.entry(*id).or_default() += 1;
+            }
+        }
+        Ok(counts
             .into_iter()
-            .fold(HashSet::new(), |mut acc, item| {
-                let (_group, objects, include) = item;
-                let objects: HashSet<ObjectID> = HashSet::from_iter(objects);
-                if include {
-                    acc.extend(objects)
-                } else {
-                    acc.retain(|&id| !objects.contains(&id))
+            .filter(|(_, n)| *n >= k)
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    /// Restrict matches to objects carrying `scope`
+    pub(crate) fn set_scope(&self, scope: Option<Label>) -> Result<()> {
+        *self.scope.try_borrow_mut()? = scope;
+        Ok(())
+    }
+
+    /// Look up the labels for every `ValueEquals`, `ValuePrefix`, `Range` and
+    /// `ExcludeGlob` group
+    ///
+    /// sled trees cannot be iterated inside a transaction, so this runs beforehand and
+    /// is not isolated from concurrent writers. Labels written earlier in the same
+    /// transaction are added inside it, see `matching_ids`.
+    pub(crate) fn resolve_values(&self, bucket: &Bucket) -> Result<()> {
+        let values = self.resolved_values(bucket)?;
+        *self.values.try_borrow_mut()? = values;
+        Ok(())
+    }
+
+    /// The labels matched by each group that needs a scan, keyed by the group as
+    /// `evaluate` sees it
+    fn resolved_values(&self, bucket: &Bucket) -> Result<HashMap<LabelGroup, Vec<Label>>> {
+        let normalize = bucket.config()?.normalize_keys;
+        let groups = self.groups.try_borrow()?;
+        let mut values = HashMap::new();
+        for group in groups.iter() {
+            let group = match normalize {
+                true => group.normalized(),
+                false => group.clone(),
+            };
+            let labels = match &group {
+                LabelGroup::ValueEquals(value) => {
+                    Self::labels_with_value(&bucket.t_labels_invert, value, bucket.format)?
                 }
-                acc
-            });
+                LabelGroup::ValuePrefix(key, prefix) => {
+                    Self::labels_with_prefix(&bucket.t_label_keys, key, prefix, bucket.format)?
+                }
+                LabelGroup::Range(key, min, max) => {
+                    Self::labels_with_prefix(&bucket.t_label_keys, key, "", bucket.format)?
+                        .into_iter()
+                        .filter(|l| l.as_int().is_some_and(|v| (*min..=*max).contains(&v)))
+                        .collect()
+                }
+                LabelGroup::ExcludeGlob(key, pattern) => {
+                    // Only values sharing the pattern's literal start can match
+                    let prefix = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+                    Self::labels_with_prefix(&bucket.t_label_keys, key, prefix, bucket.format)?
+                        .into_iter()
+                        .filter(|l| glob_match(pattern, &l.1))
+                        .collect()
+                }
+                _ => continue,
+            };
+            values.insert(group, labels);
+        }
+        Ok(values)
+    }
+
+    /// Every `key` label whose value starts with `prefix`, scanning only that key's
+    /// values in the label keys tree
+    pub(crate) fn labels_with_prefix(
+        lbl_keys: &Tree,
+        key: &str,
+        prefix: &str,
+        format: SerFormat,
+    ) -> Result<Vec<Label>> {
+        let mut labels = vec![];
+        for kv in lbl_keys.scan_prefix(format!("{key}{SEPARATOR}{prefix}")) {
+            let (_, v) = kv?;
+            labels.push(format.de(&v)?);
+        }
+        Ok(labels)
+    }
+
+    /// Every label holding `value`, scanning only that value's keys in the labels invert
+    /// tree
+    fn labels_with_value(lbl_invert: &Tree, value: &str, format: SerFormat) -> Result<Vec<Label>> {
+        let mut labels = vec![];
+        for kv in lbl_invert.scan_prefix(format!("{value}{SEPARATOR}")) {
+            let (_, v) = kv?;
+            labels.push(format.de(&v)?);
+        }
+        Ok(labels)
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters and `?`
+/// any one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+
+    // Where the last `*` was, and how much text it has consumed so far
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl ExecuteTransaction for FindRequest {
+    type Error = UnabortableTransactionError;
+    type Output = Vec<(ObjectID, Vec<Label>)>;
+
+    fn execute(&self, tx: &BucketTx) -> std::prelude::v1::Result<Self::Output, Self::Error> {
+        let objlbl = tx.obj_lbl;
+        let normalize = tx.config()?.normalize_keys;
+        let objects = self.matching_ids(
+            &mut LabelCache::of(tx),
+            &ObjectTimes::of(tx),
+            normalize,
+            &tx.written_labels()?,
+        )?;
 
         let mut results = vec![];
         for id in objects {
@@ -105,11 +849,66 @@ impl ExecuteTransaction for FindRequest {
             let key_bytes = Self::transaction_ser(id)?;
             match objlbl.get(&key_bytes) {
                 Ok(Some(bytes)) => {
-                    let labels: Vec<Label> = Self::transaction_de(bytes.to_vec().into())?;
+                    let labels: Vec<Label> = tx.de(&bytes)?;
                     results.push((id, labels));
                 }
                 Ok(None) => results.push((id, vec![])),
-                Err(e) => log::error!("Error in find request for object id {id}: {e}"),
+                Err(e) => tx_log!(tx, error, "Error in find request for object id {id}: {e}"),
+            }
+        }
+
+        let mut order = self
+            .order
+            .try_borrow()
+            .map_err(|e| {
+                sled::transaction::UnabortableTransactionError::Storage(sled::Error::Unsupported(
+                    e.to_string(),
+                ))
+            })?
+            .clone();
+        if let (
+            true,
+            Some(
+                OrderKey::LabelValue(key) | OrderKey::NumericAsc(key) | OrderKey::NumericDesc(key),
+            ),
+        ) = (normalize, order.as_mut())
+        {
+            *key = key.trim().to_lowercase();
+        }
+        let numeric = |labels: &[Label], key: &str| {
+            labels.iter().find(|l| l.0 == key).and_then(Label::as_int)
+        };
+        match order {
+            Some(OrderKey::IdAsc) => results.sort_by_key(|(id, _)| *id),
+            Some(OrderKey::IdDesc) => results.sort_by_key(|(id, _)| std::cmp::Reverse(*id)),
+            Some(OrderKey::LabelValue(key)) => results.sort_by_cached_key(|(id, labels)| {
+                let value = labels.iter().find(|l| l.0 == key).map(|l| l.1.clone());
+                (value.is_none(), value, *id)
+            }),
+            Some(OrderKey::NumericAsc(key)) => results.sort_by_cached_key(|(id, labels)| {
+                let value = numeric(labels, &key);
+                (value.is_none(), value, *id)
+            }),
+            Some(OrderKey::NumericDesc(key)) => results.sort_by_cached_key(|(id, labels)| {
+                let value = numeric(labels, &key);
+                (value.is_none(), value.map(std::cmp::Reverse), *id)
+            }),
+            None => (),
+        }
+
+        let projection = self.projection.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })?;
+        if let Some(keys) = projection.as_ref() {
+            let keys: HashSet<String> = keys
+                .iter()
+                .map(|k| match normalize {
+                    true => k.trim().to_lowercase(),
+                    false => k.clone(),
+                })
+                .collect();
+            for (_, labels) in results.iter_mut() {
+                labels.retain(|l| keys.contains(l.key()));
             }
         }
 
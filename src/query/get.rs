@@ -1,23 +1,40 @@
-use crate::object::{Object, ObjectID};
+use crate::object::ObjectID;
 use anyhow::Result;
 use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
 use sled::transaction::UnabortableTransactionError;
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::HashMap};
 
-use super::execute::ExecuteTransaction;
+use super::execute::{BucketTx, ExecuteTransaction};
+use super::insert::InsertRequest;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetRequest {
     ids: RefCell<Vec<ObjectID>>,
+
+    /// The byte offset and length to read of each id added with `with_range`
+    #[serde(default)]
+    ranges: RefCell<HashMap<ObjectID, (usize, usize)>>,
 }
 
 impl GetRequest {
     pub fn new(ids: Vec<ObjectID>) -> Result<Self> {
         Ok(Self {
             ids: RefCell::new(ids),
+            ranges: RefCell::new(HashMap::new()),
         })
     }
 
+    /// Read only `len` bytes of `id` from byte `offset`, for serving ranged downloads.
+    /// The slice is cut short at the end of the payload, and is empty past it.
+    ///
+    /// Chunked payloads (see `InsertRequest::chunked`) only read the chunks the range
+    /// overlaps. Others are read whole and sliced. Returns the number of ids to read.
+    pub fn with_range(&self, id: ObjectID, offset: usize, len: usize) -> Result<usize> {
+        self.ranges.try_borrow_mut()?.insert(id, (offset, len));
+        self.add_id(id)
+    }
+
     pub fn add_id(&self, id: ObjectID) -> Result<usize> {
         let mut ids = self.ids.try_borrow_mut()?;
         ids.push(id);
@@ -26,6 +43,11 @@ impl GetRequest {
         Ok(ids.len())
     }
 
+    /// The ids this request reads
+    pub fn ids(&self) -> Result<Vec<ObjectID>> {
+        Ok(self.ids.try_borrow()?.clone())
+    }
+
     pub fn set_ids(&self, ids: Vec<ObjectID>) -> Result<usize> {
         let mut my_ids = self.ids.try_borrow_mut()?;
         *my_ids = ids;
@@ -35,35 +57,46 @@ impl GetRequest {
     }
 }
 
+/// Up to `len` bytes of `payload` from `offset`, empty past its end
+pub(crate) fn byte_range(payload: &Bytes, offset: usize, len: usize) -> Bytes {
+    let start = offset.min(payload.len());
+    payload.slice(start..start.saturating_add(len).min(payload.len()))
+}
+
 impl ExecuteTransaction for GetRequest {
     type Error = UnabortableTransactionError;
     type Output = Vec<(ObjectID, Bytes)>;
 
-    fn execute(
-        &self,
-        _lbl: &sled::transaction::TransactionalTree,
-        _ilbl: &sled::transaction::TransactionalTree,
-        obj: &sled::transaction::TransactionalTree,
-        _objlbl: &sled::transaction::TransactionalTree,
-        _objilbl: &sled::transaction::TransactionalTree,
-    ) -> std::prelude::v1::Result<Self::Output, Self::Error> {
+    fn execute(&self, tx: &BucketTx) -> std::prelude::v1::Result<Self::Output, Self::Error> {
         let ids = self.ids.take();
+        let ranges = self.ranges.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+        })?;
 
         let mut results = vec![];
         for id in ids {
-            let key_bytes = Self::transaction_ser(id)?;
-            match obj.get(&key_bytes) {
-                Ok(Some(bytes)) => {
-                    let obj = Object::try_from(bytes).map_err(|e| {
-                        UnabortableTransactionError::Storage(sled::Error::Unsupported(
-                            e.to_string(),
-                        ))
-                    })?;
+            if let Some(&(offset, len)) = ranges.get(&id) {
+                let payload = match tx.cached(id)? {
+                    Some(payload) => byte_range(&payload, offset, len),
+                    None => InsertRequest::stored_range(tx, id, offset, len)?.unwrap_or_default(),
+                };
+                results.push((id, payload));
+                continue;
+            }
+
+            if let Some(payload) = tx.cached(id)? {
+                results.push((id, payload));
+                continue;
+            }
+
+            match InsertRequest::stored_object(tx, id) {
+                Ok(Some(obj)) => {
+                    tx.fill_cache(id, obj.get_inner())?;
                     results.push((id, obj.get_inner()))
                 }
                 Ok(None) => results.push((id, Bytes::new())),
                 Err(e) => {
-                    log::error!("error getting object with id {id}: {e}");
+                    tx_log!(tx, error, "error getting object with id {id}: {e}");
                     return Err(e);
                 }
             }
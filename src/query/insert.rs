@@ -1,115 +1,318 @@
+use crate::chunk::ChunkManifest;
 use crate::mango::Mango;
 use crate::query::execute::*;
 use crate::{
     label::Label,
-    object::{Object, ObjectID},
+    object::{unix_millis, Object, ObjectID, ObjectMeta},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use sled::transaction::{TransactionalTree, UnabortableTransactionError};
-use std::{cell::RefCell, io};
+use sled::transaction::UnabortableTransactionError;
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+};
+
+use super::delete::DeleteRequest;
+use super::error::TransactionError;
+use super::get::byte_range;
+
+/// How many alternative ids `CollisionPolicy::Rehash` tries before giving up
+const MAX_REHASH_ATTEMPTS: u64 = 16;
+
+/// What to do when a hash-derived id already holds a different payload
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Replace the stored object
+    #[default]
+    Overwrite,
+
+    /// Abort the transaction with `TransactionError::ObjectCollision`
+    Reject,
+
+    /// Derive a new id from the colliding one and try again
+    Rehash,
+}
 
 #[derive(Clone, Debug)]
 pub struct InsertRequest {
     pub(crate) object: Object,
     pub(crate) id: RefCell<ObjectID>,
     pub(crate) labels: RefCell<Vec<Label>>,
+
+    /// Whether the id was derived from the payload hash rather than set explicitly
+    pub(crate) hashed: RefCell<bool>,
+
+    /// Applied when a hash-derived id collides with a different payload
+    ///
+    /// Default: Overwrite
+    pub(crate) collision: RefCell<CollisionPolicy>,
+
+    /// Reuse an existing object with an identical payload
+    ///
+    /// Default: false
+    pub(crate) dedupe: RefCell<bool>,
+
+    /// Descriptive attributes stored alongside the object, not indexed
+    pub(crate) attrs: RefCell<HashMap<String, String>>,
+
+    /// Store the labels with the object without indexing them
+    ///
+    /// Default: false
+    pub(crate) skip_index: RefCell<bool>,
+
+    /// Reject a label whose key is already on this request
+    ///
+    /// Default: false
+    pub(crate) unique_keys: RefCell<bool>,
+
+    /// Seconds until `Bucket::purge_expired` may remove the object
+    ///
+    /// Default: None (never expires)
+    pub(crate) ttl: RefCell<Option<u64>>,
+
+    /// Keep this many of the payloads replaced by overwrites
+    ///
+    /// Default: 0 (no history)
+    pub(crate) keep_versions: RefCell<usize>,
+
+    /// Store the payload in pieces of at most this many bytes
+    ///
+    /// Default: None (one value)
+    pub(crate) chunk_size: RefCell<Option<usize>>,
+
+    /// Flush sled before the transaction reports success
+    ///
+    /// Default: false
+    pub(crate) durable: RefCell<bool>,
+
+    /// Only a duplicate carrying this label is reused, set by a scoped bucket. See
+    /// `Bucket::with_scope`.
+    pub(crate) scope: RefCell<Option<Label>>,
 }
 
 impl InsertRequest {
+    /// Create an insert whose id is the hash of the payload
     pub fn new(object: Bytes) -> Result<Self> {
         Ok(object.into())
     }
 
+    /// Create an insert whose payload is read from `r`, with the payload hash as its id
+    pub fn from_reader<R: io::Read>(r: R) -> Result<Self> {
+        Ok(Object::from_reader(r)?.into())
+    }
+
     pub fn new_static_id(id: ObjectID, object: Bytes) -> Result<Self> {
         let this: Self = object.into();
         this.set_id(id)?;
         Ok(this)
     }
 
+    /// Create an insert with the next id from `Mango::reserve_ids`.
+    ///
+    /// Every call reserves a single id, which is a write to the id counter in sled's
+    /// default tree. Bulk loaders should reserve a range once with `reserve_ids(n)` and
+    /// use `new_static_id` for each object instead.
     pub fn new_monotonic_id(mango: &Mango, object: Bytes) -> Result<Self> {
-        let id = mango.inner.generate_id()?;
+        let id = mango.reserve_ids(1)?.start;
         Self::new_static_id(id, object)
     }
 
     pub fn add_label(&self, label: Label) -> Result<usize> {
         let mut labels = self.labels.try_borrow_mut()?;
+        self.check_unique_keys(&labels, std::slice::from_ref(&label))?;
         labels.push(label);
         Ok(labels.len())
     }
 
     pub fn add_labels(&self, labels: Vec<Label>) -> Result<usize> {
         let mut my_labels = self.labels.try_borrow_mut()?;
+        self.check_unique_keys(&my_labels, &labels)?;
         my_labels.extend(labels);
         my_labels.sort();
         my_labels.dedup();
         Ok(my_labels.len())
     }
 
+    /// When enabled, inserting a payload that is already stored returns the existing
+    /// object's id and merges this request's labels onto it.
+    pub fn dedupe_by_content(&self, yes: bool) -> Result<bool> {
+        let mut dedupe = self.dedupe.try_borrow_mut()?;
+        *dedupe = yes;
+        Ok(*dedupe)
+    }
+
+    /// When enabled, `add_label` and `add_labels` fail with
+    /// `TransactionError::DuplicateLabelKey` for a label whose key the request already
+    /// has with a different value. Fails the same way if the labels added so far conflict.
+    pub fn unique_keys(&self, yes: bool) -> Result<bool> {
+        if yes {
+            Self::check_keys(&[], &self.labels.try_borrow()?)?;
+        }
+        let mut unique_keys = self.unique_keys.try_borrow_mut()?;
+        *unique_keys = yes;
+        Ok(*unique_keys)
+    }
+
+    fn check_unique_keys(&self, existing: &[Label], added: &[Label]) -> Result<()> {
+        match *self.unique_keys.try_borrow()? {
+            true => Self::check_keys(existing, added),
+            false => Ok(()),
+        }
+    }
+
+    /// Error on the first label in `added` whose key is already used with another value
+    fn check_keys(existing: &[Label], added: &[Label]) -> Result<()> {
+        let mut seen: HashMap<&str, &Label> = existing.iter().map(|l| (l.key(), l)).collect();
+        for label in added {
+            match seen.insert(label.key(), label) {
+                Some(prev) if prev != label => {
+                    return Err(TransactionError::DuplicateLabelKey(label.key().to_string()).into())
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// When enabled, the labels are stored with the object but not written to the label
+    /// indexes, saving three tree writes per label.
+    ///
+    /// Such objects can be read by id and their labels listed, but no FindRequest will
+    /// return them. Re-inserting an indexed object this way removes it from the indexes.
+    pub fn skip_label_index(&self, yes: bool) -> Result<bool> {
+        let mut skip_index = self.skip_index.try_borrow_mut()?;
+        *skip_index = yes;
+        Ok(*skip_index)
+    }
+
+    /// Store the payload in the chunks tree in pieces of at most `chunk_size` bytes,
+    /// instead of as one value. Reads reassemble it.
+    ///
+    /// Chunked payloads are never deduplicated, and `dedupe_by_content` is ignored.
+    pub fn chunked(&self, chunk_size: usize) -> Result<usize> {
+        if chunk_size == 0 {
+            return Err(anyhow!("chunk size must be at least one byte"));
+        }
+        *self.chunk_size.try_borrow_mut()? = Some(chunk_size);
+        Ok(chunk_size)
+    }
+
+    /// Expire the object `seconds` from now. It stays readable until
+    /// `Bucket::purge_expired` removes it, and `Bucket::touch` can extend it.
+    ///
+    /// Overwriting an object without a TTL makes it permanent again. A deduplicated insert
+    /// leaves the existing object's expiry alone.
+    pub fn ttl(&self, seconds: u64) -> Result<u64> {
+        *self.ttl.try_borrow_mut()? = Some(seconds);
+        Ok(seconds)
+    }
+
+    /// When this insert replaces a different payload, keep the old one as the object's
+    /// next version and drop all but the `n` most recent. See `Bucket::get_version`.
+    pub fn keep_versions(&self, n: usize) -> Result<usize> {
+        let mut keep_versions = self.keep_versions.try_borrow_mut()?;
+        *keep_versions = n;
+        Ok(*keep_versions)
+    }
+
+    /// When enabled, `Transaction::execute` flushes sled to disk after committing and
+    /// only returns `Ok` once the flush succeeds, so the insert survives a crash.
+    ///
+    /// A flush writes out everything sled has buffered, not just this insert, and
+    /// typically costs milliseconds, so enable it only for writes that must not be lost.
+    pub fn durable(&self, yes: bool) -> Result<bool> {
+        let mut durable = self.durable.try_borrow_mut()?;
+        *durable = yes;
+        Ok(*durable)
+    }
+
+    /// Set a descriptive attribute on the object.
+    ///
+    /// Unlike Labels, attributes are not searchable. Returns the previous value.
+    pub fn set_attr(&self, key: &str, value: &str) -> Result<Option<String>> {
+        let mut attrs = self.attrs.try_borrow_mut()?;
+        Ok(attrs.insert(key.to_string(), value.to_string()))
+    }
+
+    /// Choose what happens when this request's hash-derived id already holds a different
+    /// payload. Has no effect on requests with an explicit id.
+    pub fn collision_policy(&self, policy: CollisionPolicy) -> Result<CollisionPolicy> {
+        let mut collision = self.collision.try_borrow_mut()?;
+        *collision = policy;
+        Ok(*collision)
+    }
+
     pub fn set_id(&self, new: ObjectID) -> Result<ObjectID> {
         let mut id = self.id.try_borrow_mut()?;
         let old = *id;
         *id = new;
+        *self.hashed.try_borrow_mut()? = false;
         Ok(old)
     }
 }
 
 impl From<Bytes> for InsertRequest {
     fn from(value: Bytes) -> Self {
+        Object::from(value).into()
+    }
+}
+
+impl From<Object> for InsertRequest {
+    fn from(object: Object) -> Self {
         Self {
-            object: value.into(),
-            id: RefCell::new(0),
+            id: RefCell::new(object.hash_id()),
+            object,
             labels: RefCell::new(vec![]),
+            hashed: RefCell::new(true),
+            collision: RefCell::new(CollisionPolicy::default()),
+            dedupe: RefCell::new(false),
+            skip_index: RefCell::new(false),
+            unique_keys: RefCell::new(false),
+            keep_versions: RefCell::new(0),
+            ttl: RefCell::new(None),
+            chunk_size: RefCell::new(None),
+            durable: RefCell::new(false),
+            attrs: RefCell::new(HashMap::new()),
+            scope: RefCell::new(None),
         }
     }
 }
 
-impl ExecuteTransaction for InsertRequest {
-    type Error = UnabortableTransactionError;
-    type Output = ObjectID;
-
-    fn execute(
-        &self,
-        lbl: &TransactionalTree,
-        lbl_invert: &TransactionalTree,
-        obj: &TransactionalTree,
-        obj_lbl: &TransactionalTree,
-        lbl_obj: &TransactionalTree,
-    ) -> Result<Self::Output, Self::Error> {
-        let object_id = *self.id.try_borrow().map_err(|e| {
-            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
-        })?;
-        let labels = self
-            .labels
-            .try_borrow()
-            .map_err(|e| {
-                UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
-            })?
-            .clone();
-
-        // Insert the object
-        {
-            let key_bytes = Self::transaction_ser(object_id)?;
-            let val_bytes = Self::transaction_ser(self.object.get_inner())?;
-            obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-            log::trace!("Inserted bytes for object with id {object_id}");
-        }
-
-        for label in &labels {
+impl InsertRequest {
+    /// Add `object_id` to the index entries of each label
+    fn index_labels(
+        tx: &BucketTx,
+        object_id: ObjectID,
+        labels: &[Label],
+    ) -> Result<(), UnabortableTransactionError> {
+        for label in labels {
             // Insert key=value to labels tree
             {
                 let key_bytes = Self::ser_label(label.clone())?;
-                let val_bytes = Self::transaction_ser(label.clone())?;
-                lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-                log::trace!("Inserted label {} into labels", label.to_string_ltr());
+                let val_bytes = tx.ser(label)?;
+                tx.lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                tx.lbl_keys.insert(label.key_raw(), val_bytes.to_vec())?;
+                tx.wrote_label(label)?;
+                tx_log!(
+                    tx,
+                    trace,
+                    "Inserted label {} into labels",
+                    label.to_string_ltr()
+                );
             }
 
             // Insert value=key to labels invert tree
             {
                 let key_bytes = Self::ser_label_invert(label.clone())?;
-                let val_bytes = Self::transaction_ser(label.clone())?;
-                lbl_invert.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-                log::trace!(
+                let val_bytes = tx.ser(label)?;
+                tx.lbl_invert
+                    .insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                tx_log!(
+                    tx,
+                    trace,
                     "Inserted label {} into labels_inverse",
                     label.to_string_rtl()
                 )
@@ -118,22 +321,28 @@ impl ExecuteTransaction for InsertRequest {
             // Upsert this object id into this label in the objects labels invert tree
             {
                 let key_bytes = Self::ser_label(label.clone())?;
-                match lbl_obj.get(&key_bytes.clone()) {
+                match tx.lbl_obj.get(&key_bytes) {
                     Ok(Some(thing)) => {
-                        let mut objects: Vec<ObjectID> =
-                            Self::transaction_de(Bytes::from(thing.to_vec()))?;
+                        let mut objects: Vec<ObjectID> = tx.de(&thing)?;
+                        if objects.contains(&object_id) {
+                            continue;
+                        }
                         objects.push(object_id);
-                        let val_bytes = Self::transaction_ser(objects)?;
-                        lbl_obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-                        log::trace!(
+                        let val_bytes = tx.ser(&objects)?;
+                        tx.lbl_obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                        tx_log!(
+                            tx,
+                            trace,
                             "Upserted object id {object_id} into label {}",
                             label.to_string_ltr()
                         );
                     }
                     Ok(None) => {
-                        let val_bytes = Self::transaction_ser(vec![object_id])?;
-                        lbl_obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-                        log::trace!(
+                        let val_bytes = tx.ser(&vec![object_id])?;
+                        tx.lbl_obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                        tx_log!(
+                            tx,
+                            trace,
                             "Inserted object id {object_id} into new label {}",
                             label.to_string_ltr()
                         );
@@ -142,13 +351,536 @@ impl ExecuteTransaction for InsertRequest {
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Store this request's attributes for `object_id`, over any already there
+    fn write_attrs(
+        &self,
+        tx: &BucketTx,
+        object_id: ObjectID,
+    ) -> Result<(), UnabortableTransactionError> {
+        let attrs = self
+            .attrs
+            .try_borrow()
+            .map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+            })?
+            .clone();
+        if attrs.is_empty() {
+            return Ok(());
+        }
+
+        let key_bytes = Self::transaction_ser(object_id)?;
+        let mut merged: HashMap<String, String> = match tx.obj_attrs.get(&key_bytes)? {
+            Some(bytes) => tx.de(&bytes)?,
+            None => HashMap::new(),
+        };
+        merged.extend(attrs);
+        let val_bytes = tx.ser(&merged)?;
+        tx.obj_attrs
+            .insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+        tx_log!(
+            tx,
+            trace,
+            "Inserted attributes for object with id {object_id}"
+        );
+        Ok(())
+    }
+
+    /// Find an existing object holding exactly this payload. A scoped insert only reuses
+    /// an object carrying its scope label, so it never merges into another tenant's object;
+    /// when the recorded holder is outside the scope the payload is stored again.
+    fn find_duplicate(
+        &self,
+        tx: &BucketTx,
+        hash_key: &Bytes,
+        normalize: bool,
+    ) -> Result<Option<ObjectID>, UnabortableTransactionError> {
+        let existing = match tx.content_hashes.get(hash_key)? {
+            Some(bytes) => tx.de::<ObjectID>(&bytes)?,
+            None => return Ok(None),
+        };
+
+        let scope = self.scope.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        if let Some(scope) = scope.as_ref() {
+            let scope = match normalize {
+                true => scope.normalized(),
+                false => scope.clone(),
+            };
+            let labels: Vec<Label> = match tx.obj_lbl.get(Self::transaction_ser(existing)?)? {
+                Some(bytes) => tx.de(&bytes)?,
+                None => vec![],
+            };
+            if !labels.contains(&scope) {
+                tx_log!(
+                    tx,
+                    trace,
+                    "not reusing object with id {existing}, it is outside scope {scope}"
+                );
+                return Ok(None);
+            }
+        }
+
+        // Guard against hash collisions and stale entries by comparing the stored bytes
+        match Self::stored_object(tx, existing)? {
+            Some(stored) if stored == self.object => Ok(Some(existing)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The object recorded as holding the payload with this hash
+    pub(crate) fn content_owner(
+        tx: &BucketTx,
+        hash_key: &[u8],
+    ) -> Result<Option<ObjectID>, UnabortableTransactionError> {
+        match tx.content_hashes.get(hash_key)? {
+            Some(bytes) => Ok(Some(tx.de(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// How many inserts share the payload with this hash
+    pub(crate) fn content_refs(
+        tx: &BucketTx,
+        hash_key: &[u8],
+    ) -> Result<u64, UnabortableTransactionError> {
+        match tx.content_refcount.get(hash_key)? {
+            Some(bytes) => tx.de(&bytes),
+            None => Ok(1),
+        }
+    }
+
+    /// The object currently stored at `id`, with a chunked payload reassembled
+    pub(crate) fn stored_object(
+        tx: &BucketTx,
+        id: ObjectID,
+    ) -> Result<Option<Object>, UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(id)?;
+        if let Some(bytes) = tx.chunks.get(&key_bytes)? {
+            let manifest: ChunkManifest = tx.de(&bytes)?;
+            let mut payload = Vec::with_capacity(manifest.len as usize);
+            for n in 0..manifest.chunks {
+                payload.extend_from_slice(&Self::stored_chunk(tx, id, n)?);
+            }
+            return Ok(Some(Object::new(payload.into())));
+        }
+        match tx.obj.get(&key_bytes)? {
+            Some(bytes) => Ok(Some(Object::new(tx.de_payload(&bytes)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Up to `len` bytes of the payload stored at `id` from `offset`, cut short at the
+    /// end of the payload. Chunked payloads only read the chunks the range overlaps.
+    pub(crate) fn stored_range(
+        tx: &BucketTx,
+        id: ObjectID,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Bytes>, UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(id)?;
+        let Some(bytes) = tx.chunks.get(&key_bytes)? else {
+            return Ok(
+                Self::stored_object(tx, id)?.map(|o| byte_range(&o.get_inner(), offset, len))
+            );
+        };
+
+        let manifest: ChunkManifest = tx.de(&bytes)?;
+        let start = offset.min(manifest.len as usize);
+        let end = start.saturating_add(len).min(manifest.len as usize);
+        if start == end {
+            return Ok(Some(Bytes::new()));
+        }
+
+        // Every chunk but the last is as long as the first
+        let first = Self::stored_chunk(tx, id, 0)?;
+        let chunk_size = first.len();
+        let mut payload = Vec::with_capacity(end - start);
+        for n in start / chunk_size..=(end - 1) / chunk_size {
+            let chunk = match n {
+                0 => first.clone(),
+                n => Self::stored_chunk(tx, id, n as u64)?,
+            };
+            let chunk_start = n * chunk_size;
+            let from = start.saturating_sub(chunk_start);
+            let to = (end - chunk_start).min(chunk.len());
+            payload.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(Some(payload.into()))
+    }
+
+    /// Chunk `n` of the chunked payload stored at `id`
+    fn stored_chunk(
+        tx: &BucketTx,
+        id: ObjectID,
+        n: u64,
+    ) -> Result<Bytes, UnabortableTransactionError> {
+        let chunk = tx
+            .chunks
+            .get(Self::transaction_ser((id, n))?)?
+            .ok_or_else(|| {
+                UnabortableTransactionError::Storage(sled::Error::Unsupported(format!(
+                    "chunk {n} of object {id} is missing"
+                )))
+            })?;
+        tx.de(&chunk)
+    }
+
+    /// Drop the content hash ownership and chunks of whatever `object_id` holds before
+    /// it is replaced by `object`
+    fn release_payload(
+        tx: &BucketTx,
+        object_id: ObjectID,
+        object: &Object,
+    ) -> Result<(), UnabortableTransactionError> {
+        if let Some(stored) = Self::stored_object(tx, object_id)? {
+            if &stored != object {
+                let old_key = Self::transaction_ser(stored.hash_id())?;
+                if Self::content_owner(tx, &old_key)? == Some(object_id) {
+                    tx.content_hashes.remove(old_key.to_vec())?;
+                    tx.content_refcount.remove(old_key.to_vec())?;
+                }
+            }
+        }
+        Self::remove_chunks(tx, object_id)
+    }
+
+    /// Remove the manifest and chunks of `object_id`, if it was stored chunked
+    pub(crate) fn remove_chunks(
+        tx: &BucketTx,
+        object_id: ObjectID,
+    ) -> Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(object_id)?;
+        if let Some(bytes) = tx.chunks.remove(key_bytes.to_vec())? {
+            let manifest: ChunkManifest = tx.de(&bytes)?;
+            for n in 0..manifest.chunks {
+                tx.chunks
+                    .remove(Self::transaction_ser((object_id, n))?.to_vec())?;
+            }
+            tx_log!(
+                tx,
+                trace,
+                "Removed {} chunks of object with id {object_id}",
+                manifest.chunks
+            );
+        }
+        Ok(())
+    }
+
+    /// Stamp `object_id` as changed now, and as created now unless it was inserted before
+    fn record_created(
+        tx: &BucketTx,
+        object_id: ObjectID,
+    ) -> Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(object_id)?;
+        let val_bytes = tx.ser(&unix_millis())?;
+        if tx.created.get(&key_bytes)?.is_none() {
+            tx.created.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+        }
+        tx.updated.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+        Ok(())
+    }
+
+    /// Stamp `object_id` as changed now
+    pub(crate) fn record_updated(
+        tx: &BucketTx,
+        object_id: ObjectID,
+    ) -> Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(object_id)?;
+        let val_bytes = tx.ser(&unix_millis())?;
+        tx.updated.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+        Ok(())
+    }
+
+    /// Store `object` in pieces of `chunk_size` bytes, leaving an empty payload in the
+    /// objects tree so the object is still found by id
+    fn write_chunks(
+        tx: &BucketTx,
+        object_id: ObjectID,
+        object: &Object,
+        chunk_size: usize,
+    ) -> Result<(), UnabortableTransactionError> {
+        Self::release_payload(tx, object_id, object)?;
+        Self::record_created(tx, object_id)?;
+
+        let key_bytes = Self::transaction_ser(object_id)?;
+        let val_bytes = tx.ser(&Bytes::new())?;
+        tx.obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+        tx.invalidate(object_id)?;
+
+        let payload = object.get_inner();
+        let mut chunks = 0u64;
+        for chunk in payload.chunks(chunk_size) {
+            let val_bytes = tx.ser(&Bytes::copy_from_slice(chunk))?;
+            tx.chunks.insert(
+                Self::transaction_ser((object_id, chunks))?.to_vec(),
+                val_bytes.to_vec(),
+            )?;
+            chunks += 1;
+        }
+        let manifest = ChunkManifest {
+            len: payload.len() as u64,
+            chunks,
+        };
+        tx.chunks
+            .insert(key_bytes.to_vec(), tx.ser(&manifest)?.to_vec())?;
+        tx_log!(
+            tx,
+            trace,
+            "Inserted {chunks} chunks for object with id {object_id}"
+        );
+        Ok(())
+    }
+
+    /// Store `object` as the payload of `object_id`, moving content hash ownership from
+    /// any payload it replaces
+    pub(crate) fn write_payload(
+        tx: &BucketTx,
+        object_id: ObjectID,
+        object: &Object,
+    ) -> Result<(), UnabortableTransactionError> {
+        // An overwritten payload no longer belongs to this id
+        Self::release_payload(tx, object_id, object)?;
+        Self::record_created(tx, object_id)?;
+
+        // Insert the object
+        {
+            let key_bytes = Self::transaction_ser(object_id)?;
+            let val_bytes = tx.ser(&object.get_inner())?;
+            tx.obj.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+            tx.invalidate(object_id)?;
+            tx_log!(tx, trace, "Inserted bytes for object with id {object_id}");
+        }
+
+        // Record which object holds this payload, unless another object already does
+        let hash_key = Self::transaction_ser(object.hash_id())?;
+        if Self::content_owner(tx, &hash_key)?.is_none() {
+            let val_bytes = tx.ser(&object_id)?;
+            tx.content_hashes
+                .insert(hash_key.to_vec(), val_bytes.to_vec())?;
+            let val_bytes = tx.ser(&1u64)?;
+            tx.content_refcount
+                .insert(hash_key.to_vec(), val_bytes.to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// Move the payload `object_id` holds into the versions tree if `object` replaces it,
+    /// keeping only the `keep` most recent versions
+    fn archive_version(
+        tx: &BucketTx,
+        object_id: ObjectID,
+        object: &Object,
+        keep: usize,
+    ) -> Result<(), UnabortableTransactionError> {
+        if keep == 0 {
+            return Ok(());
+        }
+        let Some(stored) = Self::stored_object(tx, object_id)? else {
+            return Ok(());
+        };
+        if &stored == object {
+            return Ok(());
+        }
+
+        let latest_key = Self::transaction_ser(object_id)?;
+        let version = match tx.versions.get(&latest_key)? {
+            Some(bytes) => tx.de::<u64>(&bytes)? + 1,
+            None => 1,
+        };
+        let val_bytes = tx.ser(&stored.get_inner())?;
+        tx.versions.insert(
+            Self::transaction_ser((object_id, version))?.to_vec(),
+            val_bytes.to_vec(),
+        )?;
+        tx.versions
+            .insert(latest_key.to_vec(), tx.ser(&version)?.to_vec())?;
+        tx_log!(
+            tx,
+            trace,
+            "Kept version {version} of object with id {object_id}"
+        );
+
+        // Versions are contiguous, so stop at the first one already dropped
+        let mut expired = version.saturating_sub(keep as u64);
+        while expired > 0 {
+            let key_bytes = Self::transaction_ser((object_id, expired))?;
+            if tx.versions.remove(key_bytes.to_vec())?.is_none() {
+                break;
+            }
+            expired -= 1;
+        }
+        Ok(())
+    }
+
+    /// Apply the collision policy to a hash-derived id, returning the id to store under
+    fn resolve_collision(
+        &self,
+        tx: &BucketTx,
+        id: ObjectID,
+    ) -> Result<ObjectID, UnabortableTransactionError> {
+        let policy = *self.collision.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+
+        let mut candidate = id;
+        for attempt in 0..MAX_REHASH_ATTEMPTS {
+            match Self::stored_object(tx, candidate)? {
+                Some(stored) if stored != self.object => match policy {
+                    CollisionPolicy::Overwrite => return Ok(candidate),
+                    CollisionPolicy::Reject => {
+                        return Err(tx.abort(TransactionError::ObjectCollision(candidate)))
+                    }
+                    CollisionPolicy::Rehash => {
+                        tx_log!(
+                            tx,
+                            trace,
+                            "id {candidate} holds a different object, rehashing"
+                        );
+                        let mut hasher = DefaultHasher::new();
+                        (candidate, attempt).hash(&mut hasher);
+                        candidate = hasher.finish();
+                    }
+                },
+                _ => return Ok(candidate),
+            }
+        }
+        Err(tx.abort(TransactionError::ObjectCollision(candidate)))
+    }
+}
+
+impl ExecuteTransaction for InsertRequest {
+    type Error = UnabortableTransactionError;
+    type Output = ObjectID;
+
+    fn execute(&self, tx: &BucketTx) -> Result<Self::Output, Self::Error> {
+        let object_id = *self.id.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        let hashed = *self.hashed.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        let config = tx.config()?;
+        let mut labels = self
+            .labels
+            .try_borrow()
+            .map_err(|e| {
+                UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+            })?
+            .clone();
+        if config.normalize_keys {
+            labels = labels.iter().map(Label::normalized).collect();
+            labels.sort();
+            labels.dedup();
+        }
+        let dedupe = *self.dedupe.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })? || config.dedupe_by_content;
+        let skip_index = *self.skip_index.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        let chunk_size = *self.chunk_size.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        let dedupe = dedupe && chunk_size.is_none();
+        let hash_key = Self::transaction_ser(self.object.hash_id())?;
+
+        // Merge the labels onto an existing copy of this payload instead of storing it twice
+        if dedupe {
+            if let Some(existing_id) = self.find_duplicate(tx, &hash_key, config.normalize_keys)? {
+                let key_bytes = Self::transaction_ser(existing_id)?;
+                let mut merged: Vec<Label> = match tx.obj_lbl.get(&key_bytes)? {
+                    Some(bytes) => tx.de(&bytes)?,
+                    None => vec![],
+                };
+                let new_labels: Vec<Label> =
+                    labels.into_iter().filter(|l| !merged.contains(l)).collect();
+                if !skip_index {
+                    Self::index_labels(tx, existing_id, &new_labels)?;
+                }
+
+                merged.extend(new_labels);
+                merged.sort();
+                let val_bytes = tx.ser(&merged)?;
+                tx.obj_lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                self.write_attrs(tx, existing_id)?;
+                Self::record_updated(tx, existing_id)?;
+
+                let refs = Self::content_refs(tx, &hash_key)? + 1;
+                let val_bytes = tx.ser(&refs)?;
+                tx.content_refcount
+                    .insert(hash_key.to_vec(), val_bytes.to_vec())?;
+                tx_log!(
+                    tx,
+                    trace,
+                    "Merged duplicate payload into object with id {existing_id}, {refs} references"
+                );
+                return Ok(existing_id);
+            }
+        }
+
+        let object_id = match hashed {
+            true => self.resolve_collision(tx, object_id)?,
+            false => object_id,
+        };
+
+        let keep_versions = *self.keep_versions.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        Self::archive_version(tx, object_id, &self.object, keep_versions)?;
+        match chunk_size {
+            Some(chunk_size) => Self::write_chunks(tx, object_id, &self.object, chunk_size)?,
+            None => Self::write_payload(tx, object_id, &self.object)?,
+        }
+
+        // Drop relations for labels the previous copy of this object had
+        {
+            let key_bytes = Self::transaction_ser(object_id)?;
+            if let Some(bytes) = tx.obj_lbl.get(&key_bytes)? {
+                let previous: Vec<Label> = tx.de(&bytes)?;
+                for label in previous
+                    .iter()
+                    .filter(|l| skip_index || !labels.contains(l))
+                {
+                    DeleteRequest::unindex_label(tx, object_id, label, true)?;
+                }
+            }
+        }
+
+        if !skip_index {
+            Self::index_labels(tx, object_id, &labels)?;
+        }
+        self.write_attrs(tx, object_id)?;
+
+        let ttl = *self.ttl.try_borrow().map_err(|e| {
+            UnabortableTransactionError::Storage(sled::Error::Io(io::Error::other(e)))
+        })?;
+        {
+            let key_bytes = Self::transaction_ser(object_id)?;
+            match ttl {
+                Some(ttl) => {
+                    let val_bytes = tx.ser(&ObjectMeta::expiring_in(ttl))?;
+                    tx.obj_meta.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+                }
+                None => {
+                    tx.obj_meta.remove(key_bytes.to_vec())?;
+                }
+            }
+        }
 
         // Add object id = [labels] to objects labels tree
         {
             let key_bytes = Self::transaction_ser(object_id)?;
-            let val_bytes = Self::transaction_ser(labels)?;
-            obj_lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
-            log::trace!("Inserted labels for object with id {object_id} into objects_labels tree.");
+            let val_bytes = tx.ser(&labels)?;
+            tx.obj_lbl.insert(key_bytes.to_vec(), val_bytes.to_vec())?;
+            tx_log!(
+                tx,
+                trace,
+                "Inserted labels for object with id {object_id} into objects_labels tree."
+            );
         }
 
         Ok(object_id)
@@ -1,7 +1,21 @@
+/// Log through `log`, prefixed with the correlation id of `$tx`, a `BucketTx` or anything
+/// else with a `correlation_id: Option<u64>` field
+macro_rules! tx_log {
+    ($tx:expr, $lvl:ident, $($arg:tt)+) => {
+        log::$lvl!(
+            "[tx {}] {}",
+            $crate::query::transaction::CorrelationId($tx.correlation_id),
+            format_args!($($arg)+)
+        )
+    };
+}
+
+pub mod cswap;
 pub mod delete;
 pub mod error;
 pub mod execute;
 pub mod find;
 pub mod get;
 pub mod insert;
+pub(crate) mod rename;
 pub mod transaction;
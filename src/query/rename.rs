@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+
+use crate::label::Label;
+use crate::object::ObjectID;
+use anyhow::Result;
+use sled::transaction::UnabortableTransactionError;
+
+use super::execute::{BucketTx, ExecuteTransaction};
+use super::insert::InsertRequest;
+
+/// Move every object from one set of labels to another, see `Bucket::rename_label_key`.
+///
+/// The labels to rename are resolved before the transaction opens, since transactional
+/// trees cannot be scanned.
+#[derive(Clone, Debug)]
+pub(crate) struct RenameLabels {
+    /// (from, to) pairs
+    renames: Vec<(Label, Label)>,
+}
+
+impl RenameLabels {
+    pub(crate) fn new(renames: Vec<(Label, Label)>) -> Self {
+        Self { renames }
+    }
+
+    /// Drop `from` from the labels trees, returning the objects it described
+    fn take_label(
+        tx: &BucketTx,
+        from: &Label,
+    ) -> Result<Vec<ObjectID>, UnabortableTransactionError> {
+        let key_bytes = Self::ser_label(from.clone())?;
+        tx.lbl.remove(key_bytes.to_vec())?;
+        tx.lbl_keys.remove(from.key_raw())?;
+        tx.lbl_invert
+            .remove(Self::ser_label_invert(from.clone())?.to_vec())?;
+        match tx.lbl_obj.remove(key_bytes.to_vec())? {
+            Some(bytes) => tx.de(&bytes),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Add `ids` to `to`, merging with any objects it already describes
+    fn merge_label(
+        tx: &BucketTx,
+        to: &Label,
+        ids: &[ObjectID],
+    ) -> Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::ser_label(to.clone())?;
+        let mut objects: Vec<ObjectID> = match tx.lbl_obj.get(&key_bytes)? {
+            Some(bytes) => tx.de(&bytes)?,
+            None => vec![],
+        };
+        let mut seen: HashSet<ObjectID> = objects.iter().copied().collect();
+        objects.extend(ids.iter().filter(|id| seen.insert(**id)));
+
+        tx.lbl_obj
+            .insert(key_bytes.to_vec(), tx.ser(&objects)?.to_vec())?;
+        tx.lbl.insert(key_bytes.to_vec(), tx.ser(to)?.to_vec())?;
+        tx.lbl_keys.insert(to.key_raw(), tx.ser(to)?.to_vec())?;
+        tx.wrote_label(to)?;
+        tx.lbl_invert.insert(
+            Self::ser_label_invert(to.clone())?.to_vec(),
+            tx.ser(to)?.to_vec(),
+        )?;
+        Ok(())
+    }
+
+    /// Replace `from` with `to` in the label list of `id`
+    fn relabel_object(
+        tx: &BucketTx,
+        id: ObjectID,
+        from: &Label,
+        to: &Label,
+    ) -> Result<(), UnabortableTransactionError> {
+        let key_bytes = Self::transaction_ser(id)?;
+        let Some(bytes) = tx.obj_lbl.get(&key_bytes)? else {
+            return Ok(());
+        };
+        let mut labels: Vec<Label> = tx.de(&bytes)?;
+        labels.retain(|l| l != from);
+        if !labels.contains(to) {
+            labels.push(to.clone());
+        }
+        tx.obj_lbl
+            .insert(key_bytes.to_vec(), tx.ser(&labels)?.to_vec())?;
+        InsertRequest::record_updated(tx, id)
+    }
+}
+
+impl ExecuteTransaction for RenameLabels {
+    type Error = UnabortableTransactionError;
+    type Output = usize;
+
+    fn execute(&self, tx: &BucketTx) -> Result<Self::Output, Self::Error> {
+        for (from, to) in self.renames.iter() {
+            let ids = Self::take_label(tx, from)?;
+            Self::merge_label(tx, to, &ids)?;
+            for id in ids.iter() {
+                Self::relabel_object(tx, *id, from, to)?;
+            }
+            tx_log!(
+                tx,
+                trace,
+                "renamed label {from} to {to} on {} objects",
+                ids.len()
+            );
+        }
+        Ok(self.renames.len())
+    }
+}
@@ -1,25 +1,51 @@
 use crate::bucket::Bucket;
+use crate::cache::{CacheOps, ObjectCache};
+use crate::format::SerFormat;
+use crate::label::Label;
+use crate::object::ObjectID;
 use anyhow::{anyhow, Result};
+use rayon::prelude::*;
 
-use sled::transaction::{
-    ConflictableTransactionError, TransactionalTree, UnabortableTransactionError,
+use sled::transaction::{ConflictableTransactionError, UnabortableTransactionError};
+use sled::{Transactional, Tree};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
 };
-use sled::Transactional;
-use std::cell::RefCell;
 
-use super::delete::DeleteRequest;
+use super::cswap::CswapRequest;
+use super::delete::{DeleteRequest, WRITES_PER_DELETE};
 use super::error::*;
-use super::execute::ExecuteTransaction;
+use super::execute::{BucketTx, ExecuteTransaction};
 use super::find::FindRequest;
 use super::get::GetRequest;
 use super::insert::InsertRequest;
 
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A correlation id for log lines, `-` outside a transaction
+pub(crate) struct CorrelationId(pub(crate) Option<u64>);
+
+impl Display for CorrelationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "{id}"),
+            None => f.write_str("-"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Request {
     Insert(InsertRequest),
     Delete(DeleteRequest),
     Find(FindRequest),
     Get(GetRequest),
+    Cswap(CswapRequest),
 }
 
 impl From<InsertRequest> for Request {
@@ -42,6 +68,11 @@ impl From<GetRequest> for Request {
         Self::Get(value)
     }
 }
+impl From<CswapRequest> for Request {
+    fn from(value: CswapRequest) -> Self {
+        Self::Cswap(value)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum RequestResult {
@@ -73,58 +104,160 @@ pub enum RequestResult {
             <GetRequest as ExecuteTransaction>::Error,
         >,
     ),
+    Cswap(
+        Box<CswapRequest>,
+        std::result::Result<
+            <CswapRequest as ExecuteTransaction>::Output,
+            <CswapRequest as ExecuteTransaction>::Error,
+        >,
+    ),
+}
+
+impl RequestResult {
+    /// The objects found by a successful FindRequest
+    pub fn as_find(&self) -> Option<&<FindRequest as ExecuteTransaction>::Output> {
+        match self {
+            RequestResult::Find(_, Ok(res)) => Some(res),
+            _ => None,
+        }
+    }
+
+    /// The objects returned by a successful GetRequest
+    pub fn as_get(&self) -> Option<&<GetRequest as ExecuteTransaction>::Output> {
+        match self {
+            RequestResult::Get(_, Ok(res)) => Some(res),
+            _ => None,
+        }
+    }
+
+    /// The id assigned by a successful InsertRequest
+    pub fn as_insert_id(&self) -> Option<ObjectID> {
+        match self {
+            RequestResult::Insert(_, Ok(id)) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// The id swapped by a successful CswapRequest
+    pub fn as_cswap_id(&self) -> Option<ObjectID> {
+        match self {
+            RequestResult::Cswap(_, Ok(id)) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// The per-object outcome of a successful DeleteRequest
+    pub fn as_delete(&self) -> Option<&<DeleteRequest as ExecuteTransaction>::Output> {
+        match self {
+            RequestResult::Delete(_, Ok(res)) => Some(res),
+            _ => None,
+        }
+    }
+}
+
+impl Request {
+    /// Whether this request only reads from the bucket
+    pub fn is_read(&self) -> bool {
+        matches!(self, Request::Find(_) | Request::Get(_))
+    }
+
+    /// The kind of request, as recorded in logs and tracing spans
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Insert(_) => "insert",
+            Request::Delete(_) => "delete",
+            Request::Find(_) => "find",
+            Request::Get(_) => "get",
+            Request::Cswap(_) => "cswap",
+        }
+    }
 }
 
 impl ExecuteTransaction for Request {
     type Error = UnabortableTransactionError;
     type Output = RequestResult;
 
-    fn execute(
-        &self,
-        lbl: &TransactionalTree,
-        lbl_invert: &TransactionalTree,
-        obj: &TransactionalTree,
-        obj_lbl: &TransactionalTree,
-        lbl_obj: &TransactionalTree,
-    ) -> Result<Self::Output, Self::Error> {
-        match self {
+    fn execute(&self, tx: &BucketTx) -> Result<Self::Output, Self::Error> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "request",
+            kind = self.kind(),
+            elapsed_us = tracing::field::Empty
+        )
+        .entered();
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+
+        let result = match self {
             Request::Insert(r) => {
-                let inner = r.execute(lbl, lbl_invert, obj, obj_lbl, lbl_obj);
+                let inner = r.execute(tx);
                 match inner {
                     Ok(_) => Ok(RequestResult::Insert(Box::new(r.clone()), inner)),
                     Err(e) => Err(e),
                 }
             }
             Request::Delete(r) => {
-                let inner = r.execute(lbl, lbl_invert, obj, obj_lbl, lbl_obj);
+                let inner = r.execute(tx);
                 match inner {
                     Ok(_) => Ok(RequestResult::Delete(Box::new(r.clone()), inner)),
                     Err(e) => Err(e),
                 }
             }
             Request::Find(r) => {
-                let inner = r.execute(lbl, lbl_invert, obj, obj_lbl, lbl_obj);
+                let inner = r.execute(tx);
                 match inner {
                     Ok(_) => Ok(RequestResult::Find(Box::new(r.clone()), inner)),
                     Err(e) => Err(e),
                 }
             }
             Request::Get(r) => {
-                let inner = r.execute(lbl, lbl_invert, obj, obj_lbl, lbl_obj);
+                let inner = r.execute(tx);
                 match inner {
                     Ok(_) => Ok(RequestResult::Get(Box::new(r.clone()), inner)),
                     Err(e) => Err(e),
                 }
             }
-        }
+            Request::Cswap(r) => {
+                let inner = r.execute(tx);
+                match inner {
+                    Ok(_) => Ok(RequestResult::Cswap(Box::new(r.clone()), inner)),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_us", started.elapsed().as_micros() as u64);
+        result
     }
 }
 
+/// Receives each result with its request index, see `Transaction::execute_with`
+type ResultSink<'a> = &'a mut dyn FnMut(usize, &RequestResult);
+
+/// Shared by every sled transaction one `Transaction::execute` runs, including each of
+/// its parallel reads: how often sled may run them and the id their log lines carry
+struct TxRun {
+    correlation_id: Option<u64>,
+    budget: Option<usize>,
+    attempts: AtomicUsize,
+
+    /// Runs that fail with a conflict before sled touches the trees, for tests
+    #[cfg(test)]
+    simulated_conflicts: AtomicUsize,
+}
+
 pub struct Transaction {
     pub(crate) namespace: Bucket,
     pub(crate) reqs: RefCell<Vec<Request>>,
     pub(crate) results: RefCell<Vec<RequestResult>>,
     pub(crate) completed: RefCell<bool>,
+    pub(crate) correlation_id: RefCell<Option<u64>>,
+    pub(crate) retry_budget: RefCell<Option<usize>>,
+    pub(crate) max_writes: RefCell<Option<usize>>,
+    pub(crate) attempts: RefCell<usize>,
+    #[cfg(test)]
+    pub(crate) simulated_conflicts: RefCell<usize>,
 }
 
 impl Transaction {
@@ -153,6 +286,18 @@ impl Transaction {
         Ok(())
     }
 
+    /// Discard every appended request. Only valid before `execute`; use `reset` after.
+    pub fn rollback(&self) -> Result<()> {
+        if self.completed()? {
+            return Err(TransactionError::AlreadyExecuted.into());
+        }
+
+        self.reqs.try_borrow_mut()?.clear();
+        self.results.try_borrow_mut()?.clear();
+        *self.completed.try_borrow_mut()? = false;
+        Ok(())
+    }
+
     pub fn completed(&self) -> Result<bool> {
         Ok(*self.completed.try_borrow()?)
     }
@@ -162,49 +307,330 @@ impl Transaction {
         Ok(results.to_owned())
     }
 
+    /// The outputs of every FindRequest in this transaction, in request order
+    pub fn find_results(&self) -> Result<Vec<<FindRequest as ExecuteTransaction>::Output>> {
+        let results = self.results.try_borrow()?;
+        Ok(results
+            .iter()
+            .filter_map(|r| r.as_find())
+            .cloned()
+            .collect())
+    }
+
     pub fn execute(&self) -> Result<()> {
+        self.run(None)
+    }
+
+    /// Like `execute`, but hand each result to `f` with its request index as the request
+    /// completes instead of keeping it, so `results` stays empty afterwards.
+    ///
+    /// `f` runs inside the transaction. If sled retries after a conflict, `f` sees every
+    /// result again from index 0, and if the transaction fails it may have seen results
+    /// that were never committed. Read-only transactions run in a single transaction
+    /// rather than in parallel.
+    pub fn execute_with(&self, mut f: impl FnMut(usize, &RequestResult)) -> Result<()> {
+        self.run(Some(&mut f))
+    }
+
+    /// Execute the requests, buffering results into `results` unless `sink` takes them
+    fn run(&self, sink: Option<ResultSink>) -> Result<()> {
         match self.completed.try_borrow() {
-            Ok(c) => match *c {
-                true => return Err(TransactionError::AlreadyExecuted.into()),
-                false => (),
-            },
+            Ok(c) => {
+                if *c {
+                    return Err(TransactionError::AlreadyExecuted.into());
+                }
+            }
             Err(e) => return Err(anyhow!(e)),
         }
 
+        // Writing to a dropped bucket would silently recreate its trees
+        if !self.namespace.check()? {
+            return Err(TransactionError::BucketDropped(self.namespace.name.clone()).into());
+        }
+
+        let config = self.namespace.config()?;
         let requests = self.reqs.try_borrow()?;
+        for req in requests.iter() {
+            if let Request::Insert(r) = req {
+                let derived = self
+                    .namespace
+                    .derived_labels(&r.object.get_inner(), &r.labels.try_borrow()?)?;
+                if !derived.is_empty() {
+                    r.add_labels(derived)?;
+                }
+            }
+        }
+        if let Some(scope) = &self.namespace.scope {
+            self.apply_scope(&requests, scope, config.normalize_keys)?;
+        }
+        for req in requests.iter() {
+            match req {
+                Request::Find(r) => r.resolve_values(&self.namespace)?,
+                Request::Delete(r) => {
+                    if let Some(find) = r.find.try_borrow()?.as_ref() {
+                        find.resolve_values(&self.namespace)?;
+                    }
+                }
+                Request::Insert(r) => {
+                    for label in r.labels.try_borrow()?.iter() {
+                        config.check_label(label)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let writes = match *self.max_writes.try_borrow()? {
+            Some(limit) => {
+                let writes = Self::estimated_writes(&requests)?;
+                if writes > limit {
+                    return Err(TransactionError::TooLarge(writes, limit).into());
+                }
+                Some((writes, limit))
+            }
+            None => None,
+        };
+
+        let correlation_id = NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed);
+        *self.correlation_id.try_borrow_mut()? = Some(correlation_id);
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "transaction",
+            bucket = %self.namespace.name,
+            correlation_id,
+            requests = requests.len(),
+            elapsed_us = tracing::field::Empty
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+
+        // Independent reads skip the shared transaction and run side by side
+        let trees: Vec<Tree> = self.namespace.trees().into_iter().cloned().collect();
+        let format = self.namespace.format;
+        let cache = self.namespace.cache.as_deref();
+        let tx_run = TxRun {
+            correlation_id: Some(correlation_id),
+            budget: *self.retry_budget.try_borrow()?,
+            attempts: AtomicUsize::new(0),
+            #[cfg(test)]
+            simulated_conflicts: AtomicUsize::new(*self.simulated_conflicts.try_borrow()?),
+        };
+        let sink = sink.map(RefCell::new);
+        let results = match requests.iter().all(Request::is_read) && sink.is_none() {
+            true => requests
+                .clone()
+                .into_par_iter()
+                .map(|req| {
+                    #[cfg(feature = "tracing")]
+                    let _entered = span.enter();
+                    Self::execute_requests(&trees, &[req], format, cache, &tx_run, None, None)
+                        .map(|mut r| r.remove(0))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            false => Self::execute_requests(
+                &trees,
+                &requests,
+                format,
+                cache,
+                &tx_run,
+                writes,
+                sink.as_ref(),
+            )?,
+        };
+        *self.attempts.try_borrow_mut()? = tx_run.attempts.into_inner();
+
+        // Confirm durable inserts are on disk before reporting success
+        let mut durable = false;
+        for req in requests.iter() {
+            if let Request::Insert(r) = req {
+                durable |= *r.durable.try_borrow()?;
+            }
+        }
+        if durable {
+            let flushed = self.namespace.parent.inner.flush()?;
+            tx_log!(tx_run, trace, "flushed {flushed} bytes for durable inserts");
+        }
+
+        let mut my_results = self.results.try_borrow_mut()?;
+        *my_results = results;
+        *self.completed.try_borrow_mut()? = true;
+
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_us", started.elapsed().as_micros() as u64);
+        Ok(())
+    }
+
+    /// Confine `requests` to objects carrying `scope`: finds only match them, inserts add
+    /// the label and only deduplicate against them, and requests naming an existing object
+    /// without it are refused
+    fn apply_scope(&self, requests: &[Request], scope: &Label, normalize: bool) -> Result<()> {
+        let check = |id: ObjectID| -> Result<()> {
+            match self.namespace.in_scope(id, scope, normalize)? {
+                true => Ok(()),
+                false => Err(TransactionError::OutOfScope(id, scope.to_string()).into()),
+            }
+        };
+
+        for req in requests {
+            match req {
+                Request::Find(r) => r.set_scope(Some(scope.clone()))?,
+                Request::Insert(r) => {
+                    check(*r.id.try_borrow()?)?;
+                    if !r.labels.try_borrow()?.contains(scope) {
+                        r.add_label(scope.clone())?;
+                    }
+                    *r.scope.try_borrow_mut()? = Some(scope.clone());
+                }
+                Request::Get(r) => r.ids()?.into_iter().try_for_each(check)?,
+                Request::Delete(r) => {
+                    r.ids()?.into_iter().try_for_each(check)?;
+                    if let Some(find) = r.find.try_borrow()?.as_ref() {
+                        find.set_scope(Some(scope.clone()))?;
+                    }
+                }
+                Request::Cswap(r) => check(r.id())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Roughly how many keys `requests` write, from the requests alone: one per inserted
+    /// or swapped object and one per label it carries, and `WRITES_PER_DELETE` per deleted
+    /// id. Objects a delete's find matches are counted inside the transaction.
+    fn estimated_writes(requests: &[Request]) -> Result<usize> {
+        let mut writes = 0;
+        for req in requests {
+            writes += match req {
+                Request::Insert(r) => 1 + r.labels.try_borrow()?.len(),
+                Request::Cswap(_) => 1,
+                Request::Delete(r) => r.ids()?.len() * WRITES_PER_DELETE,
+                Request::Find(_) | Request::Get(_) => 0,
+            };
+        }
+        Ok(writes)
+    }
 
+    /// Run `requests` in order inside a single transaction over the bucket's trees,
+    /// returning their results, or handing them to `sink` if given.
+    ///
+    /// sled reruns the closure whenever it conflicts, so every run counts against the budget.
+    /// The object cache only sees the reads and writes of the run that committed.
+    fn execute_requests(
+        trees: &[Tree],
+        requests: &[Request],
+        format: SerFormat,
+        cache: Option<&Mutex<ObjectCache>>,
+        tx_run: &TxRun,
+        writes: Option<(usize, usize)>,
+        sink: Option<&RefCell<ResultSink>>,
+    ) -> Result<Vec<RequestResult>> {
         let results = RefCell::new(vec![]);
-        (
-            &self.namespace.t_labels,
-            &self.namespace.t_labels_invert,
-            &self.namespace.t_objects,
-            &self.namespace.t_objects_labels,
-            &self.namespace.t_labels_objects,
-        )
-            .transaction(|(tx_lbl, tx_ilbl, tx_obj, tx_objlbl, tx_objilbl)| {
+        let cache_ops = RefCell::new(CacheOps::default());
+        let runs = Cell::new(0usize);
+        trees
+            .transaction(|trees| {
+                let attempt = runs.replace(runs.get() + 1);
+                if let Some(budget) = tx_run.budget.filter(|budget| attempt > *budget) {
+                    tx_log!(tx_run, debug, "giving up after {budget} retries");
+                    return Err(ConflictableTransactionError::Abort(
+                        TransactionError::RetryBudgetExhausted(budget),
+                    ));
+                }
+                tx_run.attempts.fetch_add(1, Ordering::Relaxed);
+                if attempt > 0 {
+                    tx_log!(
+                        tx_run,
+                        trace,
+                        "retrying after conflict, attempt {}",
+                        attempt + 1
+                    );
+                }
+                #[cfg(test)]
+                if tx_run
+                    .simulated_conflicts
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok()
+                {
+                    return Err(ConflictableTransactionError::Conflict);
+                }
+                results
+                    .try_borrow_mut()
+                    .map(|mut r| r.clear())
+                    .map_err(|e| {
+                        ConflictableTransactionError::Storage(sled::Error::Unsupported(
+                            e.to_string(),
+                        ))
+                    })?;
+                let tx = BucketTx::from_trees(trees, format, cache, writes, tx_run.correlation_id)
+                    .ok_or_else(|| {
+                        ConflictableTransactionError::Storage(sled::Error::Unsupported(
+                            "bucket trees do not match BucketTx".to_string(),
+                        ))
+                    })?;
                 for (n, req) in requests.iter().enumerate() {
-                    let res = req.execute(tx_lbl, tx_ilbl, tx_obj, tx_objlbl, tx_objilbl)?;
+                    let res = req.execute(&tx).map_err(|e| match tx.take_abort() {
+                        Some(error) => ConflictableTransactionError::Abort(error),
+                        None => e.into(),
+                    })?;
 
-                    let mut results = results.try_borrow_mut().map_err(|e| {
+                    let borrow_err = |e: std::cell::BorrowMutError| {
                         ConflictableTransactionError::Storage(sled::Error::Unsupported(
                             e.to_string(),
                         ))
-                    })?;
-                    results.push(res);
+                    };
+                    match sink {
+                        Some(sink) => (sink.try_borrow_mut().map_err(borrow_err)?)(n, &res),
+                        None => results.try_borrow_mut().map_err(borrow_err)?.push(res),
+                    }
 
-                    log::trace!(
+                    tx_log!(
+                        tx,
+                        trace,
                         "completed request {} of {} in transaction",
                         n + 1,
                         requests.len()
                     );
                 }
-                Ok::<(), ConflictableTransactionError<String>>(())
+                *cache_ops.try_borrow_mut().map_err(|e| {
+                    ConflictableTransactionError::Storage(sled::Error::Unsupported(e.to_string()))
+                })? = tx.take_cache_ops()?;
+                Ok::<(), ConflictableTransactionError<TransactionError>>(())
             })
-            .map_err(|e| anyhow!("{}", e))?;
+            .map_err(|e| match e {
+                sled::transaction::TransactionError::Abort(error) => error.into(),
+                e => anyhow!("{}", e),
+            })?;
+        if let Some(cache) = cache {
+            cache_ops.take().apply(cache)?;
+        }
+        Ok(results.take())
+    }
 
-        let mut my_results = self.results.try_borrow_mut()?;
-        *my_results = results.take();
-        Ok(())
+    /// Override the Mango's `retry_budget` for this transaction, None to retry until it commits
+    pub fn retry_budget(&self, budget: Option<usize>) -> Result<Option<usize>> {
+        *self.retry_budget.try_borrow_mut()? = budget;
+        Ok(budget)
+    }
+
+    /// Override the Mango's `max_transaction_writes` for this transaction, None to allow
+    /// any size
+    pub fn max_writes(&self, limit: Option<usize>) -> Result<Option<usize>> {
+        *self.max_writes.try_borrow_mut()? = limit;
+        Ok(limit)
+    }
+
+    /// How many times sled ran this transaction's requests in the most recent `execute`,
+    /// one more than its tx_run. Parallel reads each count their own runs.
+    pub fn attempts(&self) -> Result<usize> {
+        Ok(*self.attempts.try_borrow()?)
+    }
+
+    /// The id prefixed to every log line from the most recent `execute`
+    pub fn correlation_id(&self) -> Result<Option<u64>> {
+        Ok(*self.correlation_id.try_borrow()?)
     }
 
     pub fn len(&self) -> Result<usize> {
@@ -225,6 +651,12 @@ impl From<&Bucket> for Transaction {
             reqs: RefCell::new(vec![]),
             results: RefCell::new(vec![]),
             completed: RefCell::new(false),
+            correlation_id: RefCell::new(None),
+            retry_budget: RefCell::new(value.parent.config().retry_budget),
+            max_writes: RefCell::new(value.parent.config().max_transaction_writes),
+            attempts: RefCell::new(0),
+            #[cfg(test)]
+            simulated_conflicts: RefCell::new(0),
         }
     }
 }
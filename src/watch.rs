@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::mpsc::RecvTimeoutError,
+    time::Duration,
+};
+
+use anyhow::Result;
+use sled::{Event, IVec, Subscriber, Tree};
+
+use crate::{format::SerFormat, label::Label, object::ObjectID};
+
+/// A change to the set of objects described by a watched Label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Inserted(ObjectID),
+    Removed(ObjectID),
+}
+
+/// Iterator over changes to a single Label, created by `Bucket::watch_label`.
+///
+/// Events are best-effort: they are only produced for writes made through this
+/// process, and are derived by diffing successive object lists for the label.
+pub struct LabelWatcher {
+    key: IVec,
+    subscriber: Subscriber,
+    known: HashSet<ObjectID>,
+    pending: VecDeque<ChangeEvent>,
+    format: SerFormat,
+}
+
+impl LabelWatcher {
+    pub(crate) fn new(label: &Label, tree: &Tree, format: SerFormat) -> Result<Self> {
+        let key = label.key_ltr()?;
+
+        // Subscribe before reading the current state so nothing slips between
+        let subscriber = tree.watch_prefix(&key);
+        let known = match tree.get(&key)? {
+            Some(bytes) => format.de::<Vec<ObjectID>>(&bytes)?.into_iter().collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            key,
+            subscriber,
+            known,
+            pending: VecDeque::new(),
+            format,
+        })
+    }
+
+    /// Wait up to `timeout` for the next event.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ChangeEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            match self.subscriber.next_timeout(timeout) {
+                Ok(event) => self.apply(event),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        if event.key() != &self.key {
+            return;
+        }
+
+        let current: HashSet<ObjectID> = match event {
+            Event::Insert { value, .. } => match self.format.de::<Vec<ObjectID>>(&value) {
+                Ok(ids) => ids.into_iter().collect(),
+                Err(e) => {
+                    log::error!("error decoding watched object list: {e}");
+                    return;
+                }
+            },
+            Event::Remove { .. } => HashSet::new(),
+        };
+
+        let mut inserted: Vec<ObjectID> = current.difference(&self.known).copied().collect();
+        let mut removed: Vec<ObjectID> = self.known.difference(&current).copied().collect();
+        inserted.sort();
+        removed.sort();
+
+        self.pending
+            .extend(inserted.into_iter().map(ChangeEvent::Inserted));
+        self.pending
+            .extend(removed.into_iter().map(ChangeEvent::Removed));
+        self.known = current;
+    }
+}
+
+impl Iterator for LabelWatcher {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let event = self.subscriber.next()?;
+            self.apply(event);
+        }
+    }
+}